@@ -1,5 +1,5 @@
 use chacha::{ChaCha, KeyStream};
-use vm::{eval, SideEffectEngine, Noun};
+use vm::{eval, DefaultCostModel, SideEffectEngine, Noun};
 use std::collections::HashMap;
 use std::iter::Peekable;
 use std::io;
@@ -21,8 +21,8 @@ impl TestSideEffectEngine {
 }
 
 impl SideEffectEngine for TestSideEffectEngine {
-    fn nearest_neighbor(&mut self, _near: &[u8; 32]) -> [u8; 32] {
-        [0u8; 32]
+    fn nearest_neighbors(&mut self, _near: &[u8; 32], _count: usize) -> Vec<[u8; 32]> {
+        Vec::new()
     }
     fn random(&mut self, dest: &mut [u8]) {
         for b in dest.iter_mut() {
@@ -36,10 +36,15 @@ impl SideEffectEngine for TestSideEffectEngine {
     fn store(&mut self, key: &[u8], value: &[u8]) {
         self.storage.insert(key.into(), value.into());
     }
-    fn send(&mut self, _destination: &[u8; 32], _message: &[u8], _local_cost: u64) {}
+    fn send(&mut self, _destination: &[u8; 32], _message: &[u8], _local_cost: u64) -> bool {
+        false
+    }
     fn secret(&self) -> &[u8; 32] {
         b"this is a thirty-two byte secret"
     }
+    fn start_neighboring(&mut self, _identity: &[u8; 32], _address: &[u8; 16], _port: u16) -> bool {
+        false
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -183,7 +188,7 @@ fn main() {
         let mut tokens = Tokenizer::new(line.trim().as_bytes().iter().map(|x| *x)).peekable();
         match parse(&mut tokens) {
             Ok(expr) => {
-                match eval(expr, &mut engine, 1000000) {
+                match eval(expr, &mut engine, &DefaultCostModel, 1000000, 10_000_000) {
                     Ok(result) => { println!("{:?}", result) },
                     Err(err) => { println!("Error: {:?}", err); }
                 }
@@ -1,29 +1,105 @@
 use identity::Identity;
-use ip_address_port::IpAddressPort;
+use ip_address_port::{self, IpAddressPort};
 
+use crypto::blake2b::Blake2b;
 use crypto::chacha20poly1305::ChaCha20Poly1305;
 use crypto::ed25519;
 use crypto::aead::{AeadEncryptor, AeadDecryptor};
 
+use std::cmp::min;
 use std::collections::HashMap;
 
 use std::iter;
-use vm::{self, Vm, Fault};
-use content_packet::ContentPacket;
+use vm::{self, CostModel, DefaultCostModel, Noun, EvalError, Ticks};
+use content_packet::{self, ContentPacket};
 use initiation_packet::{self, InitiationPacketInner, InitiationPacketOuter};
 use rand::Rng;
-use stream::StreamCluster;
+use stream::{self, StreamCluster};
 use expected_packet_set::{ExpectedPacket, ExpectedPacketSet};
+use side_effect_engine::AgentSideEffectEngine;
+use ledger::{Ledger, LedgerConfig};
+use neighbor_policy::{DefaultNeighborPolicy, NeighborPolicy, NeighborSummary};
+use replay_cache::{Rejection, ReplayCache};
+use routing_table::{self, RoutingTable};
+use lookup::{self, Lookup};
+use message::Message;
+use reliable::ReliableChannel;
+use fragment::{self, Reassembler, ReassemblyLimits};
+use snapshot::{self, SnapshotError};
 
-struct NeighborState {
+pub struct NeighborState {
     address: IpAddressPort,
     
-    streams: StreamCluster,
+    pub streams: StreamCluster,
+    
+    /// Timestamp of the last packet we got from this neighbor, or of when we started streaming
+    /// with them if they have not sent anything yet.
+    last_heard_from: u64,
+    
+    /// When we last sent this neighbor new key material, and how many packets we have sent since.
+    rekeyed_at: u64,
+    sent_since_rekey: u64,
+    
+    reliable: ReliableChannel,
+    
+    reassembler: Reassembler,
+    next_fragmented_message: u64,
+}
+
+/// How often an agent replaces the key material it uses with each neighbor, so that a key
+/// compromised later does not expose earlier traffic.
+#[derive(Debug, Copy, Clone)]
+pub struct RekeySchedule {
+    /// Rekey once this much time has passed since the last rekey. Zero disables this.
+    pub interval: u64,
+    
+    /// Rekey after sending this many packets since the last rekey. Zero disables this.
+    pub packet_limit: u64,
+}
+
+impl Default for RekeySchedule {
+    fn default() -> RekeySchedule {
+        RekeySchedule{
+            interval: 10*60,
+            packet_limit: 10_000,
+        }
+    }
+}
+
+impl RekeySchedule {
+    fn is_due(&self, neighbor_state: &NeighborState, now: u64) -> bool {
+        (self.interval != 0 && now.saturating_sub(neighbor_state.rekeyed_at) >= self.interval)
+            || (self.packet_limit != 0 && neighbor_state.sent_since_rekey >= self.packet_limit)
+    }
 }
 
 pub struct Task {
     pub requestor: Identity,
-    pub vm: Vm,
+    
+    /// A `[subject formula]` cell, as accepted by `vm::eval`.
+    pub program: Noun,
+    
+    /// Set if the requestor wants the result sent back, to the number they gave the request.
+    pub request: Option<u64>,
+}
+
+/// Why a request sent with `Agent::send_request` came back without a result.
+#[derive(Debug, Eq, PartialEq)]
+pub enum RequestError {
+    /// The neighbor ran the program, and it failed, or the program did not deserialize.
+    Eval(EvalError),
+    
+    /// No response came within `Agent::request_timeout`.
+    TimedOut,
+    
+    /// The result did not deserialize.
+    MalformedResult,
+}
+
+/// A request sent with `Agent::send_request` that has not been answered yet.
+struct OutstandingRequest {
+    neighbor: Identity,
+    sent_at: u64,
 }
 
 pub trait AgentEnvironment {
@@ -31,20 +107,78 @@ pub trait AgentEnvironment {
     fn send(&mut self, address: &IpAddressPort, packet: &[u8]);
     
     /// Schedule a task for execution. This will _not_ wait until the task is complete
-    /// to return.
+    /// to return. The task is carried out by handing it back to `Agent::run_task`.
     fn execute(&mut self, task: Task);
+    
+    /// Persistent storage for programs that neighbors run on this agent.
+    fn load(&mut self, key: &[u8]) -> Option<Vec<u8>>;
+    fn store(&mut self, key: &[u8], value: &[u8]);
+    
+    /// A message sent with `Agent::send_reliably` has been delivered. `sequence` is the number
+    /// `send_reliably` returned for it.
+    fn acknowledged(&mut self, neighbor: &Identity, sequence: u64);
+    
+    /// A request sent with `Agent::send_request` has been answered, or has timed out. `request`
+    /// is the number `send_request` returned for it.
+    fn responded(&mut self, neighbor: &Identity, request: u64, result: Result<Noun, RequestError>);
 }
 
 pub struct Agent<E>{
     identity: Identity,
     private_key: [u8; 64],
+    
+    /// Known only to this agent. Programs use it, through the VM, to derive keys that only
+    /// this agent can compute.
+    secret: [u8; 32],
     neighbors: HashMap<Identity, NeighborState>,
     pub environment: E,
     
+    /// Balance of trade with each neighbor, which limits how much work we do for them.
+    pub ledger: Ledger,
+    
+    /// Prices the work done running tasks, in ticks taken from the requestor's allowance.
+    pub cost_model: Box<dyn CostModel>,
+    
+    /// Decides who may become a neighbor, and who to drop when there are too many.
+    pub neighbor_policy: Box<dyn NeighborPolicy>,
+    
+    /// How far an initiation packet's timestamp may be from our own clock, in either direction.
+    pub timestamp_window: u64,
+    
+    /// Ephemeral keys of initiation packets accepted within the last `timestamp_window`.
+    recent_initiations: ReplayCache,
+    
+    pub rekey_schedule: RekeySchedule,
+    
+    /// How long to wait for a reliable message to be acknowledged before sending it again.
+    pub retransmit_interval: u64,
+    
+    /// Bounds what each neighbor can make us buffer by sending fragmented messages.
+    pub reassembly_limits: ReassemblyLimits,
+    
+    /// Identities we know how to reach, by distance from our own. Neighbors are added whenever
+    /// we hear from them.
+    pub routing_table: RoutingTable,
+    
+    lookups: HashMap<u64, Lookup>,
+    next_lookup: u64,
+    
+    /// How long a lookup waits for an agent to answer before giving up on it.
+    pub lookup_timeout: u64,
+    
+    requests: HashMap<u64, OutstandingRequest>,
+    next_request: u64,
+    
+    /// How long `send_request` waits for a response before reporting `RequestError::TimedOut`.
+    pub request_timeout: u64,
+    
+    /// How many bytes a task may allocate while it is evaluated.
+    pub memory_limit: u64,
+    
     /// Associates expected incoming packet identifiers with the streams they 
     /// may have come from.
-    /// Streams are identified by the Identity of their endpoint, their
-    /// symmetric key, and their packet index.
+    /// Streams are identified by the Identity of their endpoint, the
+    /// number their cluster gave them, and their packet index.
     upcoming_packets: ExpectedPacketSet,
 }
 
@@ -59,9 +193,47 @@ pub enum HandleError {
     InternalError,
     CannotStreamWithSelf,
     NotANeighbor,
-    VmCreationFailed(Fault),
+    NeighborRejected,
+    BadTimestamp,
+    Replayed,
+    TooManyInitiations,
+    MalformedPayload,
+    MalformedProgram,
+    WindowFull,
 }
 
+/// Default for `Agent::timestamp_window`, in the units of `AgentEnvironment::get_current_timestamp`.
+pub const DEFAULT_TIMESTAMP_WINDOW: u64 = 120;
+
+/// Default for `Agent::retransmit_interval`.
+pub const DEFAULT_RETRANSMIT_INTERVAL: u64 = 2;
+
+/// Default for `Agent::request_timeout`.
+pub const DEFAULT_REQUEST_TIMEOUT: u64 = 10;
+
+/// Default for `Agent::memory_limit`.
+pub const DEFAULT_MEMORY_LIMIT: u64 = 10_000_000;
+
+/// How many recent initiation packets are remembered for replay protection.
+const REPLAY_CACHE_CAPACITY: usize = 4096;
+
+/// How many of those may have come from any one address.
+const REPLAY_CACHE_PER_SOURCE: usize = REPLAY_CACHE_CAPACITY / 16;
+
+/// Largest datagram handed to `AgentEnvironment::send`: the IPv6 minimum MTU, less the IPv6 and
+/// UDP headers. Longer messages are split into fragments.
+pub const MAX_DATAGRAM_LEN: usize = 1280 - 40 - 8;
+
+/// Longest encoded message that fits in a datagram unfragmented.
+const MAX_UNFRAGMENTED_LEN: usize = MAX_DATAGRAM_LEN - CONTENTFUL_PACKET_THRESHOLD - content_packet::FRAME_LENGTH_LEN;
+
+/// Limits how much of a program's serialization is atoms, matching what the VM allows itself.
+const MAX_PROGRAM_LEN: usize = vm::MAX_SERIALIZED_LEN;
+
+/// Storage key for the generation of the latest snapshot. It ends in a tag, 3, that neither
+/// values stored by programs nor spent `EXECUTE_AS` counters do.
+const SNAPSHOT_GENERATION_KEY: &'static [u8] = b"snapshot generation\x03";
+
 pub const CONTENTFUL_PACKET_THRESHOLD: usize = 
     8 + // packet identifier
     4 + // length
@@ -73,15 +245,42 @@ pub const CONTENTFUL_PACKET_THRESHOLD: usize =
 impl<E:AgentEnvironment+Rng> Agent<E> {
     pub fn new(identity_seed: &[u8; 32], environment: E) -> Agent<E> {
         let (private_key, identity_bytes) = ed25519::keypair(&identity_seed[..]);
+        let mut secret = [0u8; 32];
+        Blake2b::blake2b(&mut secret[..], &identity_seed[..], b"hoplight vm secret");
         Agent{
             identity: Identity::from_bytes(&identity_bytes),
             private_key: private_key,
+            secret: secret,
             neighbors: HashMap::new(),
             upcoming_packets: ExpectedPacketSet::new(),
             environment: environment,
+            ledger: Ledger::new(LedgerConfig::default()),
+            cost_model: Box::new(DefaultCostModel),
+            neighbor_policy: Box::new(DefaultNeighborPolicy::default()),
+            timestamp_window: DEFAULT_TIMESTAMP_WINDOW,
+            recent_initiations: ReplayCache::new(REPLAY_CACHE_CAPACITY, REPLAY_CACHE_PER_SOURCE),
+            rekey_schedule: RekeySchedule::default(),
+            retransmit_interval: DEFAULT_RETRANSMIT_INTERVAL,
+            reassembly_limits: ReassemblyLimits::default(),
+            routing_table: RoutingTable::new(&Identity::from_bytes(&identity_bytes), routing_table::DEFAULT_BUCKET_SIZE),
+            lookups: HashMap::new(),
+            next_lookup: 0,
+            lookup_timeout: lookup::DEFAULT_LOOKUP_TIMEOUT,
+            requests: HashMap::new(),
+            next_request: 0,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            memory_limit: DEFAULT_MEMORY_LIMIT,
         }
     }
 
+    pub fn identity(&self) -> Identity {
+        self.identity
+    }
+    
+    pub fn is_neighbor(&self, identity: &Identity) -> bool {
+        self.neighbors.contains_key(identity)
+    }
+    
     pub fn handle_packet(&mut self, source: &IpAddressPort, packet: &[u8]) {
         if packet.len() >= CONTENTFUL_PACKET_THRESHOLD {
             match self.handle_contentful_packet(packet) {
@@ -95,7 +294,10 @@ impl<E:AgentEnvironment+Rng> Agent<E> {
                     println!("handle_initiation_packet failed: {:?}", e);
                 }
                 _ => {
-                    //println!("TODO");
+                    // A stream a lookup was waiting on may be ready now.
+                    if !self.lookups.is_empty() {
+                        self.continue_lookups();
+                    }
                 }
             }
         }
@@ -117,7 +319,7 @@ impl<E:AgentEnvironment+Rng> Agent<E> {
                 return Err(HandleError::InternalError)
             };
             
-            if let Ok(payload) = neighbor_state.streams.decrypt_incoming_payload(&expected_packet.stream_key, expected_packet.packet_number, parts.encrypted_payload, parts.checksum) {
+            if let Ok(payload) = neighbor_state.streams.decrypt_incoming_payload(expected_packet.stream, expected_packet.packet_number, parts.encrypted_payload, parts.checksum) {
                 found = Some( (*expected_packet, payload) );
                 //found_neighbor_state = Some(neighbor_state);
                 break;
@@ -130,23 +332,165 @@ impl<E:AgentEnvironment+Rng> Agent<E> {
         
         // We just want to hoist that variable out of the loop in there, but the borrow checker won't let us.
         let neighbor_state = self.neighbors.get_mut(&expected_packet.stream_with).unwrap();
+        neighbor_state.last_heard_from = self.environment.get_current_timestamp();
+        self.routing_table.observe(&expected_packet.stream_with, &neighbor_state.address, neighbor_state.last_heard_from);
         
         neighbor_state.streams.got_incoming_packet(&expected_packet, parts.packet_identifier, &mut self.upcoming_packets);
         //self.upcoming_packets.remove(&expected_packet, parts.packet_identifier);
         
-        let payload_words = vm::le_bytes_to_words(&payload);
+        let neighbor = expected_packet.stream_with;
+        let message = try!(Message::decode(try!(content_packet::unframe_payload(&payload))));
+        self.handle_message(&neighbor, message)
+    }
+    
+    fn handle_message(&mut self, neighbor: &Identity, message: Message) -> Result<(), HandleError> {
+        let neighbor = *neighbor;
+        match message {
+            Message::Unreliable(body) => {
+                try!(self.execute_program(&neighbor, None, body));
+            }
+            Message::Reliable(sequence, body) => {
+                let (deliverable, next_expected) = {
+                    let reliable = &mut self.neighbors.get_mut(&neighbor).unwrap().reliable;
+                    (reliable.receive(sequence, body), reliable.next_expected())
+                };
+                
+                for body in deliverable.iter() {
+                    // A malformed program only spoils itself. Those after it have been delivered too.
+                    let _ = self.execute_program(&neighbor, None, &body[..]);
+                }
+                
+                // Acknowledge even duplicates, since the sender evidently missed our last acknowledgement.
+                // The channel has already moved past what was delivered, so if this fails, the next
+                // duplicate is acknowledged instead.
+                let _ = self.send_message(&neighbor, &Message::Acknowledgement(next_expected));
+            }
+            Message::Acknowledgement(next_expected) => {
+                let acknowledged = self.neighbors.get_mut(&neighbor).unwrap().reliable.acknowledge(next_expected);
+                for sequence in acknowledged.into_iter() {
+                    self.environment.acknowledged(&neighbor, sequence);
+                }
+            }
+            Message::Fragment(fragment) => {
+                let now = self.environment.get_current_timestamp();
+                let limits = self.reassembly_limits;
+                let reassembled = try!(self.neighbors.get_mut(&neighbor).unwrap().reassembler.receive(&fragment, now, &limits));
+                if let Some(reassembled) = reassembled {
+                    match try!(Message::decode(&reassembled[..])) {
+                        Message::Fragment(_) => { return Err(HandleError::MalformedPayload); }
+                        message => { try!(self.handle_message(&neighbor, message)); }
+                    }
+                }
+            }
+            Message::FindNode(lookup, target) => {
+                let nodes: Vec<(Identity, IpAddressPort)> = self.routing_table.closest(&target, routing_table::DEFAULT_BUCKET_SIZE + 1).into_iter()
+                    .filter(|contact| contact.identity != neighbor)
+                    .take(routing_table::DEFAULT_BUCKET_SIZE)
+                    .map(|contact| (contact.identity, contact.address))
+                    .collect();
+                try!(self.send_message(&neighbor, &Message::Nodes(lookup, nodes)));
+            }
+            Message::Nodes(lookup, nodes) => {
+                let own_identity = self.identity;
+                let nodes: Vec<(Identity, IpAddressPort)> = nodes.into_iter().filter(|&(identity, _)| identity != own_identity).collect();
+                let answered = self.lookups.get_mut(&lookup).map(|lookup| lookup.responded(&neighbor, &nodes[..])).unwrap_or(false);
+                if answered {
+                    self.continue_lookups();
+                }
+            }
+            Message::Request(request, body) => {
+                if let Err(e) = self.execute_program(&neighbor, Some(request), body) {
+                    // Otherwise the requestor would wait out the timeout without learning why.
+                    self.respond(&neighbor, request, 0, &Err(EvalError::MalformedProgram));
+                    return Err(e);
+                }
+            }
+            Message::Response(request, ticks, result) => {
+                // Only the neighbor that was asked may answer, and only once.
+                if self.requests.get(&request).map(|outstanding| outstanding.neighbor == neighbor).unwrap_or(false) {
+                    self.requests.remove(&request);
+                    
+                    // We cannot check what the neighbor says it charged, but it can only claim this
+                    // for work we asked for, and no more than we would grant one request ourselves.
+                    let now = self.environment.get_current_timestamp();
+                    let credited = min(ticks, self.ledger.config().max_ticks);
+                    self.ledger.record_work_done_for_us(&neighbor, credited, now);
+                    
+                    let result = match result {
+                        Ok(serialized) => vm::deserialize(serialized).map_err(|_| RequestError::MalformedResult),
+                        Err(e) => Err(RequestError::Eval(e)),
+                    };
+                    self.environment.responded(&neighbor, request, result);
+                }
+            }
+        }
         
-        let vm = try!(Vm::new(&payload_words).map_err(|e| 
-            HandleError::VmCreationFailed(e)
+        Ok( () )
+    }
+    
+    fn execute_program(&mut self, requestor: &Identity, request: Option<u64>, serialized: &[u8]) -> Result<(), HandleError> {
+        let program = try!(vm::deserialize(serialized).map_err(|_| 
+            HandleError::MalformedProgram
         ));
         
-        self.environment.execute(Task{ requestor: expected_packet.stream_with, vm: vm});
-        
+        self.environment.execute(Task{ requestor: *requestor, program: program, request: request });
         Ok( () )
     }
     
-    pub fn check_timestamp(&self, _timestamp: u64) -> Result<(), HandleError> {
-        // TODO: If this is too different from now, return a BadTimestamp error.
+    /// Evaluate a task that was handed to `AgentEnvironment::execute`.
+    ///
+    /// Messages the program sent are delivered afterwards, even if evaluation failed part way
+    /// through, since the requestor has already paid for them. If the task came from
+    /// `send_request`, the result is sent back to the requestor too.
+    pub fn run_task(&mut self, task: Task) -> Result<Noun, EvalError> {
+        let (result, ticks_consumed, outgoing, neighboring_requests) = {
+            let mut engine = AgentSideEffectEngine::new(&mut self.environment, &mut self.ledger, &task.requestor, &self.identity, &self.neighbors, &self.routing_table, &self.secret);
+            let mut ticks = Ticks::new(engine.requestor_ticks());
+            let (result, report) = vm::eval_with_report(task.program, &mut engine, &*self.cost_model, &mut ticks, &mut Ticks::new(self.memory_limit));
+            (result, report.total_ticks(), engine.outgoing, engine.neighboring_requests)
+        };
+        
+        // Ticks spent executing as someone else are the requestor's to pay for, too.
+        let now = self.environment.get_current_timestamp();
+        self.ledger.record_ticks_consumed(&task.requestor, ticks_consumed, now);
+        
+        for (destination, message) in outgoing.into_iter() {
+            // The engine only accepted messages for neighbors whose streams are ready.
+            let _ = self.send_to(&destination, &message[..]);
+        }
+        
+        for (identity, address) in neighboring_requests.into_iter() {
+            // The engine already screened out ourselves and existing neighbors, so this cannot fail.
+            let _ = self.initiate_stream_with(&identity, &address);
+        }
+        
+        if let Some(request) = task.request {
+            self.respond(&task.requestor, request, ticks_consumed, &result);
+        }
+        result
+    }
+    
+    fn respond(&mut self, requestor: &Identity, request: u64, ticks: u64, result: &Result<Noun, EvalError>) {
+        let serialized = match *result {
+            Ok(ref noun) => vm::serialize(noun, MAX_PROGRAM_LEN).map_err(|_| EvalError::MemoryExceeded),
+            Err(ref e) => Err(e.clone()),
+        };
+        let sent = match serialized {
+            Ok(ref serialized) => self.send_message(requestor, &Message::Response(request, ticks, Ok(&serialized[..]))),
+            Err(e) => self.send_message(requestor, &Message::Response(request, ticks, Err(e))),
+        };
+        if let Err(HandleError::InternalLimitExceeded) = sent {
+            // The result is too long to send, which the requestor should still hear about.
+            let _ = self.send_message(requestor, &Message::Response(request, ticks, Err(EvalError::MemoryExceeded)));
+        }
+    }
+    
+    pub fn check_timestamp(&self, timestamp: u64) -> Result<(), HandleError> {
+        let now = self.environment.get_current_timestamp();
+        let difference = if now > timestamp { now - timestamp } else { timestamp - now };
+        if difference > self.timestamp_window {
+            return Err(HandleError::BadTimestamp);
+        }
         Ok( () )
     }
     
@@ -176,12 +520,20 @@ impl<E:AgentEnvironment+Rng> Agent<E> {
         
         try!(self.check_timestamp(inner_parts.timestamp));
         
-        // There might eventually be a policy decision here where we decide whether or not it is worth keeping this
-        // this new neighbor in memory. For now, we will just accept them.
+        // Anyone can sign initiations with identities they mint, so each source address only gets
+        // a share of the cache. Packets from enough addresses can still flush it; see `ReplayCache`.
+        let oldest_acceptable = self.environment.get_current_timestamp().saturating_sub(self.timestamp_window);
+        match self.recent_initiations.insert(parts.ephemeral_public_key, source, inner_parts.timestamp, oldest_acceptable) {
+            Ok( () ) => {}
+            Err(Rejection::Replayed) => { return Err(HandleError::Replayed); }
+            Err(Rejection::SourceFull) => { return Err(HandleError::TooManyInitiations); }
+        }
         
         let neighbor_is_known = 
             if let Some(neighbor_state) = self.neighbors.get_mut(&sender_identity) {
                 neighbor_state.address = *source;
+                neighbor_state.last_heard_from = self.environment.get_current_timestamp();
+                self.routing_table.observe(&sender_identity, source, neighbor_state.last_heard_from);
                 
                 neighbor_state.streams.push_neighbor_key_material(&parts.ephemeral_public_key, &mut self.upcoming_packets);
                 
@@ -192,23 +544,68 @@ impl<E:AgentEnvironment+Rng> Agent<E> {
         
         if !neighbor_is_known {
             let neighbor_is_later = try!(sender_identity.is_greater_than(&self.identity).map_err(|_| HandleError::CannotStreamWithSelf));
-            let own_seed = { let mut bs = [0u8;32]; self.environment.fill_bytes(&mut bs); bs };
+            try!(self.make_room_for(&sender_identity));
+            let mut own_seed = { let mut bs = [0u8;32]; self.environment.fill_bytes(&mut bs); bs };
             
             self.send_initiation_packet(&sender_identity, source, &own_seed);
             
+            let now = self.environment.get_current_timestamp();
             let mut n = NeighborState {
                 address: *source,
                 streams: StreamCluster::new(&sender_identity, neighbor_is_later),
+                last_heard_from: now,
+                rekeyed_at: now,
+                sent_since_rekey: 0,
+                reliable: ReliableChannel::new(),
+                reassembler: Reassembler::new(),
+                next_fragmented_message: 0,
             };
             n.streams.push_neighbor_key_material(&parts.ephemeral_public_key, &mut self.upcoming_packets);
             n.streams.push_own_seed(&own_seed, &mut self.upcoming_packets);
+            stream::wipe(&mut own_seed);
             
+            self.routing_table.observe(&sender_identity, source, now);
             self.neighbors.insert(sender_identity, n);
         }
         
         Ok( () )
     }
     
+    /// Ask the neighbor policy whether `candidate` may become a neighbor, evicting someone if
+    /// we are already at capacity.
+    fn make_room_for(&mut self, candidate: &Identity) -> Result<(), HandleError> {
+        if !self.neighbor_policy.admit(candidate) {
+            return Err(HandleError::NeighborRejected);
+        }
+        
+        let now = self.environment.get_current_timestamp();
+        while self.neighbors.len() >= self.neighbor_policy.max_neighbors() {
+            let summaries: Vec<NeighborSummary> = {
+                let ledger = &mut self.ledger;
+                self.neighbors.iter().map(|(identity, neighbor_state)| NeighborSummary{
+                    identity: *identity,
+                    balance: ledger.balance(identity, now),
+                    last_heard_from: neighbor_state.last_heard_from,
+                }).collect()
+            };
+            
+            match self.neighbor_policy.choose_eviction(&summaries[..], now) {
+                Some(ref evicted) if self.neighbors.contains_key(evicted) => self.evict(evicted),
+                _ => { return Err(HandleError::NeighborRejected); }
+            }
+        }
+        
+        Ok( () )
+    }
+    
+    /// Stop streaming with a neighbor. Its balance stays in the ledger, so leaving and coming
+    /// back does not wipe out a debt.
+    pub fn evict(&mut self, neighbor: &Identity) {
+        if self.neighbors.remove(neighbor).is_some() {
+            self.upcoming_packets.remove_stream_with(neighbor);
+        }
+    }
+    
     fn form_initiation_packet(&self, neighbor_identity: &Identity, own_seed: &[u8; 32]) -> [u8; 152] {
         let (stream_private, stream_public) = ed25519::keypair(&own_seed[..]);
         let symmetric_key = ed25519::exchange(&neighbor_identity.as_bytes()[..], &stream_private[..]);
@@ -248,142 +645,437 @@ impl<E:AgentEnvironment+Rng> Agent<E> {
     }
     
     pub fn initiate_stream_with(&mut self, neighbor_identity: &Identity, neighbor_location: &IpAddressPort) -> Result<(), HandleError> {
-        let own_seed = { let mut bs = [0u8;32]; self.environment.fill_bytes(&mut bs); bs };
+        let mut own_seed = { let mut bs = [0u8;32]; self.environment.fill_bytes(&mut bs); bs };
      
         let neighbor_is_later = try!(neighbor_identity.is_greater_than(&self.identity).map_err(|_| HandleError::CannotStreamWithSelf));
+        if self.neighbors.contains_key(neighbor_identity) {
+            // Start over with fresh streams.
+            self.evict(neighbor_identity);
+        } else {
+            try!(self.make_room_for(neighbor_identity));
+        }
         
+        let now = self.environment.get_current_timestamp();
         let mut n = NeighborState {
             address: *neighbor_location,
             streams: StreamCluster::new(neighbor_identity, neighbor_is_later),
+            last_heard_from: now,
+            rekeyed_at: now,
+            sent_since_rekey: 0,
+            reliable: ReliableChannel::new(),
+            reassembler: Reassembler::new(),
+            next_fragmented_message: 0,
         };
         n.streams.push_own_seed( &own_seed, &mut self.upcoming_packets );
         
-        let packet = self.form_initiation_packet(neighbor_identity, &own_seed);
-        self.environment.send(neighbor_location, &packet[..]);
+        self.send_initiation_packet(neighbor_identity, neighbor_location, &own_seed);
+        stream::wipe(&mut own_seed);
         
         self.neighbors.insert(*neighbor_identity, n);
         
         Ok( () )
     }
     
+    /// Send a payload once, with no indication of whether it arrived.
     pub fn send_to(&mut self, neighbor: &Identity, payload: &[u8]) -> Result<(), HandleError> {
+        self.send_message(neighbor, &Message::Unreliable(payload))
+    }
+    
+    /// Send a payload that will be retransmitted until the neighbor acknowledges it, and be
+    /// delivered after everything sent reliably before it. Returns a sequence number, which is
+    /// later passed to `AgentEnvironment::acknowledged`.
+    pub fn send_reliably(&mut self, neighbor: &Identity, payload: &[u8]) -> Result<u64, HandleError> {
+        let now = self.environment.get_current_timestamp();
+        let sequence = match self.neighbors.get_mut(neighbor) {
+            Some(neighbor_state) => try!(neighbor_state.reliable.push_outgoing(payload, now)),
+            None => { return Err(HandleError::NotANeighbor); }
+        };
+        
+        match self.send_message(neighbor, &Message::Reliable(sequence, payload)) {
+            // Once the stream is ready, retransmission will take care of it.
+            Ok( () ) | Err(HandleError::StreamNotReady) => Ok(sequence),
+            Err(e) => {
+                if let Some(neighbor_state) = self.neighbors.get_mut(neighbor) {
+                    neighbor_state.reliable.cancel_latest();
+                }
+                Err(e)
+            }
+        }
+    }
+    
+    /// Send again every reliable message that has gone `retransmit_interval` without being
+    /// acknowledged. The environment should call this periodically.
+    pub fn retransmit_unacknowledged(&mut self) {
+        let now = self.environment.get_current_timestamp();
+        let interval = self.retransmit_interval;
+        let mut due = Vec::new();
+        for (identity, neighbor_state) in self.neighbors.iter_mut() {
+            let overdue = neighbor_state.reliable.due_for_retransmission(now, interval);
+            if overdue.len() > 0 {
+                self.routing_table.record_failure(identity);
+            }
+            for (sequence, body) in overdue.into_iter() {
+                due.push((*identity, sequence, body));
+            }
+        }
+        
+        for (neighbor, sequence, body) in due.into_iter() {
+            let _ = self.send_message(&neighbor, &Message::Reliable(sequence, &body[..]));
+        }
+    }
+    
+    fn send_message(&mut self, neighbor: &Identity, message: &Message) -> Result<(), HandleError> {
+        let encoded = message.encode();
+        if encoded.len() <= MAX_UNFRAGMENTED_LEN {
+            return self.send_packet(neighbor, &encoded[..]);
+        }
+        if encoded.len() > self.reassembly_limits.max_message_len {
+            return Err(HandleError::InternalLimitExceeded);
+        }
+        
+        let message_id = if let Some(neighbor_state) = self.neighbors.get_mut(neighbor) {
+            neighbor_state.next_fragmented_message += 1;
+            neighbor_state.next_fragmented_message
+        } else {
+            return Err(HandleError::NotANeighbor);
+        };
+        
+        for fragment in try!(fragment::split(message_id, &encoded[..], MAX_UNFRAGMENTED_LEN - fragment::HEADER_LEN)).into_iter() {
+            try!(self.send_packet(neighbor, &Message::Fragment(fragment).encode()[..]));
+        }
+        Ok( () )
+    }
+    
+    /// Encrypt an encoded message into a single content packet and send it.
+    fn send_packet(&mut self, neighbor: &Identity, encoded: &[u8]) -> Result<(), HandleError> {
         let mut neighbor_state = if let Some(x) = self.neighbors.get_mut(neighbor) { x } else {
             return Err(HandleError::NotANeighbor);
         };
         
+        let framed = try!(content_packet::frame_payload(encoded));
         let (identifier, mut keystream) = try!(neighbor_state.streams.produce_outgoing_identifier());
         
-        let packet_size = payload.len() + CONTENTFUL_PACKET_THRESHOLD; // TODO
+        let packet_size = framed.len() + CONTENTFUL_PACKET_THRESHOLD; // TODO
         let mut buffer: Vec<u8> = iter::repeat(0).take(packet_size).collect();
         {
-            let mut packet_writer = try!(ContentPacket::prepare(&mut buffer[..], payload.len(), identifier, &mut self.environment));
-            keystream.encrypt(payload, packet_writer.encrypted_payload, packet_writer.checksum);
+            let mut packet_writer = try!(ContentPacket::prepare(&mut buffer[..], framed.len(), identifier, &mut self.environment));
+            keystream.encrypt(&framed[..], packet_writer.encrypted_payload, packet_writer.checksum);
         }
         self.environment.send(&neighbor_state.address, &buffer[..]);
+        neighbor_state.sent_since_rekey += 1;
+        
+        let now = self.environment.get_current_timestamp();
+        if self.rekey_schedule.is_due(neighbor_state, now) {
+            self.rekey(neighbor);
+        }
         
         Ok( () )
     }
-}
-
-
-
-#[cfg(test)]
-mod test{
-    use rand::chacha::ChaChaRng;
-    use rand::{Rng, SeedableRng};
-    use super::{Agent, AgentEnvironment, Task};
-    use ip_address_port::IpAddressPort;
-    use vm::{self};
-
-    struct DummyEnvironment {
-        rng: ChaChaRng,
-        outgoing: Vec<(IpAddressPort, Vec<u8>)>,
-        location: IpAddressPort,
-        tasks: Vec<Task>,
-    }
-    
-    impl DummyEnvironment {
-        fn new(seed: u32, location: IpAddressPort) -> DummyEnvironment {
-            DummyEnvironment{
-                rng: ChaChaRng::from_seed(&[seed]),
-                outgoing: Vec::new(),
-                location: location,
-                tasks: Vec::new(),
-            }
+    
+    /// Rekey with every neighbor that `rekey_schedule` says is due. Sending checks this for the
+    /// neighbor being sent to, so this only needs to be called periodically for neighbors we
+    /// might otherwise go a long time without sending to.
+    pub fn rekey_due_neighbors(&mut self) {
+        let now = self.environment.get_current_timestamp();
+        let due: Vec<Identity> = {
+            let schedule = &self.rekey_schedule;
+            self.neighbors.iter()
+                .filter(|&(_, neighbor_state)| schedule.is_due(neighbor_state, now))
+                .map(|(identity, _)| *identity)
+                .collect()
+        };
+        
+        for neighbor in due.iter() {
+            self.rekey(neighbor);
         }
     }
     
-    impl Rng for DummyEnvironment {
-        fn next_u32(&mut self) -> u32 {
-            self.rng.next_u32()
+    /// Send a neighbor fresh key material. The streams keyed by the old material are kept until
+    /// the neighbor shows it has the new one.
+    pub fn rekey(&mut self, neighbor: &Identity) {
+        let now = self.environment.get_current_timestamp();
+        let mut fresh_seed = { let mut bs = [0u8;32]; self.environment.fill_bytes(&mut bs); bs };
+        
+        let (address, mut own_seed) = {
+            let neighbor_state = if let Some(x) = self.neighbors.get_mut(neighbor) { x } else { return };
+            neighbor_state.rekeyed_at = now;
+            neighbor_state.sent_since_rekey = 0;
+            
+            // If the neighbor has not used our current key material yet, the packet carrying it may
+            // have been lost. Replacing it would leave us with no stream the neighbor knows about,
+            // so send the same material again instead.
+            let own_seed = match neighbor_state.streams.unacknowledged_seed() {
+                Some(seed) => seed,
+                None => {
+                    neighbor_state.streams.push_own_seed(&fresh_seed, &mut self.upcoming_packets);
+                    fresh_seed
+                }
+            };
+            (neighbor_state.address, own_seed)
+        };
+        
+        self.send_initiation_packet(neighbor, &address, &own_seed);
+        stream::wipe(&mut own_seed);
+        stream::wipe(&mut fresh_seed);
+    }
+    
+    /// Send a program for the neighbor to run on our behalf.
+    pub fn send_program(&mut self, neighbor: &Identity, program: &Noun) -> Result<(), HandleError> {
+        let serialized = try!(vm::serialize(program, MAX_PROGRAM_LEN).map_err(|_| HandleError::InternalLimitExceeded));
+        self.send_to(neighbor, &serialized[..])
+    }
+    
+    /// Send a program with `send_reliably`.
+    pub fn send_program_reliably(&mut self, neighbor: &Identity, program: &Noun) -> Result<u64, HandleError> {
+        let serialized = try!(vm::serialize(program, MAX_PROGRAM_LEN).map_err(|_| HandleError::InternalLimitExceeded));
+        self.send_reliably(neighbor, &serialized[..])
+    }
+    
+    /// Send a program for the neighbor to run, and send back the result. Returns a number for
+    /// the request, which is passed to `AgentEnvironment::responded` along with the result, or
+    /// with `RequestError::TimedOut` if none arrives within `request_timeout`.
+    ///
+    /// Like `send_program`, the request is sent only once, so it may be lost. A response credits
+    /// the neighbor in the ledger with the ticks it says it charged, up to `LedgerConfig::max_ticks`.
+    pub fn send_request(&mut self, neighbor: &Identity, program: &Noun) -> Result<u64, HandleError> {
+        let serialized = try!(vm::serialize(program, MAX_PROGRAM_LEN).map_err(|_| HandleError::InternalLimitExceeded));
+        let request = self.next_request;
+        try!(self.send_message(neighbor, &Message::Request(request, &serialized[..])));
+        self.next_request += 1;
+        
+        let now = self.environment.get_current_timestamp();
+        self.requests.insert(request, OutstandingRequest{ neighbor: *neighbor, sent_at: now });
+        Ok(request)
+    }
+    
+    /// Give up on requests that have gone `request_timeout` without a response. The environment
+    /// should call this periodically.
+    pub fn expire_requests(&mut self) {
+        let now = self.environment.get_current_timestamp();
+        let timeout = self.request_timeout;
+        let mut expired: Vec<(u64, Identity)> = self.requests.iter()
+            .filter(|&(_, outstanding)| now.saturating_sub(outstanding.sent_at) >= timeout)
+            .map(|(request, outstanding)| (*request, outstanding.neighbor))
+            .collect();
+        expired.sort_by_key(|&(request, _)| request);
+        
+        for (request, neighbor) in expired.into_iter() {
+            self.requests.remove(&request);
+            self.environment.responded(&neighbor, request, Err(RequestError::TimedOut));
         }
     }
     
-    impl AgentEnvironment for DummyEnvironment{
-        fn get_current_timestamp(&self) -> u64 {
-            123456
+    /// Start searching for the agents closest to `target`, beginning with those we already know.
+    /// Returns a number for the lookup, to pass to `finished_lookup`.
+    ///
+    /// Agents are queried over streams, so those that are queried become neighbors.
+    pub fn start_lookup(&mut self, target: &Identity) -> u64 {
+        let mut lookup = Lookup::new(target, routing_table::DEFAULT_BUCKET_SIZE);
+        for contact in self.routing_table.closest(target, routing_table::DEFAULT_BUCKET_SIZE).iter() {
+            lookup.add(&contact.identity, &contact.address);
         }
-        
-        fn send(&mut self, dest: &IpAddressPort, packet: &[u8]) {
-            self.outgoing.push((*dest, packet.to_vec()))
+        for (identity, neighbor_state) in self.neighbors.iter() {
+            lookup.add(identity, &neighbor_state.address);
         }
         
-        fn execute(&mut self, task: Task) {
-            self.tasks.push(task);
+        let id = self.next_lookup;
+        self.next_lookup += 1;
+        self.lookups.insert(id, lookup);
+        self.continue_lookups();
+        id
+    }
+    
+    /// Look up our own identity. Agents near it are the ones we are most likely to be asked
+    /// about, and learning of them fills the routing table.
+    pub fn bootstrap(&mut self) -> u64 {
+        let own_identity = self.identity;
+        self.start_lookup(&own_identity)
+    }
+    
+    /// Send the queries that lookups are waiting on and give up on agents that have not
+    /// answered. The environment should call this periodically.
+    pub fn continue_lookups(&mut self) {
+        let now = self.environment.get_current_timestamp();
+        let timeout = self.lookup_timeout;
+        let ids: Vec<u64> = self.lookups.keys().cloned().collect();
+        for id in ids.into_iter() {
+            let (expired, chosen) = {
+                let lookup = self.lookups.get_mut(&id).unwrap();
+                (lookup.expire(now, timeout), lookup.choose_queries(now))
+            };
+            for identity in expired.iter() {
+                self.routing_table.record_failure(identity);
+            }
+            for (identity, address) in chosen.into_iter() {
+                if !self.neighbors.contains_key(&identity) && self.initiate_stream_with(&identity, &address).is_err() {
+                    self.lookups.get_mut(&id).unwrap().mark_failed(&identity);
+                }
+            }
+            
+            let (target, unsent) = {
+                let lookup = &self.lookups[&id];
+                (lookup.target, lookup.unsent())
+            };
+            for identity in unsent.into_iter() {
+                match self.send_message(&identity, &Message::FindNode(id, target)) {
+                    Ok( () ) => { self.lookups.get_mut(&id).unwrap().mark_sent(&identity); }
+                    // Sent once the neighbor has answered our initiation packet.
+                    Err(HandleError::StreamNotReady) => {}
+                    Err(_) => { self.lookups.get_mut(&id).unwrap().mark_failed(&identity); }
+                }
+            }
         }
     }
-
-    #[derive(Debug)]
-    struct InFlightPacket {
-        source: IpAddressPort,
-        destination: IpAddressPort,
-        contents: Vec<u8>,
+    
+    /// The agents closest to the target of a finished lookup, closest first. Returns `None`,
+    /// and keeps the lookup going, if it has not finished.
+    pub fn finished_lookup(&mut self, lookup: u64) -> Option<Vec<(Identity, IpAddressPort)>> {
+        if self.lookups.get(&lookup).map(|lookup| lookup.is_finished()).unwrap_or(false) {
+            self.lookups.remove(&lookup).map(|lookup| lookup.closest())
+        } else {
+            None
+        }
+    }
+    
+    fn snapshot_key(&self) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        Blake2b::blake2b(&mut key[..], &self.secret[..], b"hoplight snapshot");
+        key
+    }
+    
+    /// The generation of the latest snapshot, which is the only one that may be imported.
+    fn snapshot_generation(&mut self) -> u64 {
+        self.environment.load(SNAPSHOT_GENERATION_KEY)
+            .and_then(|bs| snapshot::read_u64(&mut &bs[..]).ok())
+            .unwrap_or(0)
     }
-    fn drain(packets_in_flight: &mut Vec<InFlightPacket>, env: &mut DummyEnvironment) {
-        let mut empty = Vec::new();
-        ::std::mem::swap(&mut empty, &mut env.outgoing);
+    
+    fn set_snapshot_generation(&mut self, generation: u64) {
+        let mut bs = Vec::new();
+        snapshot::write_u64(&mut bs, generation);
+        self.environment.store(SNAPSHOT_GENERATION_KEY, &bs[..]);
+    }
+    
+    /// Save what is needed to resume streaming with every neighbor after a restart: addresses,
+    /// seeds, key material, and stream and sequence counters. The snapshot is encrypted under a
+    /// key only this identity can derive.
+    ///
+    /// Sending after the snapshot was taken, or importing it more than once, would reuse
+    /// nonces. So this consumes the agent, handing back its environment, and only the latest
+    /// snapshot taken with that environment's storage can be imported, once.
+    pub fn export_state(mut self) -> (Vec<u8>, E) {
+        let generation = self.snapshot_generation() + 1;
+        self.set_snapshot_generation(generation);
         
-        for (destination, contents) in empty.into_iter() {
-            packets_in_flight.push(InFlightPacket{
-                source: env.location,
-                destination: destination,
-                contents: contents,
-            });
+        let mut plaintext = Vec::new();
+        snapshot::write_u64(&mut plaintext, generation);
+        snapshot::write_u64(&mut plaintext, self.neighbors.len() as u64);
+        for (identity, neighbor_state) in self.neighbors.iter() {
+            plaintext.extend_from_slice(&identity.as_bytes()[..]);
+            plaintext.extend_from_slice(&neighbor_state.address.as_bytes()[..]);
+            snapshot::write_u64(&mut plaintext, neighbor_state.last_heard_from);
+            snapshot::write_u64(&mut plaintext, neighbor_state.rekeyed_at);
+            snapshot::write_u64(&mut plaintext, neighbor_state.sent_since_rekey);
+            snapshot::write_u64(&mut plaintext, neighbor_state.next_fragmented_message);
+            neighbor_state.streams.export(&mut plaintext);
+            neighbor_state.reliable.export(&mut plaintext);
         }
+        
+        let mut key = self.snapshot_key();
+        let sealed = snapshot::seal(&key, &plaintext[..], &mut self.environment);
+        stream::wipe(&mut key);
+        stream::wipe(&mut plaintext[..]);
+        (sealed, self.environment)
     }
     
-    fn exchange(agents: &mut [&mut Agent<DummyEnvironment>]) {
-        let mut in_flights = Vec::new();
-        for agent in agents.iter_mut() {
-            drain(&mut in_flights, &mut agent.environment);
-        }
+    /// Restore the neighbors saved by `export_state`, replacing any this agent has. Streams pick
+    /// up where they left off, without a new handshake.
+    pub fn import_state(&mut self, sealed: &[u8]) -> Result<(), SnapshotError> {
+        let mut key = self.snapshot_key();
+        let unsealed = snapshot::unseal(&key, sealed);
+        stream::wipe(&mut key);
+        let mut plaintext = try!(unsealed);
         
-        for in_flight in in_flights.into_iter() {
-            for maybe_dest in agents.iter_mut() {
-                if maybe_dest.environment.location == in_flight.destination {
-                    maybe_dest.handle_packet(&in_flight.source, &in_flight.contents[..]);
+        let mut upcoming_packets = ExpectedPacketSet::new();
+        let current_generation = self.snapshot_generation();
+        let decoded = {
+            let bs = &mut &plaintext[..];
+            snapshot::read_u64(bs).and_then(|generation| {
+                if generation != current_generation {
+                    return Err(SnapshotError::Stale);
                 }
-            }
+                self.decode_neighbors(bs, &mut upcoming_packets)
+            })
+        };
+        stream::wipe(&mut plaintext[..]);
+        self.neighbors = try!(decoded);
+        self.set_snapshot_generation(current_generation + 1);
+        self.upcoming_packets = upcoming_packets;
+        for (identity, neighbor_state) in self.neighbors.iter() {
+            self.routing_table.observe(identity, &neighbor_state.address, neighbor_state.last_heard_from);
         }
+        Ok( () )
     }
     
-    fn drain_tasks(agents: &mut [&mut Agent<DummyEnvironment>]) {
-        for agent in agents.iter_mut() {
-            agent.environment.tasks.clear();
+    fn decode_neighbors(&self, mut bs: &[u8], upcoming_packets: &mut ExpectedPacketSet) -> Result<HashMap<Identity, NeighborState>, SnapshotError> {
+        let bs = &mut bs;
+        let mut neighbors = HashMap::new();
+        for _ in 0..try!(snapshot::read_u64(bs)) {
+            let identity = Identity::from_bytes(&try!(snapshot::read_32(bs)));
+            let neighbor_is_later = try!(identity.is_greater_than(&self.identity).map_err(|_| SnapshotError::Malformed));
+            let address = try!(snapshot::read_slice(bs, ip_address_port::ENCODED_LEN));
+            let address = IpAddressPort::from_bytes(array_ref!(address, 0, ip_address_port::ENCODED_LEN));
+            let last_heard_from = try!(snapshot::read_u64(bs));
+            let rekeyed_at = try!(snapshot::read_u64(bs));
+            let sent_since_rekey = try!(snapshot::read_u64(bs));
+            let next_fragmented_message = try!(snapshot::read_u64(bs));
+            let n = NeighborState {
+                address: address,
+                streams: try!(StreamCluster::import(&identity, neighbor_is_later, bs, upcoming_packets)),
+                last_heard_from: last_heard_from,
+                rekeyed_at: rekeyed_at,
+                sent_since_rekey: sent_since_rekey,
+                reliable: try!(ReliableChannel::import(bs)),
+                reassembler: Reassembler::new(),
+                next_fragmented_message: next_fragmented_message,
+            };
+            if neighbors.insert(identity, n).is_some() {
+                return Err(SnapshotError::Malformed);
+            }
+        }
+        if bs.len() != 0 {
+            return Err(SnapshotError::Malformed);
         }
+        Ok(neighbors)
     }
-    
+}
+
+
+
+#[cfg(test)]
+mod test{
+    use super::{Agent, HandleError, OutstandingRequest, RekeySchedule, RequestError, CONTENTFUL_PACKET_THRESHOLD, DEFAULT_REQUEST_TIMEOUT, DEFAULT_RETRANSMIT_INTERVAL, MAX_DATAGRAM_LEN, MAX_PROGRAM_LEN};
+    use identity::Identity;
+    use crypto::blake2b::Blake2b;
+    use ledger::{Ledger, LedgerConfig};
+    use neighbor_policy::{DefaultNeighborPolicy, EvictionCriterion, NeighborPolicy, NeighborSummary};
+    use ip_address_port::IpAddressPort;
+    use snapshot::SnapshotError;
+    use message::Message;
+    use testing::{self, connect_all, drain_tasks, exchange, run_until_quiet, MemoryEnvironment};
+    use vm::{self, opcode, AsNoun, EvalError, Noun};
+
     #[test]
     fn initiate() {
         let mut a = Agent::new(
             &[0x93, 0xA6, 0x9B, 0xDD, 0xA2, 0xC5, 0xDD, 0x38, 0xBD, 0x90, 0xC6, 0x53, 0x8A, 0x27, 0x62, 0xB0, 
               0x33, 0xBA, 0x0E, 0x31, 0x01, 0xBD, 0xA0, 0xBA, 0xEC, 0x9F, 0x2F, 0x08, 0xD1, 0x63, 0x6A, 0x3B],
-            DummyEnvironment::new(1, IpAddressPort{address: [1,1,1,1, 1,1,1,1, 1,1,1,1, 1,1,1,1], port: 5000}));
+            MemoryEnvironment::new(1, IpAddressPort{address: [1,1,1,1, 1,1,1,1, 1,1,1,1, 1,1,1,1], port: 5000}));
         
         let mut b = Agent::new(
             &[0x1F, 0xEF, 0xEE, 0x3E, 0x90, 0x63, 0x75, 0xF0, 0xB8, 0x6B, 0x69, 0xE7, 0x83, 0x99, 0xAB, 0xBF, 
               0x35, 0x8B, 0xAD, 0x0A, 0x46, 0x3A, 0x73, 0x60, 0x82, 0xB2, 0x4A, 0x61, 0xF4, 0xEA, 0xA4, 0xBD, ],
-            DummyEnvironment::new(2, IpAddressPort{address: [2,2,2,2, 2,2,2,2, 2,2,2,2, 2,2,2,2], port: 5222}));
+            MemoryEnvironment::new(2, IpAddressPort{address: [2,2,2,2, 2,2,2,2, 2,2,2,2, 2,2,2,2], port: 5222}));
         
         a.initiate_stream_with(&b.identity, &b.environment.location).ok().expect("initiate_stream_with a->b failed");
         
@@ -394,11 +1086,9 @@ mod test{
         for round in 0..500 {
             let skip = round%21 == 0;
             
-            let sample_send = [
-                0x11223344, 0x22334455 + round, 0x33445566
-            ];
+            let sample_send = (Noun::from_u64_compact(0x22334455 + round), opcode::LITERAL, 0x33).as_noun();
             if !skip {
-                a.send_to(&b.identity, &vm::words_to_le_bytes(&sample_send)[..]).ok().expect("send_to failed");//&[1,2,3,4]);
+                a.send_program(&b.identity, &sample_send).ok().expect("send_program failed");
             }
             
             assert!(b.environment.tasks.len()==0);
@@ -410,14 +1100,630 @@ mod test{
             } else {
                 assert!(b.environment.tasks.len()==1);
                 assert_eq!(b.environment.tasks[0].requestor, a.identity);
-                assert_eq!(b.environment.tasks[0].vm.read_memory(1), 0x22334455 + round);
-                assert_eq!(b.environment.tasks[0].vm.read_memory(2), 0x33445566);
+                assert_eq!(b.environment.tasks[0].program, sample_send);
             }
             
             drain_tasks(&mut [&mut a, &mut b]);
         }
         
     }
+    
+    fn run_only_task(agent: &mut Agent<MemoryEnvironment>) -> Noun {
+        assert_eq!(agent.environment.tasks.len(), 1);
+        let task = agent.environment.tasks.remove(0);
+        agent.run_task(task).ok().expect("run_task failed")
+    }
+
+    /// Take apart the two agents `testing::agents` made, so they can be borrowed separately.
+    fn split_pair(mut agents: Vec<Agent<MemoryEnvironment>>) -> (Agent<MemoryEnvironment>, Agent<MemoryEnvironment>) {
+        assert_eq!(agents.len(), 2);
+        let b = agents.pop().unwrap();
+        (agents.pop().unwrap(), b)
+    }
+    
+    #[test]
+    fn run_stored_programs() {
+        let mut agents = testing::agents(2, 0x31);
+        connect_all(&mut agents[..]);
+        let (mut a, mut b) = split_pair(agents);
+        
+        let store = (&b"orange"[..], opcode::STORE_BY_KEY, (opcode::LITERAL, &b"color"[..]), ((opcode::LITERAL, opcode::LITERAL), (opcode::AXIS, 1))).as_noun();
+        a.send_program(&b.identity, &store).ok().expect("send_program failed");
+        exchange(&mut [&mut a, &mut b]);
+        assert_eq!(run_only_task(&mut b), Noun::from_bool(true));
+        
+        let retrieve = (&b"color"[..], opcode::RETRIEVE_BY_KEY, (opcode::AXIS, 1)).as_noun();
+        a.send_program(&b.identity, &retrieve).ok().expect("send_program failed");
+        exchange(&mut [&mut a, &mut b]);
+        assert_eq!(run_only_task(&mut b), (true, &b"orange"[..]).as_noun());
+        
+        let near = (a.identity.as_bytes().to_vec(), opcode::NEIGHBORS_NEAR, (opcode::AXIS, 1), (opcode::LITERAL, 4)).as_noun();
+        a.send_program(&b.identity, &near).ok().expect("send_program failed");
+        exchange(&mut [&mut a, &mut b]);
+        assert_eq!(run_only_task(&mut b), (a.identity.as_bytes().to_vec(), 0).as_noun());
+    }
+    
+    #[test]
+    fn ledger_limits_work() {
+        let mut agents = testing::agents(2, 0x61);
+        connect_all(&mut agents[..]);
+        let (mut a, mut b) = split_pair(agents);
+        b.ledger = Ledger::new(LedgerConfig{ half_life: 0, base_allowance: 10, max_ticks: 1000, ticks_per_stored_byte: 1 });
+        
+        // Three ticks: distribute, then one for each side.
+        let cheap = (5, (opcode::LITERAL, 6), (opcode::AXIS, 1)).as_noun();
+        for _ in 0..3 {
+            a.send_program(&b.identity, &cheap).ok().expect("send_program failed");
+            exchange(&mut [&mut a, &mut b]);
+            assert_eq!(run_only_task(&mut b), (6, 5).as_noun());
+        }
+        assert_eq!(b.ledger.account(&a.identity, 0).unwrap().ticks_consumed, 9);
+        
+        // Only one tick is left in a's allowance.
+        a.send_program(&b.identity, &cheap).ok().expect("send_program failed");
+        exchange(&mut [&mut a, &mut b]);
+        let task = b.environment.tasks.remove(0);
+        assert_eq!(b.run_task(task), Err(EvalError::TickLimitExceeded));
+        
+        b.ledger.record_work_done_for_us(&a.identity, 100, 0);
+        a.send_program(&b.identity, &cheap).ok().expect("send_program failed");
+        exchange(&mut [&mut a, &mut b]);
+        assert_eq!(run_only_task(&mut b), (6, 5).as_noun());
+    }
+    
+    #[test]
+    fn longest_program_delivered() {
+        let mut agents = testing::agents(2, 11);
+        connect_all(&mut agents[..]);
+        let b = agents[1].identity();
+        
+        // Single byte atoms take the most structure bits, making the longest serialization the
+        // VM would send. A balanced tree keeps it shallow.
+        let mut level: Vec<Noun> = (0..MAX_PROGRAM_LEN - 2).map(|_| Noun::from_u8(7)).collect();
+        while level.len() > 1 {
+            level = level.chunks(2).map(|pair| match pair.len() {
+                2 => Noun::new_cell(pair[0].clone(), pair[1].clone()),
+                _ => pair[0].clone(),
+            }).collect();
+        }
+        let program = (level.pop().unwrap(), opcode::LITERAL, 0).as_noun();
+        assert!(vm::serialize(&program, MAX_PROGRAM_LEN).ok().expect("serialize failed").len() > 1 << 20);
+        
+        agents[0].send_program(&b, &program).ok().expect("send_program failed");
+        exchange(&mut agents[..]);
+        assert_eq!(agents[1].environment.take_tasks()[0].program, program);
+    }
+    
+    #[test]
+    fn overwriting_storage_billed_once() {
+        let mut agents = testing::agents(2, 0x65);
+        connect_all(&mut agents[..]);
+        let (mut a, mut b) = split_pair(agents);
+        
+        let mut store = |b: &mut Agent<MemoryEnvironment>, value: Vec<u8>| {
+            a.send_program(&b.identity, &(0, opcode::STORE_BY_KEY, opcode::LITERAL, (5, value)).as_noun()).ok().expect("send_program failed");
+            exchange(&mut [&mut a, &mut *b]);
+            run_only_task(b);
+            b.ledger.account(&a.identity, 0).unwrap().storage_held
+        };
+        let held = store(&mut b, vec![1; 100]);
+        assert_eq!(store(&mut b, vec![2; 100]), held);
+        assert_eq!(store(&mut b, vec![3; 120]), held + 20);
+        assert_eq!(store(&mut b, vec![4; 10]), held + 20);
+    }
+    
+    #[test]
+    fn runner_ticks_billed_to_requestor() {
+        let mut agents = testing::agents(2, 0x63);
+        connect_all(&mut agents[..]);
+        let (mut a, mut b) = split_pair(agents);
+        
+        // The private key for an atom on the left is its unkeyed hash, so anyone can run as it.
+        let runner = vec![7u8; 32];
+        let mut runner_private_key = [0u8; 32];
+        Blake2b::blake2b(&mut runner_private_key[..], &runner[..], &[][..]);
+        let encrypt = (runner_private_key.to_vec(), opcode::ENCRYPT, (opcode::AXIS, 1), (opcode::LITERAL, (opcode::HASH, opcode::AXIS, 1))).as_noun();
+        a.send_program(&b.identity, &encrypt).ok().expect("send_program failed");
+        exchange(&mut [&mut a, &mut b]);
+        let body = run_only_task(&mut b);
+        let body_len = body.as_bytes().unwrap().len() as u64;
+        
+        let before = b.ledger.account(&a.identity, 0).unwrap().ticks_consumed;
+        let execute_as = (0, opcode::EXECUTE_AS, opcode::LITERAL, (9, runner, [0u8; 8].to_vec(), body)).as_noun();
+        a.send_program(&b.identity, &execute_as).ok().expect("send_program failed");
+        exchange(&mut [&mut a, &mut b]);
+        run_only_task(&mut b);
+        
+        // Two steps, deriving the key and decrypting for a, and then two steps and a hash of the
+        // three byte serialization of 9 for the runner.
+        let consumed = b.ledger.account(&a.identity, 0).unwrap().ticks_consumed - before;
+        assert_eq!(consumed, 2 + 32 + body_len + 2 + 20 + 3);
+    }
+    
+    /// Remove the initiation packets an agent is about to send, returning how many there were.
+    fn drop_initiations(env: &mut MemoryEnvironment) -> usize {
+        let before = env.outgoing.len();
+        env.outgoing.retain(|&(_, ref packet)| packet.len() >= CONTENTFUL_PACKET_THRESHOLD);
+        before - env.outgoing.len()
+    }
+    
+    fn count_initiations(env: &MemoryEnvironment) -> usize {
+        env.outgoing.iter().filter(|&&(_, ref packet)| packet.len() < CONTENTFUL_PACKET_THRESHOLD).count()
+    }
+    
+    /// Send a program each way between two neighbors, checking that both arrive. Returns how
+    /// many initiation packets `a` sent along with its program.
+    fn round_trip(a: &mut Agent<MemoryEnvironment>, b: &mut Agent<MemoryEnvironment>, round: u64, lose_initiations: bool) -> usize {
+        let to_b = (Noun::from_u64_compact(round), opcode::LITERAL, 1).as_noun();
+        a.send_program(&b.identity, &to_b).ok().expect("send_program a->b failed");
+        let initiations = if lose_initiations { drop_initiations(&mut a.environment) } else { count_initiations(&a.environment) };
+        exchange(&mut [&mut *a, &mut *b]);
+        assert_eq!(run_only_task(b), Noun::from_u8(1));
+        
+        let to_a = (Noun::from_u64_compact(round), opcode::LITERAL, 2).as_noun();
+        b.send_program(&a.identity, &to_a).ok().expect("send_program b->a failed");
+        exchange(&mut [&mut *a, &mut *b]);
+        assert_eq!(run_only_task(a), Noun::from_u8(2));
+        
+        initiations
+    }
+    
+    #[test]
+    fn rekeys_on_schedule() {
+        let mut agents = testing::agents(2, 0x91);
+        connect_all(&mut agents[..]);
+        let (mut a, mut b) = split_pair(agents);
+        a.rekey_schedule = RekeySchedule{ interval: 0, packet_limit: 3 };
+        b.rekey_schedule = RekeySchedule{ interval: 100, packet_limit: 0 };
+        
+        let mut rekeys = 0;
+        for round in 0..30 {
+            if round % 10 == 9 {
+                b.environment.now += 100;
+                b.rekey_due_neighbors();
+                rekeys += count_initiations(&b.environment);
+            }
+            rekeys += round_trip(&mut a, &mut b, round, false);
+        }
+        assert!(rekeys >= 10);
+    }
+    
+    #[test]
+    fn rekey_survives_lost_packet() {
+        let mut agents = testing::agents(2, 0x93);
+        connect_all(&mut agents[..]);
+        let (mut a, mut b) = split_pair(agents);
+        a.rekey_schedule = RekeySchedule{ interval: 0, packet_limit: 1 };
+        
+        let mut rekeys = 0;
+        for round in 0..30 {
+            // Losing two rekeys in a row means a must not move on to new key material before b
+            // has seen the last.
+            rekeys += round_trip(&mut a, &mut b, round, round % 6 == 1 || round % 6 == 2);
+        }
+        assert_eq!(rekeys, 30);
+    }
+    
+    #[test]
+    fn reliable_delivery() {
+        let mut agents = testing::agents(2, 0xa1);
+        connect_all(&mut agents[..]);
+        let (mut a, mut b) = split_pair(agents);
+        
+        let programs: Vec<Noun> = (0..5).map(|idx| (idx, opcode::LITERAL, idx + 10).as_noun()).collect();
+        for (idx, program) in programs.iter().enumerate() {
+            assert_eq!(a.send_program_reliably(&b.identity, program).ok(), Some(idx as u64));
+            if idx == 1 || idx == 3 {
+                a.environment.outgoing.pop();
+            }
+        }
+        
+        // b can only run the first, and its acknowledgements are lost too.
+        exchange(&mut [&mut a, &mut b]);
+        assert_eq!(b.environment.tasks.len(), 1);
+        b.environment.outgoing.clear();
+        
+        a.retransmit_unacknowledged();
+        assert!(a.environment.outgoing.is_empty());
+        a.environment.now += DEFAULT_RETRANSMIT_INTERVAL;
+        a.retransmit_unacknowledged();
+        assert_eq!(a.environment.outgoing.len(), 5);
+        
+        for _ in 0..2 {
+            exchange(&mut [&mut a, &mut b]);
+        }
+        let delivered: Vec<Noun> = b.environment.tasks.iter().map(|task| task.program.clone()).collect();
+        assert_eq!(delivered, programs);
+        assert_eq!(a.environment.acknowledged, (0..5).map(|sequence| (b.identity, sequence)).collect::<Vec<_>>());
+        
+        a.environment.now += DEFAULT_RETRANSMIT_INTERVAL;
+        a.retransmit_unacknowledged();
+        assert!(a.environment.outgoing.is_empty());
+    }
+    
+    #[test]
+    fn reliable_delivery_survives_failed_acknowledgement() {
+        let mut agents = testing::agents(2, 10);
+        let a = agents[0].identity();
+
+        // b has not heard back from a yet, so it has no stream to acknowledge on.
+        testing::connect(&mut agents[..], 1, 0);
+        let program = (1, opcode::LITERAL, 2).as_noun();
+        let serialized = vm::serialize(&program, MAX_PROGRAM_LEN).unwrap();
+        let initiations = agents[1].environment.outgoing.len();
+        assert!(agents[1].handle_message(&a, Message::Reliable(0, &serialized[..])).is_ok());
+        assert_eq!(agents[1].environment.outgoing.len(), initiations);
+        let tasks = agents[1].environment.take_tasks();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].program, program);
+
+        // The retransmission is acknowledged, and not run again.
+        assert!(run_until_quiet(&mut agents[..], 10));
+        assert!(agents[1].handle_message(&a, Message::Reliable(0, &serialized[..])).is_ok());
+        assert!(agents[1].environment.tasks.is_empty());
+        assert_eq!(agents[1].environment.outgoing.len(), 1);
+    }
+
+    #[test]
+    fn fragmented_programs() {
+        let mut agents = testing::agents(2, 0xb1);
+        connect_all(&mut agents[..]);
+        let (mut a, mut b) = split_pair(agents);
+        
+        let big_value: Vec<u8> = (0..20_000).map(|x| (x * 7) as u8).collect();
+        let big = (0, opcode::LITERAL, big_value).as_noun();
+        a.send_program(&b.identity, &big).ok().expect("send_program failed");
+        assert!(a.environment.outgoing.len() > 15);
+        assert!(a.environment.outgoing.iter().all(|&(_, ref packet)| packet.len() <= MAX_DATAGRAM_LEN));
+        
+        // Fragments can arrive in any order.
+        a.environment.outgoing.reverse();
+        exchange(&mut [&mut a, &mut b]);
+        assert_eq!(b.environment.tasks.len(), 1);
+        assert_eq!(b.environment.tasks[0].program, big);
+        b.environment.tasks.clear();
+        
+        // A lost fragment spoils the whole message, but a reliable one is sent again in full.
+        a.send_program_reliably(&b.identity, &big).ok().expect("send_program_reliably failed");
+        a.environment.outgoing.remove(3);
+        exchange(&mut [&mut a, &mut b]);
+        assert!(b.environment.tasks.is_empty());
+        
+        a.environment.now += DEFAULT_RETRANSMIT_INTERVAL;
+        a.retransmit_unacknowledged();
+        for _ in 0..2 {
+            exchange(&mut [&mut a, &mut b]);
+        }
+        assert_eq!(b.environment.tasks.len(), 1);
+        assert_eq!(b.environment.tasks[0].program, big);
+        assert_eq!(a.environment.acknowledged, vec![(b.identity, 0)]);
+    }
+    
+    /// The first agent `testing::agents` makes from `seed`, started again with `environment`.
+    fn restart(seed: u32, environment: MemoryEnvironment) -> Agent<MemoryEnvironment> {
+        let mut agent = testing::agents(1, seed).pop().unwrap();
+        agent.environment = environment;
+        agent
+    }
+    
+    #[test]
+    fn resumes_after_restart() {
+        let mut agents = testing::agents(2, 0xc1);
+        connect_all(&mut agents[..]);
+        let (mut a, mut b) = split_pair(agents);
+        a.rekey_schedule = RekeySchedule{ interval: 0, packet_limit: 4 };
+        for round in 0..10 {
+            round_trip(&mut a, &mut b, round, false);
+        }
+        
+        // This one is lost, and must still be delivered after the restart.
+        let lost = (9, opcode::LITERAL, 10).as_noun();
+        a.send_program_reliably(&b.identity, &lost).ok().expect("send_program_reliably failed");
+        a.environment.outgoing.clear();
+        let (stale, environment) = a.export_state();
+        
+        // Restart twice, so that the first snapshot is superseded.
+        let mut a = restart(0xc1, environment);
+        a.import_state(&stale[..]).ok().expect("import_state failed");
+        let (saved, environment) = a.export_state();
+        
+        let mut stranger = testing::agents(1, 0xc3).pop().unwrap();
+        assert_eq!(stranger.import_state(&saved[..]), Err(SnapshotError::NotAuthentic));
+        
+        let mut a = restart(0xc1, environment);
+        a.rekey_schedule = RekeySchedule{ interval: 0, packet_limit: 0 };
+        assert_eq!(a.import_state(&stale[..]), Err(SnapshotError::Stale));
+        a.import_state(&saved[..]).ok().expect("import_state failed");
+        assert_eq!(a.import_state(&saved[..]), Err(SnapshotError::Stale));
+        a.retransmit_unacknowledged();
+        assert_eq!(count_initiations(&a.environment), 0);
+        exchange(&mut [&mut a, &mut b]);
+        assert_eq!(run_only_task(&mut b), Noun::from_u8(10));
+        exchange(&mut [&mut a, &mut b]);
+        assert_eq!(a.environment.acknowledged, vec![(b.identity, 0)]);
+        
+        for round in 10..20 {
+            assert_eq!(round_trip(&mut a, &mut b, round, false), 0);
+        }
+        assert_eq!(count_initiations(&b.environment), 0);
+    }
+    
+    fn run_lookup(agents: &mut Vec<Agent<MemoryEnvironment>>, searcher: usize, lookup: u64) -> Vec<(Identity, IpAddressPort)> {
+        for _ in 0..40 {
+            exchange(&mut agents[..]);
+            if let Some(closest) = agents[searcher].finished_lookup(lookup) {
+                return closest;
+            }
+        }
+        panic!("lookup did not finish");
+    }
+    
+    #[test]
+    fn lookup_and_bootstrap() {
+        let mut agents = testing::agents(8, 0xd0);
+        
+        // 1 is a hub that 0 and 2 through 6 know, and 7 is known only to 6.
+        let links = [(0, 1), (2, 1), (3, 1), (4, 1), (5, 1), (6, 1), (7, 6)];
+        for &(from, to) in links.iter() {
+            testing::connect(&mut agents[..], from, to);
+        }
+        assert!(run_until_quiet(&mut agents[..], 10));
+        assert_eq!(agents[0].routing_table.len(), 1);
+        
+        let target = agents[7].identity;
+        let lookup = agents[0].start_lookup(&target);
+        let closest = run_lookup(&mut agents, 0, lookup);
+        assert_eq!(closest.len(), 7);
+        assert_eq!(closest[0], (target, agents[7].environment.location));
+        assert!(agents[0].is_neighbor(&target));
+        assert_eq!(agents[0].routing_table.len(), 7);
+        
+        // 7 has heard from 6 and 0 so far, and learns of the rest by looking itself up.
+        assert_eq!(agents[7].routing_table.len(), 2);
+        let lookup = agents[7].bootstrap();
+        run_lookup(&mut agents, 7, lookup);
+        assert_eq!(agents[7].routing_table.len(), 7);
+        assert!(agents[7].finished_lookup(lookup).is_none());
+    }
+    
+    #[test]
+    fn requests() {
+        let mut agents = testing::agents(2, 0x21);
+        connect_all(&mut agents[..]);
+        let b_identity = agents[1].identity();
+        
+        let cheap = (5, (opcode::LITERAL, 6), (opcode::AXIS, 1)).as_noun();
+        let failing = (5, opcode::AXIS, 7).as_noun();
+        assert_eq!(agents[0].send_request(&b_identity, &cheap).ok(), Some(0));
+        assert_eq!(agents[0].send_request(&b_identity, &failing).ok(), Some(1));
+        exchange(&mut agents[..]);
+        
+        let tasks = agents[1].environment.take_tasks();
+        assert_eq!(tasks.iter().map(|task| task.request).collect::<Vec<_>>(), vec![Some(0), Some(1)]);
+        let results: Vec<_> = tasks.into_iter().map(|task| agents[1].run_task(task)).collect();
+        let error = results[1].clone().err().expect("program should have failed");
+        exchange(&mut agents[..]);
+        assert_eq!(agents[0].environment.responses, vec![
+            (b_identity, 0, Ok((6, 5).as_noun())),
+            (b_identity, 1, Err(RequestError::Eval(error))),
+        ]);
+        
+        // b is credited with what it charged a.
+        let (a_identity, now) = (agents[0].identity(), agents[0].environment.now);
+        let charged = agents[1].ledger.account(&a_identity, now).unwrap().ticks_consumed;
+        assert!(charged > 0);
+        assert_eq!(agents[0].ledger.account(&b_identity, now).unwrap().work_done_for_us, charged);
+        
+        // Programs sent without asking for a response get none.
+        agents[0].send_program(&b_identity, &cheap).ok().expect("send_program failed");
+        exchange(&mut agents[..]);
+        let task = agents[1].environment.tasks.remove(0);
+        assert_eq!(task.request, None);
+        agents[1].run_task(task).ok().expect("run_task failed");
+        assert!(agents[1].environment.outgoing.is_empty());
+        
+        // A lost request times out, and a response after that is ignored.
+        agents[0].environment.responses.clear();
+        assert_eq!(agents[0].send_request(&b_identity, &cheap).ok(), Some(2));
+        agents[0].environment.outgoing.clear();
+        assert_eq!(agents[0].send_request(&b_identity, &cheap).ok(), Some(3));
+        exchange(&mut agents[..]);
+        agents[0].environment.now += DEFAULT_REQUEST_TIMEOUT - 1;
+        agents[0].expire_requests();
+        assert!(agents[0].environment.responses.is_empty());
+        agents[0].environment.now += 1;
+        agents[0].expire_requests();
+        assert_eq!(agents[0].environment.responses, vec![(b_identity, 2, Err(RequestError::TimedOut)), (b_identity, 3, Err(RequestError::TimedOut))]);
+        
+        let late = agents[1].environment.tasks.remove(0);
+        agents[1].run_task(late).ok().expect("run_task failed");
+        exchange(&mut agents[..]);
+        assert_eq!(agents[0].environment.responses.len(), 2);
+        
+        // Running out of memory is reported like any other failure.
+        agents[1].memory_limit = 100;
+        let greedy = (0, opcode::RANDOM, opcode::LITERAL, 101).as_noun();
+        assert_eq!(agents[0].send_request(&b_identity, &greedy).ok(), Some(4));
+        exchange(&mut agents[..]);
+        let task = agents[1].environment.tasks.remove(0);
+        assert_eq!(agents[1].run_task(task), Err(EvalError::MemoryExceeded));
+        exchange(&mut agents[..]);
+        assert_eq!(agents[0].environment.responses[2], (b_identity, 4, Err(RequestError::Eval(EvalError::MemoryExceeded))));
+        
+        // So is a program that does not deserialize.
+        let now = agents[0].environment.now;
+        agents[0].requests.insert(5, OutstandingRequest{ neighbor: b_identity, sent_at: now });
+        agents[0].send_message(&b_identity, &Message::Request(5, &[0xff][..])).ok().expect("send_message failed");
+        exchange(&mut agents[..]);
+        assert!(agents[1].environment.tasks.is_empty());
+        exchange(&mut agents[..]);
+        assert_eq!(agents[0].environment.responses[3], (b_identity, 5, Err(RequestError::Eval(EvalError::MalformedProgram))));
+    }
+    
+    #[test]
+    fn response_credit_limited() {
+        let mut agents = testing::agents(2, 0x22);
+        connect_all(&mut agents[..]);
+        let b_identity = agents[1].identity();
+        
+        let program = (0, opcode::LITERAL, 1).as_noun();
+        assert_eq!(agents[0].send_request(&b_identity, &program).ok(), Some(0));
+        agents[0].environment.outgoing.clear();
+        
+        // Nobody else may answer, and b cannot claim more than a would grant one request.
+        assert!(agents[0].handle_message(&Identity::from_bytes(&[5; 32]), Message::Response(0, 10, Ok(&[1, 1, 0][..]))).is_ok());
+        assert!(agents[0].environment.responses.is_empty());
+        assert!(agents[0].handle_message(&b_identity, Message::Response(0, u64::max_value(), Ok(&[1, 1, 0][..]))).is_ok());
+        assert!(agents[0].handle_message(&b_identity, Message::Response(0, u64::max_value(), Ok(&[1, 1, 0][..]))).is_ok());
+        assert_eq!(agents[0].environment.responses, vec![(b_identity, 0, Ok(Noun::from_u8(1)))]);
+        let (max_ticks, now) = (agents[0].ledger.config().max_ticks, agents[0].environment.now);
+        assert_eq!(agents[0].ledger.account(&b_identity, now).unwrap().work_done_for_us, max_ticks);
+    }
+    
+    #[test]
+    fn replayed_initiation() {
+        let (mut a, mut b) = split_pair(testing::agents(2, 0x81));
+        
+        a.initiate_stream_with(&b.identity, &b.environment.location).ok().expect("initiate_stream_with a->b failed");
+        let (_, initiation) = a.environment.outgoing[0].clone();
+        
+        b.handle_initiation_packet(&a.environment.location, &initiation[..]).ok().expect("first initiation failed");
+        match b.handle_initiation_packet(&a.environment.location, &initiation[..]) {
+            Err(HandleError::Replayed) => {}
+            other => panic!("expected Replayed, got {:?}", other),
+        }
+        
+        // Once the packet has gone stale, it is turned away for that reason instead.
+        b.environment.now += 1000;
+        match b.handle_initiation_packet(&a.environment.location, &initiation[..]) {
+            Err(HandleError::BadTimestamp) => {}
+            other => panic!("expected BadTimestamp, got {:?}", other),
+        }
+    }
+    
+    #[test]
+    fn stale_initiation() {
+        let (mut a, mut b) = split_pair(testing::agents(2, 0x83));
+        a.environment.now = b.environment.now - 121;
+        
+        a.initiate_stream_with(&b.identity, &b.environment.location).ok().expect("initiate_stream_with a->b failed");
+        let (_, initiation) = a.environment.outgoing[0].clone();
+        match b.handle_initiation_packet(&a.environment.location, &initiation[..]) {
+            Err(HandleError::BadTimestamp) => {}
+            other => panic!("expected BadTimestamp, got {:?}", other),
+        }
+        assert!(b.neighbors.is_empty());
+        
+        b.timestamp_window = 121;
+        b.handle_initiation_packet(&a.environment.location, &initiation[..]).ok().expect("initiation within widened window failed");
+        assert!(b.neighbors.contains_key(&a.identity));
+    }
+    
+    #[test]
+    fn neighbor_cap_evicts() {
+        let mut agents = testing::agents(4, 0x71);
+        let identities: Vec<Identity> = agents.iter().map(|agent| agent.identity()).collect();
+        agents[0].neighbor_policy = Box::new(DefaultNeighborPolicy{ max_neighbors: 2, criterion: EvictionCriterion::LowestBalance });
+        
+        testing::connect(&mut agents[..], 1, 0);
+        testing::connect(&mut agents[..], 2, 0);
+        assert!(run_until_quiet(&mut agents[..], 10));
+        assert_eq!(agents[0].neighbors.len(), 2);
+        
+        agents[0].ledger.record_ticks_consumed(&identities[1], 500, 0);
+        testing::connect(&mut agents[..], 3, 0);
+        assert!(run_until_quiet(&mut agents[..], 10));
+        assert_eq!(agents[0].neighbors.len(), 2);
+        assert!(!agents[0].neighbors.contains_key(&identities[1]));
+        assert!(agents[0].neighbors.contains_key(&identities[2]));
+        assert!(agents[0].neighbors.contains_key(&identities[3]));
+        
+        // Nothing from 1 is recognized any more.
+        agents[1].send_program(&identities[0], &(1, opcode::LITERAL, 2).as_noun()).ok().expect("send_program 1->0 failed");
+        agents[3].send_program(&identities[0], &(3, opcode::LITERAL, 4).as_noun()).ok().expect("send_program 3->0 failed");
+        exchange(&mut agents[..]);
+        assert_eq!(run_only_task(&mut agents[0]), Noun::from_u8(4));
+    }
+    
+    #[test]
+    fn neighbor_policy_rejects() {
+        struct NoOne;
+        impl NeighborPolicy for NoOne {
+            fn admit(&mut self, _candidate: &Identity) -> bool { false }
+            fn max_neighbors(&self) -> usize { 10 }
+            fn choose_eviction(&mut self, _neighbors: &[NeighborSummary], _now: u64) -> Option<Identity> { None }
+        }
+        
+        let (mut a, mut b) = split_pair(testing::agents(2, 0x75));
+        a.neighbor_policy = Box::new(NoOne);
+        
+        testing::connect(&mut [&mut a, &mut b], 1, 0);
+        assert!(run_until_quiet(&mut [&mut a, &mut b], 10));
+        assert!(a.neighbors.is_empty());
+        assert!(a.initiate_stream_with(&b.identity, &b.environment.location).is_err());
+    }
+    
+    #[test]
+    fn program_sends_to_neighbor() {
+        let mut agents = testing::agents(3, 0x51);
+        let identities: Vec<Identity> = agents.iter().map(|agent| agent.identity()).collect();
+        testing::connect(&mut agents[..], 0, 1);
+        testing::connect(&mut agents[..], 1, 2);
+        assert!(run_until_quiet(&mut agents[..], 10));
+        
+        // 0 asks 1 to pass a program along to 2.
+        let relayed = (7, opcode::LITERAL, 8).as_noun();
+        let relay = (identities[2].as_bytes().to_vec(), opcode::SEND, (opcode::AXIS, 1), (opcode::LITERAL, relayed.clone())).as_noun();
+        agents[0].send_program(&identities[1], &relay).ok().expect("send_program failed");
+        exchange(&mut agents[..]);
+        assert_eq!(run_only_task(&mut agents[1]), Noun::from_bool(true));
+        
+        exchange(&mut agents[..]);
+        assert_eq!(agents[2].environment.tasks.len(), 1);
+        assert_eq!(agents[2].environment.tasks[0].requestor, identities[1]);
+        assert_eq!(agents[2].environment.tasks[0].program, relayed);
+        assert_eq!(run_only_task(&mut agents[2]), Noun::from_u8(8));
+        
+        // 0 cannot reach an agent it has no stream with.
+        let unreachable = (identities[2].as_bytes().to_vec(), opcode::SEND, (opcode::AXIS, 1), (opcode::LITERAL, relayed)).as_noun();
+        agents[1].send_program(&identities[0], &unreachable).ok().expect("send_program failed");
+        exchange(&mut agents[..]);
+        assert_eq!(run_only_task(&mut agents[0]), Noun::from_bool(false));
+        exchange(&mut agents[..]);
+        assert!(agents[2].environment.tasks.is_empty());
+    }
+    
+    #[test]
+    fn program_starts_neighboring() {
+        let mut agents = testing::agents(3, 0x41);
+        let identities: Vec<Identity> = agents.iter().map(|agent| agent.identity()).collect();
+        testing::connect(&mut agents[..], 0, 1);
+        assert!(run_until_quiet(&mut agents[..], 10));
+        
+        let location = agents[2].environment.location;
+        let introduce = (
+            identities[2].as_bytes().to_vec(),
+            opcode::START_NEIGHBORING,
+            (opcode::AXIS, 1),
+            (opcode::LITERAL, location.address.to_vec()),
+            (opcode::LITERAL, vec![location.port as u8, (location.port >> 8) as u8]),
+        ).as_noun();
+        agents[0].send_program(&identities[1], &introduce).ok().expect("send_program failed");
+        exchange(&mut agents[..]);
+        assert_eq!(run_only_task(&mut agents[1]), Noun::from_bool(true));
+        
+        assert!(run_until_quiet(&mut agents[..], 10));
+        assert!(agents[1].neighbors.contains_key(&identities[2]));
+        assert!(agents[2].neighbors.contains_key(&identities[1]));
+        
+        // Streams in both directions are ready.
+        agents[1].send_program(&identities[2], &(1, opcode::LITERAL, 2).as_noun()).ok().expect("send_program 1->2 failed");
+        agents[2].send_program(&identities[1], &(3, opcode::LITERAL, 4).as_noun()).ok().expect("send_program 2->1 failed");
+        exchange(&mut agents[..]);
+        assert_eq!(run_only_task(&mut agents[2]), Noun::from_u8(2));
+        assert_eq!(run_only_task(&mut agents[1]), Noun::from_u8(4));
+    }
+
 
 }
 
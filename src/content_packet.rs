@@ -32,6 +32,35 @@ pub const CONTENTFUL_PACKET_THRESHOLD: usize =
     0 // minimum payload length. TODO: This will be longer to accomodate 
 ;
 
+pub const FRAME_LENGTH_LEN: usize = 4;
+
+/// Content packet payloads are a whole number of words long, so a payload of arbitrary length
+/// is sent with its byte length in front and zero padding behind.
+pub fn frame_payload(payload: &[u8]) -> Result<Vec<u8>, HandleError> {
+    let payload_len = try!(payload.len().as_u32_checked().ok_or(HandleError::InternalLimitExceeded));
+    let unpadded_len = try!(FRAME_LENGTH_LEN.checked_add(payload.len()).ok_or(HandleError::InternalLimitExceeded));
+    let padded_len = (unpadded_len + 3) / 4 * 4;
+    
+    let mut framed = Vec::with_capacity(padded_len);
+    framed.write_u32::<LittleEndian>(payload_len).unwrap();
+    framed.extend_from_slice(payload);
+    framed.resize(padded_len, 0);
+    Ok(framed)
+}
+
+/// Recover the payload given to `frame_payload`.
+pub fn unframe_payload(framed: &[u8]) -> Result<&[u8], HandleError> {
+    if framed.len() < FRAME_LENGTH_LEN {
+        return Err(HandleError::MalformedPayload);
+    }
+    let (length_bytes, rest) = framed.split_at(FRAME_LENGTH_LEN);
+    let payload_len = try!((&length_bytes[..]).read_u32::<LittleEndian>().unwrap().as_usize_checked().ok_or(HandleError::MalformedPayload));
+    if payload_len > rest.len() {
+        return Err(HandleError::MalformedPayload);
+    }
+    Ok(&rest[..payload_len])
+}
+
 impl<'a> ContentPacket<'a> {
     pub fn decode(packet: &'a [u8]) -> Result<ContentPacket<'a>, HandleError> {
         if packet.len() < CONTENTFUL_PACKET_THRESHOLD {
@@ -108,7 +137,7 @@ impl<'a> ContentPacket<'a> {
 #[cfg(test)]
 mod test {
     use rand::{XorShiftRng, SeedableRng};
-    use super::ContentPacket;
+    use super::{ContentPacket, frame_payload, unframe_payload};
     
     #[test]
     fn read_back() {
@@ -146,5 +175,20 @@ mod test {
         read_back_with_lens(28, 0);
         read_back_with_lens(100, 0);
     }
-
+    
+    #[test]
+    fn frame_round_trip() {
+        for len in 0..9 {
+            let payload: Vec<u8> = (0..len).map(|idx| (idx*7 + 1) as u8).collect();
+            let framed = frame_payload(&payload[..]).ok().unwrap();
+            assert_eq!(framed.len() % 4, 0);
+            assert_eq!(unframe_payload(&framed[..]).ok().unwrap(), &payload[..]);
+        }
+    }
+    
+    #[test]
+    fn frame_too_long() {
+        assert!(unframe_payload(&[5, 0, 0, 0, 1, 2, 3, 4]).is_err());
+        assert!(unframe_payload(&[0, 0]).is_err());
+    }
 }
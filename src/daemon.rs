@@ -0,0 +1,245 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use rand::OsRng;
+use agent::{Agent, AgentEnvironment, HandleError};
+use identity::Identity;
+use ip_address_port::IpAddressPort;
+use keystore::{self, KdfParams, KeystoreError};
+use stream::wipe;
+use udp::{self, UdpEnvironment};
+use vm::EvalError;
+
+/// How long `run` waits for a datagram before checking whether upkeep is due.
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// An agent we introduce ourselves to on startup.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Peer {
+    pub identity: Identity,
+    pub address: SocketAddr,
+}
+
+/// Daemon settings, read from a file of `key = value` lines. Blank lines and lines starting
+/// with `#` are ignored.
+///
+/// ```text
+/// keystore = hoplight.keys
+/// bind = 127.0.0.1:5000
+/// peer = hop... 127.0.0.1:5001
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Config {
+    /// Holds this agent's identity seed. A new identity is generated if it does not exist.
+    pub keystore: PathBuf,
+    pub bind: SocketAddr,
+    pub peers: Vec<Peer>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct ConfigError {
+    pub line: usize,
+    pub reason: &'static str,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Config, ConfigError> {
+        let mut keystore = None;
+        let mut bind = None;
+        let mut peers = Vec::new();
+
+        for (idx, line) in text.lines().enumerate() {
+            let fail = |reason| ConfigError{ line: idx + 1, reason: reason };
+            let line = line.trim();
+            if line.len() == 0 || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap().trim();
+            let value = try!(parts.next().ok_or(fail("expected `key = value`"))).trim();
+            match key {
+                "keystore" => { keystore = Some(PathBuf::from(value)); }
+                "bind" => { bind = Some(try!(value.parse().map_err(|_| fail("bad address")))); }
+                "peer" => {
+                    let mut words = value.split_whitespace();
+                    let identity = try!(words.next().and_then(|word| word.parse().ok()).ok_or(fail("bad peer identity")));
+                    let address = try!(words.next().and_then(|word| word.parse().ok()).ok_or(fail("bad peer address")));
+                    if words.next().is_some() {
+                        return Err(fail("expected `peer = <identity> <address>`"));
+                    }
+                    peers.push(Peer{ identity: identity, address: address });
+                }
+                _ => { return Err(fail("unknown key")); }
+            }
+        }
+
+        Ok(Config{
+            keystore: try!(keystore.ok_or(ConfigError{ line: 0, reason: "missing keystore" })),
+            bind: try!(bind.ok_or(ConfigError{ line: 0, reason: "missing bind" })),
+            peers: peers,
+        })
+    }
+}
+
+/// Runs an agent over UDP: feeds it datagrams, runs the tasks its neighbors send, and keeps up
+/// with rekeying and retransmission.
+pub struct Daemon {
+    pub agent: Agent<UdpEnvironment>,
+    buffer: Vec<u8>,
+    last_upkeep: u64,
+
+    /// Lookups started by `bootstrap`, to be collected once they finish.
+    bootstrap_lookups: Vec<u64>,
+}
+
+impl Daemon {
+    pub fn new(identity_seed: &[u8; 32], bind: &SocketAddr) -> io::Result<Daemon> {
+        let environment = try!(UdpEnvironment::bind(bind));
+        let now = environment.get_current_timestamp();
+        Ok(Daemon{
+            agent: Agent::new(identity_seed, environment),
+            buffer: vec![0; udp::MAX_RECEIVED_LEN],
+            last_upkeep: now,
+            bootstrap_lookups: Vec::new(),
+        })
+    }
+
+    /// Start a daemon with the identity in the configured keystore, which is unlocked with
+    /// `passphrase`. It still needs to `bootstrap` with the configured peers.
+    pub fn from_config(config: &Config, passphrase: &[u8]) -> Result<Daemon, KeystoreError> {
+        let mut rng = try!(OsRng::new());
+        let mut seed = try!(keystore::load_or_generate(&config.keystore, passphrase, &KdfParams::default(), &mut rng));
+        let daemon = Daemon::new(&seed, &config.bind);
+        wipe(&mut seed[..]);
+        Ok(try!(daemon))
+    }
+
+    pub fn local_address(&self) -> io::Result<SocketAddr> {
+        self.agent.environment.local_address()
+    }
+
+    /// Start streaming with each of `peers`, then look for the agents near us through them.
+    /// Peers we cannot stream with are skipped, and returned with the reason.
+    pub fn bootstrap(&mut self, peers: &[Peer]) -> Vec<(Peer, HandleError)> {
+        let mut skipped = Vec::new();
+        for peer in peers.iter() {
+            if let Err(e) = self.agent.initiate_stream_with(&peer.identity, &IpAddressPort::from(peer.address)) {
+                skipped.push((*peer, e));
+            }
+        }
+        let lookup = self.agent.bootstrap();
+        self.bootstrap_lookups.push(lookup);
+        skipped
+    }
+
+    /// Handle at most one datagram, waiting up to `timeout` for it, then run the tasks it led to
+    /// and do any upkeep that is due. Returns why any of the tasks failed.
+    pub fn step(&mut self, timeout: Duration) -> io::Result<Vec<EvalError>> {
+        try!(self.agent.environment.socket().set_read_timeout(Some(timeout)));
+        if let Some((len, source)) = try!(self.agent.environment.receive(&mut self.buffer[..])) {
+            self.agent.handle_packet(&source, &self.buffer[..len]);
+        }
+
+        let mut failures = Vec::new();
+        while let Some(task) = self.agent.environment.next_task() {
+            if let Err(e) = self.agent.run_task(task) {
+                failures.push(e);
+            }
+        }
+
+        // What bootstrapping found is already in the routing table.
+        let agent = &mut self.agent;
+        self.bootstrap_lookups.retain(|lookup| agent.finished_lookup(*lookup).is_none());
+
+        let now = self.agent.environment.get_current_timestamp();
+        if now != self.last_upkeep {
+            self.last_upkeep = now;
+            self.agent.rekey_due_neighbors();
+            self.agent.retransmit_unacknowledged();
+            self.agent.continue_lookups();
+            self.agent.expire_requests();
+        }
+        Ok(failures)
+    }
+
+    /// Step until the socket fails, handing `task_failed` the error of every task that fails.
+    pub fn run<F: FnMut(EvalError)>(&mut self, mut task_failed: F) -> io::Result<()> {
+        loop {
+            for e in try!(self.step(Duration::from_millis(POLL_INTERVAL_MS))).into_iter() {
+                task_failed(e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Config, ConfigError, Daemon, Peer};
+    use agent::{AgentEnvironment, HandleError};
+    use identity::Identity;
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use vm::{self, opcode, AsNoun};
+
+    #[test]
+    fn parse_config() {
+        let identity = Identity::from_bytes(&[0xab; 32]);
+        let text = format!("# a comment\n\nkeystore = /tmp/keys\nbind = 127.0.0.1:5000\npeer = {} [::1]:5001\n", identity);
+        assert_eq!(Config::parse(&text), Ok(Config{
+            keystore: PathBuf::from("/tmp/keys"),
+            bind: "127.0.0.1:5000".parse().unwrap(),
+            peers: vec![Peer{ identity: identity, address: "[::1]:5001".parse().unwrap() }],
+        }));
+
+        assert_eq!(Config::parse("bind = 127.0.0.1:5000\nkeystore = x\npeer = abc 127.0.0.1:1"), Err(ConfigError{ line: 3, reason: "bad peer identity" }));
+        assert_eq!(Config::parse("bind = 127.0.0.1:5000"), Err(ConfigError{ line: 0, reason: "missing keystore" }));
+        assert_eq!(Config::parse("colour = blue"), Err(ConfigError{ line: 1, reason: "unknown key" }));
+    }
+
+    fn step_all(daemons: &mut [&mut Daemon]) {
+        for _ in 0..10 {
+            for daemon in daemons.iter_mut() {
+                daemon.step(Duration::from_millis(5)).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn daemons_on_loopback() {
+        let loopback = "127.0.0.1:0".parse().unwrap();
+        let mut a = Daemon::new(&[0xc1; 32], &loopback).unwrap();
+        let mut b = Daemon::new(&[0xc2; 32], &loopback).unwrap();
+        let mut c = Daemon::new(&[0xc3; 32], &loopback).unwrap();
+        let (b_identity, c_identity) = (b.agent.identity(), c.agent.identity());
+
+        // b and c each know only a.
+        let a_peer = Peer{ identity: a.agent.identity(), address: a.local_address().unwrap() };
+        assert!(b.bootstrap(&[a_peer]).is_empty());
+        assert!(c.bootstrap(&[a_peer]).is_empty());
+
+        // We cannot stream with ourselves.
+        let c_peer = Peer{ identity: c_identity, address: c.local_address().unwrap() };
+        match &c.bootstrap(&[c_peer])[..] {
+            [(peer, HandleError::CannotStreamWithSelf)] if *peer == c_peer => {}
+            other => panic!("expected CannotStreamWithSelf, got {:?}", other),
+        }
+        step_all(&mut [&mut a, &mut b, &mut c]);
+
+        // Their bootstrap lookups have finished and been collected.
+        assert!(b.bootstrap_lookups.is_empty() && c.bootstrap_lookups.is_empty());
+
+        // b asks a to pass a store request along to c.
+        let store = (&b"greeting"[..], opcode::STORE_BY_KEY, (opcode::LITERAL, &b"hello"[..]), ((opcode::LITERAL, opcode::LITERAL), (opcode::AXIS, 1))).as_noun();
+        let relay = (c_identity.as_bytes().to_vec(), opcode::SEND, (opcode::AXIS, 1), (opcode::LITERAL, store)).as_noun();
+        b.agent.send_program(&a.agent.identity(), &relay).unwrap();
+        step_all(&mut [&mut a, &mut b, &mut c]);
+
+        let mut storage_key = vm::serialize(&(&b"hello"[..]).as_noun(), 100).unwrap();
+        storage_key.push(0);
+        assert!(c.agent.environment.load(&storage_key[..]).is_some());
+        assert!(a.agent.environment.load(&storage_key[..]).is_none());
+        assert!(a.agent.is_neighbor(&b_identity));
+    }
+}
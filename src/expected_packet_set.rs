@@ -10,7 +10,10 @@ pub struct ExpectedPacketSet {
 #[derive(Eq, PartialEq, Copy, Clone, Debug)]
 pub struct ExpectedPacket {
     pub stream_with: Identity,
-    pub stream_key: [u8; 32],
+
+    /// Which of the neighbor's streams, by the number its `StreamCluster` gave it. Entries are
+    /// copied around freely, so they must not hold key material.
+    pub stream: u64,
     pub packet_number: u64,
 }
 
@@ -47,6 +50,14 @@ impl ExpectedPacketSet {
         }
     }
 
+    /// Stop expecting anything from `neighbor`.
+    pub fn remove_stream_with(&mut self, neighbor: &Identity) {
+        for list in self.inner.values_mut() {
+            list.retain(|expected| expected.stream_with != *neighbor);
+        }
+        self.inner.retain(|_, list| list.len() > 0);
+    }
+
     pub fn iter(&self, identifier: u64) -> ::std::slice::Iter<ExpectedPacket> {
         if let Some(list) = self.inner.get(&identifier) {
             list.iter()
@@ -54,5 +65,31 @@ impl ExpectedPacketSet {
             self.empty.iter()
         }
     }
+
+    #[cfg(test)]
+    pub fn count_for_stream(&self, stream: u64) -> usize {
+        self.inner.values().map(|list| list.iter().filter(|expected| expected.stream == stream).count()).sum()
+    }
     
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ExpectedPacket, ExpectedPacketSet};
+    use identity::Identity;
+
+    #[test]
+    fn remove_stream_with() {
+        let a = Identity::from_bytes(&[1; 32]);
+        let b = Identity::from_bytes(&[2; 32]);
+        let mut set = ExpectedPacketSet::new();
+        set.add(ExpectedPacket{ stream_with: a, stream: 0, packet_number: 0 }, 100);
+        set.add(ExpectedPacket{ stream_with: b, stream: 0, packet_number: 0 }, 100);
+        set.add(ExpectedPacket{ stream_with: a, stream: 0, packet_number: 1 }, 101);
+
+        set.remove_stream_with(&a);
+        assert_eq!(set.iter(100).map(|expected| expected.stream_with).collect::<Vec<_>>(), vec![b]);
+        assert_eq!(set.iter(101).count(), 0);
+        assert!(!set.inner.contains_key(&101));
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::{BTreeMap, HashMap};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use checked_int_cast::CheckedIntCast;
+use agent::HandleError;
+use message;
+use vm;
+
+/// Bytes a fragment's header adds to its message encoding: the message kind, message
+/// identifier, fragment index and fragment count.
+pub const HEADER_LEN: usize = 1 + 8 + 4 + 4;
+
+/// One piece of an encoded `Message` that was too long for a single packet.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Fragment<'a> {
+    /// Shared by every fragment of one message, and distinct from other messages from the same
+    /// neighbor that might be in flight at the same time.
+    pub message_id: u64,
+    pub index: u32,
+    pub count: u32,
+    pub chunk: &'a [u8],
+}
+
+impl<'a> Fragment<'a> {
+    /// Write everything after the message kind.
+    pub fn encode_into(&self, encoded: &mut Vec<u8>) {
+        encoded.write_u64::<LittleEndian>(self.message_id).unwrap();
+        encoded.write_u32::<LittleEndian>(self.index).unwrap();
+        encoded.write_u32::<LittleEndian>(self.count).unwrap();
+        encoded.extend_from_slice(self.chunk);
+    }
+
+    /// Read what `encode_into` wrote.
+    pub fn decode(bs: &'a [u8]) -> Result<Fragment<'a>, HandleError> {
+        if bs.len() < HEADER_LEN - 1 {
+            return Err(HandleError::MalformedPayload);
+        }
+        let (mut header, chunk) = bs.split_at(HEADER_LEN - 1);
+        let message_id = header.read_u64::<LittleEndian>().unwrap();
+        let index = header.read_u32::<LittleEndian>().unwrap();
+        let count = header.read_u32::<LittleEndian>().unwrap();
+        if index >= count || chunk.len() == 0 {
+            return Err(HandleError::MalformedPayload);
+        }
+
+        Ok(Fragment{
+            message_id: message_id,
+            index: index,
+            count: count,
+            chunk: chunk,
+        })
+    }
+}
+
+/// Cut `encoded` into fragments with chunks of at most `chunk_len` bytes.
+pub fn split<'a>(message_id: u64, encoded: &'a [u8], chunk_len: usize) -> Result<Vec<Fragment<'a>>, HandleError> {
+    let count = try!(((encoded.len() + chunk_len - 1) / chunk_len).as_u32_checked().ok_or(HandleError::InternalLimitExceeded));
+    Ok(encoded.chunks(chunk_len).enumerate().map(|(index, chunk)| Fragment{
+        message_id: message_id,
+        index: index as u32,
+        count: count,
+        chunk: chunk,
+    }).collect())
+}
+
+/// Bounds on what a neighbor can make us hold while its fragmented messages arrive.
+#[derive(Debug, Copy, Clone)]
+pub struct ReassemblyLimits {
+    /// Longest message, in bytes, that will be reassembled.
+    pub max_message_len: usize,
+
+    /// Most messages from one neighbor that may be partially received at once. Starting
+    /// another abandons the oldest.
+    pub max_partial_messages: usize,
+
+    /// Partial messages are abandoned once this long has passed since their first fragment.
+    pub timeout: u64,
+}
+
+impl Default for ReassemblyLimits {
+    fn default() -> ReassemblyLimits {
+        ReassemblyLimits{
+            // Enough for the longest serialization an agent or the VM will send.
+            max_message_len: vm::maximum_serialized_length(vm::MAX_SERIALIZED_LEN) + message::MAX_HEADER_LEN,
+            max_partial_messages: 4,
+            timeout: 30,
+        }
+    }
+}
+
+struct PartialMessage {
+    count: u32,
+    chunks: BTreeMap<u32, Vec<u8>>,
+    len: usize,
+    started: u64,
+}
+
+/// Collects the fragments of messages from one neighbor.
+pub struct Reassembler {
+    partial: HashMap<u64, PartialMessage>,
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Reassembler{
+            partial: HashMap::new(),
+        }
+    }
+
+    /// Take in a fragment, returning the whole message if this was the last piece of it.
+    pub fn receive(&mut self, fragment: &Fragment, now: u64, limits: &ReassemblyLimits) -> Result<Option<Vec<u8>>, HandleError> {
+        self.partial.retain(|_, partial| now.saturating_sub(partial.started) < limits.timeout);
+
+        if !self.partial.contains_key(&fragment.message_id) {
+            if fragment.count as usize > limits.max_message_len || limits.max_partial_messages == 0 {
+                return Err(HandleError::InternalLimitExceeded);
+            }
+            while self.partial.len() >= limits.max_partial_messages {
+                let oldest = *self.partial.iter().min_by_key(|&(_, partial)| partial.started).unwrap().0;
+                self.partial.remove(&oldest);
+            }
+            self.partial.insert(fragment.message_id, PartialMessage{
+                count: fragment.count,
+                chunks: BTreeMap::new(),
+                len: 0,
+                started: now,
+            });
+        }
+
+        let complete = {
+            let partial = self.partial.get_mut(&fragment.message_id).unwrap();
+            if partial.count != fragment.count || partial.len + fragment.chunk.len() > limits.max_message_len {
+                None
+            } else {
+                if !partial.chunks.contains_key(&fragment.index) {
+                    partial.len += fragment.chunk.len();
+                    partial.chunks.insert(fragment.index, fragment.chunk.to_vec());
+                }
+                Some(partial.chunks.len() as u32 == partial.count)
+            }
+        };
+
+        match complete {
+            None => {
+                self.partial.remove(&fragment.message_id);
+                Err(HandleError::MalformedPayload)
+            }
+            Some(false) => Ok(None),
+            Some(true) => {
+                let partial = self.partial.remove(&fragment.message_id).unwrap();
+                let mut message = Vec::with_capacity(partial.len);
+                for chunk in partial.chunks.values() {
+                    message.extend_from_slice(&chunk[..]);
+                }
+                Ok(Some(message))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{split, Fragment, ReassemblyLimits, Reassembler};
+
+    fn limits() -> ReassemblyLimits {
+        ReassemblyLimits{ max_message_len: 100, max_partial_messages: 2, timeout: 10 }
+    }
+
+    #[test]
+    fn round_trip() {
+        let message: Vec<u8> = (0..95).map(|x| x as u8).collect();
+        let fragments = split(7, &message[..], 10).ok().unwrap();
+        assert_eq!(fragments.len(), 10);
+        assert_eq!(fragments[9].chunk.len(), 5);
+
+        let mut encoded = Vec::new();
+        fragments[3].encode_into(&mut encoded);
+        assert_eq!(Fragment::decode(&encoded[..]).ok().unwrap(), fragments[3]);
+
+        let mut reassembler = Reassembler::new();
+        for fragment in fragments.iter().rev().skip(1) {
+            assert_eq!(reassembler.receive(fragment, 0, &limits()).ok().unwrap(), None);
+        }
+        assert_eq!(reassembler.receive(&fragments[3], 0, &limits()).ok().unwrap(), None);
+        assert_eq!(reassembler.receive(&fragments[9], 0, &limits()).ok().unwrap(), Some(message));
+        assert!(reassembler.partial.is_empty());
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(Fragment::decode(&[0; 15]).is_err());
+        // Index past the count.
+        assert!(Fragment::decode(&[0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 9]).is_err());
+        // No chunk.
+        assert!(Fragment::decode(&[0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn too_long() {
+        let message = [0u8; 101];
+        let mut reassembler = Reassembler::new();
+        let fragments = split(1, &message[..], 50).ok().unwrap();
+        assert!(reassembler.receive(&fragments[0], 0, &limits()).ok().unwrap().is_none());
+        assert!(reassembler.receive(&fragments[1], 0, &limits()).ok().unwrap().is_none());
+        assert!(reassembler.receive(&fragments[2], 0, &limits()).is_err());
+        assert!(reassembler.partial.is_empty());
+
+        let huge = Fragment{ message_id: 2, index: 0, count: 1000, chunk: &[1] };
+        assert!(reassembler.receive(&huge, 0, &limits()).is_err());
+    }
+
+    #[test]
+    fn abandons_old_messages() {
+        let message = [5u8; 20];
+        let mut reassembler = Reassembler::new();
+        let first = split(1, &message[..], 10).ok().unwrap();
+        let second = split(2, &message[..], 10).ok().unwrap();
+        let third = split(3, &message[..], 10).ok().unwrap();
+
+        reassembler.receive(&first[0], 0, &limits()).ok().unwrap();
+        reassembler.receive(&second[0], 1, &limits()).ok().unwrap();
+        reassembler.receive(&third[0], 2, &limits()).ok().unwrap();
+        assert!(!reassembler.partial.contains_key(&1));
+        assert_eq!(reassembler.receive(&second[1], 5, &limits()).ok().unwrap(), Some(message.to_vec()));
+
+        // Too late for the third, so its last fragment starts over.
+        assert_eq!(reassembler.receive(&third[1], 12, &limits()).ok().unwrap(), None);
+        assert_eq!(reassembler.partial.len(), 1);
+    }
+}
@@ -1,8 +1,24 @@
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crypto::blake2b::Blake2b;
 use std::io::Cursor;
 use std::cmp::{Ord, PartialOrd, Ordering};
+use std::fmt;
+use std::str::FromStr;
+use vm::xor_distance;
 
-#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+/// Starts the text form of every identity.
+pub const TEXT_PREFIX: &'static str = "hop";
+
+const CHECKSUM_LEN: usize = 4;
+const ENCODED_LEN: usize = 32 + CHECKSUM_LEN;
+
+/// Characters of lowercase RFC 4648 base32.
+const ALPHABET: &'static [u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Length of the text form: the prefix, then base32 without padding.
+pub const TEXT_LEN: usize = 3 + (ENCODED_LEN * 8 + 4) / 5;
+
+#[derive(Copy, Clone, Hash, Eq, PartialEq)]
 pub struct Identity{
     words: [u32; 8]
 }
@@ -34,6 +50,12 @@ impl Identity{
         bs
     }
     
+    /// XOR of the two identities' bytes. Compared as byte arrays, these order identities by
+    /// closeness to `other`.
+    pub fn distance(&self, other: &Identity) -> [u8; 32] {
+        xor_distance(&self.as_bytes(), &other.as_bytes())
+    }
+    
     /// Compare with another Identity, where it is an error to match.
     pub fn is_greater_than(&self, other: &Identity) -> Result<bool, ()> {
         match self.cmp(other) {
@@ -54,4 +76,146 @@ impl Ord for Identity {
     fn cmp(&self, other: &Self) -> Ordering {
         self.words.cmp(&other.words)
     }
-}
\ No newline at end of file
+}
+
+fn checksum(bs: &[u8; 32]) -> [u8; CHECKSUM_LEN] {
+    let mut checksum = [0u8; CHECKSUM_LEN];
+    Blake2b::blake2b(&mut checksum[..], &bs[..], b"hoplight identity checksum");
+    checksum
+}
+
+/// Why text could not be read as an identity.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ParseIdentityError {
+    MissingPrefix,
+    WrongLength,
+    InvalidCharacter,
+
+    /// Well-formed, but not an identity as written by `Display`. It was probably mistyped.
+    ChecksumMismatch,
+}
+
+impl fmt::Display for ParseIdentityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ParseIdentityError::MissingPrefix => "identity does not start with `hop`",
+            ParseIdentityError::WrongLength => "identity has the wrong length",
+            ParseIdentityError::InvalidCharacter => "identity contains a character outside of base32",
+            ParseIdentityError::ChecksumMismatch => "identity checksum does not match",
+        })
+    }
+}
+
+/// Writes `hop` followed by the base32 encoding of the identity's bytes and a 4-byte checksum.
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bs = self.as_bytes();
+        let mut encoded = [0u8; ENCODED_LEN];
+        encoded[..32].copy_from_slice(&bs[..]);
+        encoded[32..].copy_from_slice(&checksum(&bs)[..]);
+
+        let mut text = String::with_capacity(TEXT_LEN);
+        text.push_str(TEXT_PREFIX);
+        let (mut buffer, mut bits) = (0u32, 0);
+        for b in encoded.iter() {
+            buffer = (buffer << 8) | (*b as u32);
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                text.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+            }
+        }
+        if bits > 0 {
+            text.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+        }
+        f.write_str(&text)
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Identity({})", self)
+    }
+}
+
+/// Reads what `Display` writes. Uppercase base32 is accepted too.
+impl FromStr for Identity {
+    type Err = ParseIdentityError;
+
+    fn from_str(text: &str) -> Result<Identity, ParseIdentityError> {
+        if !text.starts_with(TEXT_PREFIX) {
+            return Err(ParseIdentityError::MissingPrefix);
+        }
+        let digits = &text.as_bytes()[TEXT_PREFIX.len()..];
+        if text.len() != TEXT_LEN {
+            return Err(ParseIdentityError::WrongLength);
+        }
+
+        let mut encoded = [0u8; ENCODED_LEN];
+        let (mut buffer, mut bits, mut len) = (0u32, 0, 0);
+        for digit in digits.iter() {
+            let value = try!(ALPHABET.iter().position(|c| *c == digit.to_ascii_lowercase()).ok_or(ParseIdentityError::InvalidCharacter));
+            buffer = (buffer << 5) | (value as u32);
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                encoded[len] = (buffer >> bits) as u8;
+                len += 1;
+            }
+        }
+        if buffer & ((1 << bits) - 1) != 0 {
+            // Only one text form per identity: the unused low bits must be zero.
+            return Err(ParseIdentityError::InvalidCharacter);
+        }
+
+        let mut bs = [0u8; 32];
+        bs.copy_from_slice(&encoded[..32]);
+        if checksum(&bs)[..] != encoded[32..] {
+            return Err(ParseIdentityError::ChecksumMismatch);
+        }
+        Ok(Identity::from_bytes(&bs))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Identity, ParseIdentityError, TEXT_LEN};
+
+    #[test]
+    fn text_round_trip() {
+        for seed in [0u8, 1, 0x5a, 0xff].iter() {
+            let mut bs = [*seed; 32];
+            bs[7] = 3;
+            let identity = Identity::from_bytes(&bs);
+            let text = identity.to_string();
+            assert_eq!(text.len(), TEXT_LEN);
+            assert!(text.starts_with("hop"));
+            assert_eq!(text.parse::<Identity>(), Ok(identity));
+            assert_eq!(text.to_uppercase().replace("HOP", "hop").parse::<Identity>(), Ok(identity));
+            assert_eq!(format!("{:?}", identity), format!("Identity({})", text));
+        }
+    }
+
+    #[test]
+    fn mistyped() {
+        let text = Identity::from_bytes(&[0x42; 32]).to_string();
+
+        // Every single-character substitution is caught.
+        for idx in 3..text.len() {
+            let mut typo = text.clone().into_bytes();
+            typo[idx] = if typo[idx] == b'q' { b'r' } else { b'q' };
+            assert!(String::from_utf8(typo).unwrap().parse::<Identity>().is_err());
+        }
+
+        assert_eq!(text[1..].parse::<Identity>(), Err(ParseIdentityError::MissingPrefix));
+        assert_eq!(text[..text.len() - 1].parse::<Identity>(), Err(ParseIdentityError::WrongLength));
+        assert_eq!(format!("{}!", &text[..text.len() - 1]).parse::<Identity>(), Err(ParseIdentityError::InvalidCharacter));
+        assert_eq!(format!("{}1", &text[..text.len() - 1]).parse::<Identity>(), Err(ParseIdentityError::InvalidCharacter));
+
+        let mut swapped = text.clone().into_bytes();
+        swapped.swap(10, 11);
+        if swapped != text.as_bytes() {
+            assert_eq!(String::from_utf8(swapped).unwrap().parse::<Identity>(), Err(ParseIdentityError::ChecksumMismatch));
+        }
+    }
+}
@@ -1,5 +1,143 @@
+use std::fmt;
+use std::io;
+use std::net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
+use std::option;
+use std::str::FromStr;
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Length of the encoding given by `as_bytes`: the address followed by the port, little-endian.
+pub const ENCODED_LEN: usize = 18;
+
+/// Where a neighbor can be reached. IPv4 addresses are held as IPv4-mapped IPv6 addresses
+/// (`::ffff:a.b.c.d`), and are given back as IPv4 when converted to `std::net` types.
 #[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
 pub struct IpAddressPort{
     pub address: [u8; 16],
     pub port: u16,
 }
+
+impl IpAddressPort {
+    pub fn new(ip: IpAddr, port: u16) -> IpAddressPort {
+        let ip = match ip {
+            IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+            IpAddr::V6(v6) => v6,
+        };
+        IpAddressPort{
+            address: ip.octets(),
+            port: port,
+        }
+    }
+
+    pub fn is_ipv4_mapped(&self) -> bool {
+        self.address[..10].iter().all(|b| *b == 0) && self.address[10] == 0xff && self.address[11] == 0xff
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        let a = &self.address;
+        if self.is_ipv4_mapped() {
+            IpAddr::V4(Ipv4Addr::new(a[12], a[13], a[14], a[15]))
+        } else {
+            IpAddr::V6(Ipv6Addr::from(*a))
+        }
+    }
+
+    pub fn from_bytes(bs: &[u8; ENCODED_LEN]) -> IpAddressPort {
+        let mut address = [0u8; 16];
+        address.copy_from_slice(&bs[..16]);
+        IpAddressPort{
+            address: address,
+            port: LittleEndian::read_u16(&bs[16..]),
+        }
+    }
+
+    pub fn as_bytes(&self) -> [u8; ENCODED_LEN] {
+        let mut bs = [0u8; ENCODED_LEN];
+        bs[..16].copy_from_slice(&self.address[..]);
+        LittleEndian::write_u16(&mut bs[16..], self.port);
+        bs
+    }
+}
+
+impl From<SocketAddr> for IpAddressPort {
+    fn from(address: SocketAddr) -> IpAddressPort {
+        IpAddressPort::new(address.ip(), address.port())
+    }
+}
+
+impl From<SocketAddrV4> for IpAddressPort {
+    fn from(address: SocketAddrV4) -> IpAddressPort {
+        IpAddressPort::new(IpAddr::V4(*address.ip()), address.port())
+    }
+}
+
+impl From<SocketAddrV6> for IpAddressPort {
+    fn from(address: SocketAddrV6) -> IpAddressPort {
+        IpAddressPort::new(IpAddr::V6(*address.ip()), address.port())
+    }
+}
+
+impl From<IpAddressPort> for SocketAddr {
+    fn from(location: IpAddressPort) -> SocketAddr {
+        match location.ip() {
+            IpAddr::V4(v4) => SocketAddr::V4(SocketAddrV4::new(v4, location.port)),
+            IpAddr::V6(v6) => SocketAddr::V6(SocketAddrV6::new(v6, location.port, 0, 0)),
+        }
+    }
+}
+
+impl ToSocketAddrs for IpAddressPort {
+    type Iter = option::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        Ok(Some(SocketAddr::from(*self)).into_iter())
+    }
+}
+
+/// Formats as `a.b.c.d:port` for IPv4-mapped addresses and `[v6]:port` otherwise.
+impl fmt::Display for IpAddressPort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        SocketAddr::from(*self).fmt(f)
+    }
+}
+
+/// Parses what `Display` writes. Host names are not resolved.
+impl FromStr for IpAddressPort {
+    type Err = AddrParseError;
+
+    fn from_str(text: &str) -> Result<IpAddressPort, AddrParseError> {
+        text.parse::<SocketAddr>().map(IpAddressPort::from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::IpAddressPort;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    #[test]
+    fn conversions() {
+        for text in ["127.0.0.1:5000", "[::1]:6000", "[2001:db8::17]:65535", "0.0.0.0:0"].iter() {
+            let location: IpAddressPort = text.parse().unwrap();
+            assert_eq!(location.to_string(), *text);
+            assert_eq!(SocketAddr::from(location), text.parse::<SocketAddr>().unwrap());
+            assert_eq!(IpAddressPort::from_bytes(&location.as_bytes()), location);
+        }
+        assert!("localhost:5000".parse::<IpAddressPort>().is_err());
+        assert!("127.0.0.1".parse::<IpAddressPort>().is_err());
+    }
+
+    #[test]
+    fn ipv4_mapped() {
+        let location: IpAddressPort = "10.1.2.3:80".parse().unwrap();
+        assert_eq!(location.address, [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 10, 1, 2, 3]);
+        assert!(location.is_ipv4_mapped());
+        assert_eq!(location.ip(), IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3)));
+
+        // Written out as IPv6, a mapped address still comes back as IPv4.
+        let written_as_v6: IpAddressPort = "[::ffff:10.1.2.3]:80".parse().unwrap();
+        assert_eq!(written_as_v6, location);
+        assert_eq!(written_as_v6.to_string(), "10.1.2.3:80");
+
+        assert_eq!(&location.as_bytes()[16..], &[80, 0]);
+    }
+}
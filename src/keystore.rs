@@ -0,0 +1,292 @@
+//! Identity seeds kept on disk, encrypted under a key derived from a passphrase.
+//!
+//! A version 1 keystore file is laid out as:
+//!
+//! ```text
+//! magic "hoplkeys" (8) | version (1) | scrypt log_n (1) | r (4) | p (4) | salt (16) | nonce (8)
+//!     | encrypted seed (32) | tag (16)
+//! ```
+//!
+//! with integers little-endian. The seed is encrypted with ChaCha20-Poly1305, keyed by scrypt,
+//! and everything before it is authenticated along with it.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use byteorder::{ByteOrder, LittleEndian};
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::scrypt::{scrypt, ScryptParams};
+use rand::Rng;
+use stream::wipe;
+
+const MAGIC: &'static [u8; 8] = b"hoplkeys";
+pub const VERSION: u8 = 1;
+
+const HEADER_LEN: usize = 8 + 1 + 1 + 4 + 4 + 16 + 8;
+const FILE_LEN: usize = HEADER_LEN + 32 + 16;
+
+/// Most memory, in bytes, that deriving a keystore's key may take.
+pub const MAX_KDF_MEMORY: u64 = 1 << 30;
+
+/// How expensive it is to turn a passphrase into a key, and so to guess at one.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct KdfParams {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+}
+
+impl KdfParams {
+    /// Roughly how much memory scrypt needs: 128 bytes per block, for `r` blocks in each of
+    /// `2^log_n` entries and `p` lanes.
+    pub fn memory(&self) -> u64 {
+        128 * self.r as u64 * ((1u64 << self.log_n) + self.p as u64)
+    }
+
+    /// Parameters past these are refused, since deriving their key could exhaust memory or
+    /// take forever. scrypt also needs `log_n < 16 * r`.
+    fn is_acceptable(&self) -> bool {
+        self.log_n >= 1 && self.log_n <= 22 && (self.log_n as u32) < 16 * self.r
+            && self.r >= 1 && self.r <= 32 && self.p >= 1 && self.p <= 16
+            && self.memory() <= MAX_KDF_MEMORY
+    }
+}
+
+impl Default for KdfParams {
+    fn default() -> KdfParams {
+        KdfParams{
+            log_n: 15,
+            r: 8,
+            p: 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum KeystoreError {
+    Io(io::Error),
+
+    /// Keystores are never overwritten, so that a seed cannot be lost by accident.
+    AlreadyExists,
+
+    /// The file is not a keystore, or is one this version cannot read.
+    Malformed,
+    UnsupportedVersion(u8),
+
+    /// The passphrase is wrong, or the file has been tampered with.
+    NotAuthentic,
+
+    /// `KdfParams` asking for more than is allowed.
+    UnacceptableParams,
+}
+
+impl From<io::Error> for KeystoreError {
+    fn from(e: io::Error) -> KeystoreError {
+        KeystoreError::Io(e)
+    }
+}
+
+impl fmt::Display for KeystoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KeystoreError::Io(ref e) => e.fmt(f),
+            KeystoreError::AlreadyExists => f.write_str("keystore already exists"),
+            KeystoreError::Malformed => f.write_str("not a keystore"),
+            KeystoreError::UnsupportedVersion(version) => write!(f, "unsupported keystore version {}", version),
+            KeystoreError::NotAuthentic => f.write_str("wrong passphrase, or keystore has been altered"),
+            KeystoreError::UnacceptableParams => f.write_str("key derivation parameters out of range"),
+        }
+    }
+}
+
+fn derive_key(passphrase: &[u8], salt: &[u8], params: &KdfParams) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    scrypt(passphrase, salt, &ScryptParams::new(params.log_n, params.r, params.p), &mut key[..]);
+    key
+}
+
+/// Encrypt `seed` into the contents of a keystore file.
+pub fn seal<R: Rng>(seed: &[u8; 32], passphrase: &[u8], params: &KdfParams, rng: &mut R) -> Result<Vec<u8>, KeystoreError> {
+    if !params.is_acceptable() {
+        return Err(KeystoreError::UnacceptableParams);
+    }
+
+    let mut sealed = vec![0u8; FILE_LEN];
+    {
+        let (header, rest) = sealed.split_at_mut(HEADER_LEN);
+        header[..8].copy_from_slice(&MAGIC[..]);
+        header[8] = VERSION;
+        header[9] = params.log_n;
+        LittleEndian::write_u32(&mut header[10..14], params.r);
+        LittleEndian::write_u32(&mut header[14..18], params.p);
+        rng.fill_bytes(&mut header[18..]);
+
+        let mut key = derive_key(passphrase, &header[18..34], params);
+        let (encrypted, tag) = rest.split_at_mut(32);
+        ChaCha20Poly1305::new(&key[..], &header[34..], &header[..]).encrypt(&seed[..], encrypted, tag);
+        wipe(&mut key[..]);
+    }
+    Ok(sealed)
+}
+
+/// Decrypt the contents of a keystore file.
+pub fn unseal(sealed: &[u8], passphrase: &[u8]) -> Result<[u8; 32], KeystoreError> {
+    if sealed.len() < 9 || sealed[..8] != MAGIC[..] {
+        return Err(KeystoreError::Malformed);
+    }
+    if sealed[8] != VERSION {
+        return Err(KeystoreError::UnsupportedVersion(sealed[8]));
+    }
+    if sealed.len() != FILE_LEN {
+        return Err(KeystoreError::Malformed);
+    }
+
+    let (header, rest) = sealed.split_at(HEADER_LEN);
+    let params = KdfParams{
+        log_n: header[9],
+        r: LittleEndian::read_u32(&header[10..14]),
+        p: LittleEndian::read_u32(&header[14..18]),
+    };
+    if !params.is_acceptable() {
+        return Err(KeystoreError::Malformed);
+    }
+
+    let mut key = derive_key(passphrase, &header[18..34], &params);
+    let (encrypted, tag) = rest.split_at(32);
+    let mut seed = [0u8; 32];
+    let authentic = ChaCha20Poly1305::new(&key[..], &header[34..], &header[..]).decrypt(encrypted, &mut seed[..], tag);
+    wipe(&mut key[..]);
+    if !authentic {
+        wipe(&mut seed[..]);
+        return Err(KeystoreError::NotAuthentic);
+    }
+    Ok(seed)
+}
+
+/// Write `seed` to a new keystore at `path`.
+pub fn save<P: AsRef<Path>, R: Rng>(path: P, seed: &[u8; 32], passphrase: &[u8], params: &KdfParams, rng: &mut R) -> Result<(), KeystoreError> {
+    let sealed = try!(seal(seed, passphrase, params, rng));
+    let mut file = match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => { return Err(KeystoreError::AlreadyExists); }
+        Err(e) => { return Err(KeystoreError::Io(e)); }
+    };
+    try!(file.write_all(&sealed[..]));
+    try!(file.sync_all());
+    Ok( () )
+}
+
+/// Read the seed from the keystore at `path`.
+pub fn load<P: AsRef<Path>>(path: P, passphrase: &[u8]) -> Result<[u8; 32], KeystoreError> {
+    let mut sealed = Vec::new();
+    try!(try!(File::open(path)).take(FILE_LEN as u64 + 1).read_to_end(&mut sealed));
+    unseal(&sealed[..], passphrase)
+}
+
+/// Make a new seed and save it to a new keystore at `path`.
+pub fn generate<P: AsRef<Path>, R: Rng>(path: P, passphrase: &[u8], params: &KdfParams, rng: &mut R) -> Result<[u8; 32], KeystoreError> {
+    let mut seed = [0u8; 32];
+    rng.fill_bytes(&mut seed[..]);
+    match save(path, &seed, passphrase, params, rng) {
+        Ok( () ) => Ok(seed),
+        Err(e) => {
+            wipe(&mut seed[..]);
+            Err(e)
+        }
+    }
+}
+
+/// Load the seed from the keystore at `path`, first generating one there if there is none.
+pub fn load_or_generate<P: AsRef<Path>, R: Rng>(path: P, passphrase: &[u8], params: &KdfParams, rng: &mut R) -> Result<[u8; 32], KeystoreError> {
+    match load(path.as_ref(), passphrase) {
+        Err(KeystoreError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => generate(path, passphrase, params, rng),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{generate, load, load_or_generate, save, seal, unseal, KdfParams, KeystoreError, FILE_LEN};
+    use rand::{SeedableRng, XorShiftRng};
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    fn cheap() -> KdfParams {
+        KdfParams{ log_n: 4, r: 8, p: 1 }
+    }
+
+    fn rng() -> XorShiftRng {
+        XorShiftRng::from_seed([1, 2, 3, 4])
+    }
+
+    fn scratch_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("hoplight-keystore-{}-{}", process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn seal_round_trip() {
+        let seed = [0x77; 32];
+        let sealed = seal(&seed, b"correct horse", &cheap(), &mut rng()).unwrap();
+        assert_eq!(sealed.len(), FILE_LEN);
+        assert_eq!(unseal(&sealed[..], b"correct horse").ok(), Some(seed));
+
+        match unseal(&sealed[..], b"wrong horse") { Err(KeystoreError::NotAuthentic) => {}, x => panic!("{:?}", x) }
+
+        // So is any change to the header.
+        let mut altered = sealed.clone();
+        altered[40] ^= 1;
+        match unseal(&altered[..], b"correct horse") { Err(KeystoreError::NotAuthentic) => {}, x => panic!("{:?}", x) }
+
+        let mut future = sealed.clone();
+        future[8] = 2;
+        match unseal(&future[..], b"correct horse") { Err(KeystoreError::UnsupportedVersion(2)) => {}, x => panic!("{:?}", x) }
+        match unseal(&sealed[1..], b"correct horse") { Err(KeystoreError::Malformed) => {}, x => panic!("{:?}", x) }
+        match unseal(&sealed[..FILE_LEN - 1], b"correct horse") { Err(KeystoreError::Malformed) => {}, x => panic!("{:?}", x) }
+    }
+
+    #[test]
+    fn kdf_limits() {
+        assert!(KdfParams::default().is_acceptable());
+        assert!(KdfParams{ log_n: 19, r: 8, p: 1 }.is_acceptable());
+
+        // 16 GiB, and just over 1 GiB.
+        assert!(!KdfParams{ log_n: 22, r: 32, p: 16 }.is_acceptable());
+        assert!(!KdfParams{ log_n: 20, r: 8, p: 1 }.is_acceptable());
+
+        // Too little per block for so many entries.
+        assert!(!KdfParams{ log_n: 16, r: 1, p: 1 }.is_acceptable());
+
+        let params = KdfParams{ log_n: 22, r: 32, p: 1 };
+        match seal(&[0x77; 32], b"pass", &params, &mut rng()) { Err(KeystoreError::UnacceptableParams) => {}, x => panic!("{:?}", x) }
+
+        // Nor are files asking for them opened.
+        let mut sealed = seal(&[0x77; 32], b"pass", &cheap(), &mut rng()).unwrap();
+        sealed[9] = 22;
+        sealed[10] = 32;
+        match unseal(&sealed[..], b"pass") { Err(KeystoreError::Malformed) => {}, x => panic!("{:?}", x) }
+    }
+
+    #[test]
+    fn files() {
+        let path = scratch_path("files");
+        let seed = generate(&path, b"pass", &cheap(), &mut rng()).unwrap();
+        assert_eq!(load(&path, b"pass").ok(), Some(seed));
+        assert_eq!(load_or_generate(&path, b"pass", &cheap(), &mut rng()).ok(), Some(seed));
+
+        match save(&path, &[0; 32], b"pass", &cheap(), &mut rng()) { Err(KeystoreError::AlreadyExists) => {}, x => panic!("{:?}", x) }
+        assert_eq!(load(&path, b"pass").ok(), Some(seed));
+        fs::remove_file(&path).unwrap();
+
+        let missing = scratch_path("missing");
+        match load(&missing, b"pass") { Err(KeystoreError::Io(_)) => {}, x => panic!("{:?}", x) }
+        let generated = load_or_generate(&missing, b"pass", &cheap(), &mut rng()).unwrap();
+        assert_eq!(load(&missing, b"pass").ok(), Some(generated));
+        fs::remove_file(&missing).unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use identity::Identity;
+
+/// Tunes how generous an agent is with its neighbors.
+#[derive(Debug, Copy, Clone)]
+pub struct LedgerConfig {
+    /// Every tally in a neighbor's account halves after this much time (in the units of
+    /// `AgentEnvironment::get_current_timestamp`) passes, so old favors and debts fade.
+    /// Zero disables decay.
+    pub half_life: u64,
+
+    /// Ticks a neighbor may spend even if it has never done anything for us.
+    pub base_allowance: u64,
+
+    /// No single request gets more than this many ticks, however good the neighbor's balance.
+    pub max_ticks: u64,
+
+    /// How many ticks it is worth to us to keep holding one byte for a neighbor.
+    pub ticks_per_stored_byte: u64,
+}
+
+impl Default for LedgerConfig {
+    fn default() -> LedgerConfig {
+        LedgerConfig{
+            half_life: 24*60*60,
+            base_allowance: 100_000,
+            max_ticks: 1_000_000,
+            ticks_per_stored_byte: 1,
+        }
+    }
+}
+
+/// The balance of trade with one neighbor.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Account {
+    /// Ticks the neighbor's programs have consumed on this agent.
+    pub ticks_consumed: u64,
+
+    /// Ticks of work the neighbor has done on our behalf.
+    pub work_done_for_us: u64,
+
+    /// Bytes this agent has stored at the neighbor's request.
+    pub storage_held: u64,
+
+    last_decayed: u64,
+}
+
+impl Account {
+    fn new(now: u64) -> Account {
+        Account{
+            ticks_consumed: 0,
+            work_done_for_us: 0,
+            storage_held: 0,
+            last_decayed: now,
+        }
+    }
+
+    fn decay(&mut self, now: u64, half_life: u64) {
+        if half_life == 0 || now <= self.last_decayed {
+            return;
+        }
+
+        let halvings = (now - self.last_decayed) / half_life;
+        if halvings == 0 {
+            return;
+        }
+
+        let shift = min(halvings, 63) as u32;
+        self.ticks_consumed = self.ticks_consumed >> shift;
+        self.work_done_for_us = self.work_done_for_us >> shift;
+        self.storage_held = self.storage_held >> shift;
+        self.last_decayed = self.last_decayed.saturating_add(halvings.saturating_mul(half_life));
+    }
+
+    /// What the neighbor has given us, less what it has taken, in ticks.
+    pub fn balance(&self, config: &LedgerConfig) -> i64 {
+        let given = self.work_done_for_us;
+        let taken = self.ticks_consumed.saturating_add(self.storage_held.saturating_mul(config.ticks_per_stored_byte));
+        if given >= taken {
+            min(given - taken, i64::max_value() as u64) as i64
+        } else {
+            -(min(taken - given, i64::max_value() as u64) as i64)
+        }
+    }
+}
+
+/// Tracks the balance of trade with each neighbor, which decides how much work we are
+/// willing to do for them.
+pub struct Ledger {
+    config: LedgerConfig,
+    accounts: HashMap<Identity, Account>,
+}
+
+impl Ledger {
+    pub fn new(config: LedgerConfig) -> Ledger {
+        Ledger{
+            config: config,
+            accounts: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &LedgerConfig {
+        &self.config
+    }
+
+    fn account_mut(&mut self, neighbor: &Identity, now: u64) -> &mut Account {
+        let half_life = self.config.half_life;
+        let account = self.accounts.entry(*neighbor).or_insert_with(|| Account::new(now));
+        account.decay(now, half_life);
+        account
+    }
+
+    /// The account for `neighbor`, decayed up to `now`, if we have dealt with them.
+    pub fn account(&mut self, neighbor: &Identity, now: u64) -> Option<Account> {
+        if !self.accounts.contains_key(neighbor) {
+            return None;
+        }
+        Some(*self.account_mut(neighbor, now))
+    }
+
+    pub fn record_ticks_consumed(&mut self, neighbor: &Identity, ticks: u64, now: u64) {
+        let account = self.account_mut(neighbor, now);
+        account.ticks_consumed = account.ticks_consumed.saturating_add(ticks);
+    }
+
+    /// Agents record this when a neighbor answers a request, taking the neighbor's word for what
+    /// it charged. Hosts that pay neighbors in other ways can record that here too.
+    pub fn record_work_done_for_us(&mut self, neighbor: &Identity, ticks: u64, now: u64) {
+        let account = self.account_mut(neighbor, now);
+        account.work_done_for_us = account.work_done_for_us.saturating_add(ticks);
+    }
+
+    pub fn record_storage_held(&mut self, neighbor: &Identity, bytes: u64, now: u64) {
+        let account = self.account_mut(neighbor, now);
+        account.storage_held = account.storage_held.saturating_add(bytes);
+    }
+
+    /// What `neighbor` has given us, less what it has taken, in ticks. Zero for strangers.
+    pub fn balance(&mut self, neighbor: &Identity, now: u64) -> i64 {
+        let config = self.config;
+        self.account(neighbor, now).map(|account| account.balance(&config)).unwrap_or(0)
+    }
+
+    /// How many ticks we are willing to spend on one request from `neighbor`.
+    pub fn ticks_for(&mut self, neighbor: &Identity, now: u64) -> u64 {
+        let config = self.config;
+        let balance = self.account_mut(neighbor, now).balance(&config);
+        let allowance = (config.base_allowance as i64).saturating_add(balance);
+        min(max(allowance, 0) as u64, config.max_ticks)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Ledger, LedgerConfig};
+    use identity::Identity;
+
+    fn config() -> LedgerConfig {
+        LedgerConfig{
+            half_life: 100,
+            base_allowance: 1000,
+            max_ticks: 5000,
+            ticks_per_stored_byte: 2,
+        }
+    }
+
+    #[test]
+    fn allowance_follows_balance() {
+        let neighbor = Identity::from_bytes(&[7; 32]);
+        let mut ledger = Ledger::new(config());
+        assert_eq!(ledger.ticks_for(&neighbor, 0), 1000);
+
+        ledger.record_ticks_consumed(&neighbor, 300, 0);
+        assert_eq!(ledger.ticks_for(&neighbor, 0), 700);
+
+        ledger.record_storage_held(&neighbor, 100, 0);
+        assert_eq!(ledger.ticks_for(&neighbor, 0), 500);
+
+        ledger.record_ticks_consumed(&neighbor, 10_000, 0);
+        assert_eq!(ledger.ticks_for(&neighbor, 0), 0);
+
+        ledger.record_work_done_for_us(&neighbor, 1_000_000, 0);
+        assert_eq!(ledger.ticks_for(&neighbor, 0), 5000);
+    }
+
+    #[test]
+    fn decay() {
+        let neighbor = Identity::from_bytes(&[7; 32]);
+        let mut ledger = Ledger::new(config());
+        ledger.record_ticks_consumed(&neighbor, 800, 1000);
+        assert_eq!(ledger.ticks_for(&neighbor, 1099), 200);
+        assert_eq!(ledger.ticks_for(&neighbor, 1100), 600);
+        assert_eq!(ledger.ticks_for(&neighbor, 1250), 800);
+        assert_eq!(ledger.account(&neighbor, 1250).unwrap().ticks_consumed, 200);
+        assert_eq!(ledger.ticks_for(&neighbor, 100_000), 1000);
+    }
+
+    #[test]
+    fn unknown_neighbor() {
+        let mut ledger = Ledger::new(config());
+        assert!(ledger.account(&Identity::from_bytes(&[9; 32]), 0).is_none());
+    }
+}
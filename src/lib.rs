@@ -1,12 +1,29 @@
 
 pub mod agent;
+pub mod daemon;
 pub mod identity;
 pub mod ip_address_port;
-pub mod vm;
+pub mod keystore;
+pub mod ledger;
+pub mod neighbor_policy;
+pub mod routing_table;
+pub mod udp;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod simulator;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
 mod content_packet;
 mod expected_packet_set;
+mod fragment;
 mod initiation_packet;
+mod lookup;
+mod message;
+mod reliable;
+mod replay_cache;
+mod side_effect_engine;
+mod snapshot;
 mod stream;
 
 #[macro_use] extern crate arrayref;
@@ -14,5 +31,7 @@ extern crate byteorder;
 extern crate checked_int_cast;
 extern crate crypto;
 extern crate rand;
+extern crate vm;
 
 pub use agent::Agent;
+pub use snapshot::SnapshotError;
@@ -0,0 +1,220 @@
+use identity::Identity;
+use ip_address_port::IpAddressPort;
+
+/// How many queries a lookup keeps outstanding at once.
+pub const PARALLELISM: usize = 3;
+
+/// Default for `Agent::lookup_timeout`.
+pub const DEFAULT_LOOKUP_TIMEOUT: u64 = 5;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum QueryState {
+    Unqueried,
+
+    /// Chosen to be queried at `since`. The query itself waits until we have a stream with
+    /// the candidate.
+    Awaiting { since: u64, sent: bool },
+    Responded,
+    Failed,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Candidate {
+    identity: Identity,
+    address: IpAddressPort,
+    state: QueryState,
+}
+
+/// The progress of a search for the agents closest to `target`. Candidates are queried closest
+/// first, and each tells us of the agents it knows nearest the target, until the closest
+/// `count` that have not failed have all answered.
+pub struct Lookup {
+    pub target: Identity,
+    count: usize,
+
+    /// Sorted by distance from `target`.
+    candidates: Vec<Candidate>,
+}
+
+impl Lookup {
+    pub fn new(target: &Identity, count: usize) -> Lookup {
+        Lookup{
+            target: *target,
+            count: count,
+            candidates: Vec::new(),
+        }
+    }
+
+    /// Consider `identity` as a candidate, unless it already is one.
+    pub fn add(&mut self, identity: &Identity, address: &IpAddressPort) {
+        if self.candidates.iter().any(|candidate| candidate.identity == *identity) {
+            return;
+        }
+        let distance = identity.distance(&self.target);
+        let position = self.candidates.iter().position(|candidate| candidate.identity.distance(&self.target) > distance).unwrap_or(self.candidates.len());
+        self.candidates.insert(position, Candidate{
+            identity: *identity,
+            address: *address,
+            state: QueryState::Unqueried,
+        });
+    }
+
+    /// The closest `count` candidates that have not failed.
+    fn leading(&self) -> Vec<usize> {
+        self.candidates.iter().enumerate()
+            .filter(|&(_, candidate)| candidate.state != QueryState::Failed)
+            .map(|(idx, _)| idx)
+            .take(self.count)
+            .collect()
+    }
+
+    /// Choose who to query next, so that up to `PARALLELISM` queries are outstanding.
+    pub fn choose_queries(&mut self, now: u64) -> Vec<(Identity, IpAddressPort)> {
+        let leading = self.leading();
+        let mut outstanding = leading.iter().filter(|idx| match self.candidates[**idx].state { QueryState::Awaiting{..} => true, _ => false }).count();
+        let mut chosen = Vec::new();
+        for idx in leading.into_iter() {
+            if outstanding >= PARALLELISM {
+                break;
+            }
+            let candidate = &mut self.candidates[idx];
+            if candidate.state == QueryState::Unqueried {
+                candidate.state = QueryState::Awaiting{ since: now, sent: false };
+                chosen.push((candidate.identity, candidate.address));
+                outstanding += 1;
+            }
+        }
+        chosen
+    }
+
+    /// Candidates chosen for a query that has not been sent yet.
+    pub fn unsent(&self) -> Vec<Identity> {
+        self.candidates.iter().filter(|candidate| match candidate.state {
+            QueryState::Awaiting{ sent: false, .. } => true,
+            _ => false,
+        }).map(|candidate| candidate.identity).collect()
+    }
+
+    pub fn mark_sent(&mut self, identity: &Identity) {
+        for candidate in self.candidates.iter_mut().filter(|candidate| candidate.identity == *identity) {
+            if let QueryState::Awaiting{ since, .. } = candidate.state {
+                candidate.state = QueryState::Awaiting{ since: since, sent: true };
+            }
+        }
+    }
+
+    /// Give up on `identity`, which could not be queried.
+    pub fn mark_failed(&mut self, identity: &Identity) {
+        for candidate in self.candidates.iter_mut().filter(|candidate| candidate.identity == *identity) {
+            candidate.state = QueryState::Failed;
+        }
+    }
+
+    /// Take in `from`'s answer. Returns false, ignoring the answer, if `from` was not asked.
+    pub fn responded(&mut self, from: &Identity, nodes: &[(Identity, IpAddressPort)]) -> bool {
+        let asked = self.candidates.iter_mut().find(|candidate| candidate.identity == *from).map(|candidate| {
+            match candidate.state {
+                QueryState::Awaiting{ sent: true, .. } => {
+                    candidate.state = QueryState::Responded;
+                    true
+                }
+                _ => false,
+            }
+        }).unwrap_or(false);
+
+        if asked {
+            for &(ref identity, ref address) in nodes.iter() {
+                self.add(identity, address);
+            }
+        }
+        asked
+    }
+
+    /// Fail every query that has been outstanding for `timeout`, returning who they were to.
+    pub fn expire(&mut self, now: u64, timeout: u64) -> Vec<Identity> {
+        let mut expired = Vec::new();
+        for candidate in self.candidates.iter_mut() {
+            if let QueryState::Awaiting{ since, .. } = candidate.state {
+                if now.saturating_sub(since) >= timeout {
+                    candidate.state = QueryState::Failed;
+                    expired.push(candidate.identity);
+                }
+            }
+        }
+        expired
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.leading().iter().all(|idx| self.candidates[*idx].state == QueryState::Responded)
+    }
+
+    /// The closest agents that answered, closest first.
+    pub fn closest(&self) -> Vec<(Identity, IpAddressPort)> {
+        self.candidates.iter()
+            .filter(|candidate| candidate.state == QueryState::Responded)
+            .take(self.count)
+            .map(|candidate| (candidate.identity, candidate.address))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Lookup, PARALLELISM};
+    use identity::Identity;
+    use ip_address_port::IpAddressPort;
+
+    fn identity(first: u8) -> Identity {
+        let mut bs = [0u8; 32];
+        bs[0] = first;
+        Identity::from_bytes(&bs)
+    }
+
+    fn address(port: u16) -> IpAddressPort {
+        IpAddressPort{ address: [7; 16], port: port }
+    }
+
+    #[test]
+    fn converges() {
+        let mut lookup = Lookup::new(&identity(0), 2);
+        for first in [0x80u8, 0x40, 0x20, 0x10].iter() {
+            lookup.add(&identity(*first), &address(*first as u16));
+        }
+
+        let chosen: Vec<Identity> = lookup.choose_queries(0).iter().map(|&(identity, _)| identity).collect();
+        assert_eq!(chosen, vec![identity(0x10), identity(0x20)]);
+        assert!(lookup.choose_queries(0).is_empty());
+        assert_eq!(lookup.unsent(), vec![identity(0x10), identity(0x20)]);
+
+        // Answers from those not yet asked are ignored.
+        assert!(!lookup.responded(&identity(0x10), &[(identity(0x01), address(1))]));
+        lookup.mark_sent(&identity(0x10));
+        lookup.mark_sent(&identity(0x20));
+        assert!(lookup.responded(&identity(0x10), &[(identity(0x01), address(1)), (identity(0x02), address(2))]));
+        assert!(!lookup.is_finished());
+
+        // The closer agents it told us of are queried next, and 0x20 is no longer of interest.
+        let chosen: Vec<Identity> = lookup.choose_queries(1).iter().map(|&(identity, _)| identity).collect();
+        assert_eq!(chosen, vec![identity(0x01), identity(0x02)]);
+        lookup.mark_sent(&identity(0x01));
+        lookup.responded(&identity(0x01), &[]);
+        assert!(!lookup.is_finished());
+
+        assert_eq!(lookup.expire(6, 5), vec![identity(0x02), identity(0x20)]);
+        let chosen: Vec<Identity> = lookup.choose_queries(6).iter().map(|&(identity, _)| identity).collect();
+        assert_eq!(chosen, vec![]);
+        assert!(lookup.is_finished());
+        assert_eq!(lookup.closest(), vec![(identity(0x01), address(1)), (identity(0x10), address(0x10))]);
+    }
+
+    #[test]
+    fn limits_parallelism() {
+        let mut lookup = Lookup::new(&identity(0), 20);
+        for first in 1..20 {
+            lookup.add(&identity(first), &address(first as u16));
+        }
+        assert_eq!(lookup.choose_queries(0).len(), PARALLELISM);
+        lookup.mark_failed(&identity(1));
+        assert_eq!(lookup.choose_queries(0).len(), 1);
+    }
+}
@@ -0,0 +1,37 @@
+extern crate hoplight;
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::process;
+use hoplight::daemon::{Config, Daemon};
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 2 {
+        fail(format!("usage: HOPLIGHT_PASSPHRASE=<passphrase> {} <config file>", args[0]));
+    }
+
+    let mut text = String::new();
+    if let Err(e) = File::open(&args[1]).and_then(|mut file| file.read_to_string(&mut text)) {
+        fail(format!("Could not read {}: {}", args[1], e));
+    }
+    let config = Config::parse(&text).unwrap_or_else(|e| fail(format!("{}:{}: {}", args[1], e.line, e.reason)));
+
+    let passphrase = env::var("HOPLIGHT_PASSPHRASE").unwrap_or_else(|_| fail("Set HOPLIGHT_PASSPHRASE to the keystore's passphrase".to_string()));
+    let mut daemon = Daemon::from_config(&config, passphrase.as_bytes()).unwrap_or_else(|e| fail(format!("Could not start: {}", e)));
+    println!("Identity {}", daemon.agent.identity());
+    println!("Listening on {}", daemon.local_address().map(|address| address.to_string()).unwrap_or_default());
+    for (peer, e) in daemon.bootstrap(&config.peers[..]).into_iter() {
+        eprintln!("Could not start streaming with {}: {:?}", peer.identity, e);
+    }
+
+    if let Err(e) = daemon.run(|e| eprintln!("Task failed: {:?}", e)) {
+        fail(format!("Stopped: {}", e));
+    }
+}
@@ -0,0 +1,184 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use agent::HandleError;
+use fragment::Fragment;
+use identity::Identity;
+use ip_address_port::{self, IpAddressPort};
+use vm::EvalError;
+
+const UNRELIABLE: u8 = 0;
+const RELIABLE: u8 = 1;
+const ACKNOWLEDGEMENT: u8 = 2;
+const FRAGMENT: u8 = 3;
+const FIND_NODE: u8 = 4;
+const NODES: u8 = 5;
+const REQUEST: u8 = 6;
+const RESPONSE: u8 = 7;
+
+/// Most agents one `Nodes` message may tell of.
+pub const MAX_NODES: usize = 32;
+
+const NODE_LEN: usize = 32 + ip_address_port::ENCODED_LEN;
+
+/// Longest header a message puts in front of a serialized noun: the kind, the sequence or
+/// request number, and a `Response`'s ticks and result tag.
+pub const MAX_HEADER_LEN: usize = 1 + 8 + 8 + 1;
+
+/// What a content packet carries, once decrypted and unframed.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Message<'a> {
+    /// Delivered at most once, in no particular order.
+    Unreliable(&'a [u8]),
+
+    /// Retransmitted until acknowledged and delivered in order of sequence number.
+    Reliable(u64, &'a [u8]),
+
+    /// Every reliable message before this sequence number has been delivered.
+    Acknowledgement(u64),
+
+    /// Part of the encoding of some other message, which was too long for one packet.
+    Fragment(Fragment<'a>),
+
+    /// Asks for the agents the neighbor knows closest to an identity, as part of the lookup with
+    /// the given number.
+    FindNode(u64, Identity),
+
+    /// Answers `FindNode` with identities and where to reach them.
+    Nodes(u64, Vec<(Identity, IpAddressPort)>),
+
+    /// A program to run, whose result should be sent back in a `Response` with the same number.
+    Request(u64, &'a [u8]),
+
+    /// The serialized result of the request with the given number, or why it failed, along with
+    /// the ticks the responder charged for running it.
+    Response(u64, u64, Result<&'a [u8], EvalError>),
+}
+
+impl<'a> Message<'a> {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        match *self {
+            Message::Unreliable(body) => {
+                encoded.push(UNRELIABLE);
+                encoded.extend_from_slice(body);
+            }
+            Message::Reliable(sequence, body) => {
+                encoded.push(RELIABLE);
+                encoded.write_u64::<LittleEndian>(sequence).unwrap();
+                encoded.extend_from_slice(body);
+            }
+            Message::Acknowledgement(next_expected) => {
+                encoded.push(ACKNOWLEDGEMENT);
+                encoded.write_u64::<LittleEndian>(next_expected).unwrap();
+            }
+            Message::Fragment(ref fragment) => {
+                encoded.push(FRAGMENT);
+                fragment.encode_into(&mut encoded);
+            }
+            Message::FindNode(lookup, ref target) => {
+                encoded.push(FIND_NODE);
+                encoded.write_u64::<LittleEndian>(lookup).unwrap();
+                encoded.extend_from_slice(&target.as_bytes()[..]);
+            }
+            Message::Nodes(lookup, ref nodes) => {
+                encoded.push(NODES);
+                encoded.write_u64::<LittleEndian>(lookup).unwrap();
+                for &(ref identity, ref address) in nodes.iter() {
+                    encoded.extend_from_slice(&identity.as_bytes()[..]);
+                    encoded.extend_from_slice(&address.as_bytes()[..]);
+                }
+            }
+            Message::Request(request, body) => {
+                encoded.push(REQUEST);
+                encoded.write_u64::<LittleEndian>(request).unwrap();
+                encoded.extend_from_slice(body);
+            }
+            Message::Response(request, ticks, ref result) => {
+                encoded.push(RESPONSE);
+                encoded.write_u64::<LittleEndian>(request).unwrap();
+                encoded.write_u64::<LittleEndian>(ticks).unwrap();
+                match *result {
+                    Ok(result) => {
+                        encoded.push(0);
+                        encoded.extend_from_slice(result);
+                    }
+                    Err(ref e) => {
+                        encoded.push(1);
+                        encoded.extend_from_slice(&e.encode()[..]);
+                    }
+                }
+            }
+        }
+        encoded
+    }
+
+    pub fn decode(bs: &'a [u8]) -> Result<Message<'a>, HandleError> {
+        let (kind, rest) = if let Some((kind, rest)) = bs.split_first() { (*kind, rest) } else {
+            return Err(HandleError::MalformedPayload);
+        };
+
+        match kind {
+            UNRELIABLE => { return Ok(Message::Unreliable(rest)); }
+            FRAGMENT => { return Ok(Message::Fragment(try!(Fragment::decode(rest)))); }
+            _ => {}
+        }
+        if rest.len() < 8 {
+            return Err(HandleError::MalformedPayload);
+        }
+        let (sequence_bytes, body) = rest.split_at(8);
+        let sequence = (&sequence_bytes[..]).read_u64::<LittleEndian>().unwrap();
+        match kind {
+            RELIABLE => Ok(Message::Reliable(sequence, body)),
+            ACKNOWLEDGEMENT if body.len() == 0 => Ok(Message::Acknowledgement(sequence)),
+            FIND_NODE if body.len() == 32 => Ok(Message::FindNode(sequence, Identity::from_bytes(array_ref!(body, 0, 32)))),
+            NODES if body.len() % NODE_LEN == 0 && body.len() / NODE_LEN <= MAX_NODES => {
+                Ok(Message::Nodes(sequence, body.chunks(NODE_LEN).map(|node| (
+                    Identity::from_bytes(array_ref!(node, 0, 32)),
+                    IpAddressPort::from_bytes(array_ref!(node, 32, ip_address_port::ENCODED_LEN)),
+                )).collect()))
+            }
+            REQUEST => Ok(Message::Request(sequence, body)),
+            RESPONSE if body.len() >= 8 => {
+                let (mut ticks_bytes, result) = body.split_at(8);
+                let ticks = ticks_bytes.read_u64::<LittleEndian>().unwrap();
+                match result.split_first() {
+                    Some((&0, result)) => Ok(Message::Response(sequence, ticks, Ok(result))),
+                    Some((&1, error)) if error.len() == 2 => {
+                        let error = try!(EvalError::decode([error[0], error[1]]).ok_or(HandleError::MalformedPayload));
+                        Ok(Message::Response(sequence, ticks, Err(error)))
+                    }
+                    _ => Err(HandleError::MalformedPayload),
+                }
+            }
+            _ => Err(HandleError::MalformedPayload),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Message;
+    use fragment::Fragment;
+    use identity::Identity;
+    use ip_address_port::IpAddressPort;
+    use vm::EvalError;
+
+    #[test]
+    fn encoding() {
+        for message in [Message::Unreliable(b"abc"), Message::Reliable(0x1234, b"defg"), Message::Acknowledgement(7), Message::Unreliable(b""),
+                        Message::Fragment(Fragment{ message_id: 9, index: 1, count: 3, chunk: b"hi" }),
+                        Message::FindNode(4, Identity::from_bytes(&[8; 32])), Message::Nodes(5, vec![]),
+                        Message::Nodes(6, vec![(Identity::from_bytes(&[9; 32]), IpAddressPort{ address: [1; 16], port: 80 }); 3]),
+                        Message::Request(7, b"program"), Message::Response(7, 30, Ok(b"result")), Message::Response(8, 0, Err(EvalError::BadOpcode(3)))].iter() {
+            assert_eq!(Message::decode(&message.encode()[..]).ok().unwrap(), *message);
+        }
+        assert!(Message::decode(&[]).is_err());
+        assert!(Message::decode(&[1, 2, 3]).is_err());
+        assert!(Message::decode(&[2, 0, 0, 0, 0, 0, 0, 0, 0, 5]).is_err());
+        assert!(Message::decode(&[3, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        assert!(Message::decode(&[4, 0, 0, 0, 0, 0, 0, 0, 0, 1]).is_err());
+        assert!(Message::decode(&[5, 0, 0, 0, 0, 0, 0, 0, 0, 1]).is_err());
+        assert!(Message::decode(&[7, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        assert!(Message::decode(&[7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        assert!(Message::decode(&[7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 99, 0]).is_err());
+    }
+}
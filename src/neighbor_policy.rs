@@ -0,0 +1,104 @@
+use identity::Identity;
+
+/// What an agent knows about a current neighbor when deciding whether to keep it.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct NeighborSummary {
+    pub identity: Identity,
+
+    /// The neighbor's balance of trade with us, from the `Ledger`. Positive if it has given us
+    /// more than it has taken.
+    pub balance: i64,
+
+    /// Timestamp of the last packet we got from the neighbor.
+    pub last_heard_from: u64,
+}
+
+/// Decides which agents may be neighbors.
+pub trait NeighborPolicy {
+    /// Whether `candidate` may become a neighbor at all. Called before any room is made for it.
+    fn admit(&mut self, candidate: &Identity) -> bool;
+
+    /// The most neighbors to keep at once.
+    fn max_neighbors(&self) -> usize;
+
+    /// Pick a neighbor to drop to make room for a new one, or `None` to turn the newcomer away
+    /// instead.
+    fn choose_eviction(&mut self, neighbors: &[NeighborSummary], now: u64) -> Option<Identity>;
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EvictionCriterion {
+    /// Evict whoever has the worst balance of trade.
+    LowestBalance,
+
+    /// Evict whoever we have heard from least recently.
+    LongestIdle,
+}
+
+/// Admits everyone, up to a limit, and evicts by a single criterion once that limit is reached.
+pub struct DefaultNeighborPolicy {
+    pub max_neighbors: usize,
+    pub criterion: EvictionCriterion,
+}
+
+impl Default for DefaultNeighborPolicy {
+    fn default() -> DefaultNeighborPolicy {
+        DefaultNeighborPolicy{
+            max_neighbors: 256,
+            criterion: EvictionCriterion::LowestBalance,
+        }
+    }
+}
+
+impl NeighborPolicy for DefaultNeighborPolicy {
+    fn admit(&mut self, _candidate: &Identity) -> bool {
+        true
+    }
+
+    fn max_neighbors(&self) -> usize {
+        self.max_neighbors
+    }
+
+    fn choose_eviction(&mut self, neighbors: &[NeighborSummary], now: u64) -> Option<Identity> {
+        let idle_time = |summary: &NeighborSummary| now.saturating_sub(summary.last_heard_from);
+        let least_useful = match self.criterion {
+            // Ties go to whoever has been quiet longest.
+            EvictionCriterion::LowestBalance => neighbors.iter().min_by_key(|summary| (summary.balance, !idle_time(summary))),
+            EvictionCriterion::LongestIdle => neighbors.iter().max_by_key(|summary| (idle_time(summary), -summary.balance)),
+        };
+        least_useful.map(|summary| summary.identity)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DefaultNeighborPolicy, EvictionCriterion, NeighborPolicy, NeighborSummary};
+    use identity::Identity;
+
+    fn summaries() -> Vec<NeighborSummary> {
+        vec![
+            NeighborSummary{ identity: Identity::from_bytes(&[1; 32]), balance: 50, last_heard_from: 10 },
+            NeighborSummary{ identity: Identity::from_bytes(&[2; 32]), balance: -20, last_heard_from: 90 },
+            NeighborSummary{ identity: Identity::from_bytes(&[3; 32]), balance: -20, last_heard_from: 80 },
+            NeighborSummary{ identity: Identity::from_bytes(&[4; 32]), balance: 70, last_heard_from: 95 },
+        ]
+    }
+
+    #[test]
+    fn evict_lowest_balance() {
+        let mut policy = DefaultNeighborPolicy{ max_neighbors: 4, criterion: EvictionCriterion::LowestBalance };
+        assert_eq!(policy.choose_eviction(&summaries(), 100), Some(Identity::from_bytes(&[3; 32])));
+    }
+
+    #[test]
+    fn evict_longest_idle() {
+        let mut policy = DefaultNeighborPolicy{ max_neighbors: 4, criterion: EvictionCriterion::LongestIdle };
+        assert_eq!(policy.choose_eviction(&summaries(), 100), Some(Identity::from_bytes(&[1; 32])));
+    }
+
+    #[test]
+    fn nobody_to_evict() {
+        let mut policy = DefaultNeighborPolicy::default();
+        assert_eq!(policy.choose_eviction(&[], 100), None);
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::{BTreeMap, VecDeque};
+use agent::HandleError;
+use snapshot::{self, SnapshotError};
+
+/// Most reliable messages that may be awaiting acknowledgement at once, and most that a receiver
+/// will hold while waiting for an earlier one to arrive.
+pub const WINDOW: u64 = 256;
+
+/// Most bytes of reliable messages a receiver will hold while waiting for an earlier one to
+/// arrive. Messages past this are dropped, to be retransmitted once there is room.
+pub const MAX_OUT_OF_ORDER_LEN: usize = 4 << 20;
+
+struct Unacknowledged {
+    sequence: u64,
+    body: Vec<u8>,
+    last_sent: u64,
+}
+
+/// Sequencing state for reliable messages exchanged with one neighbor.
+pub struct ReliableChannel {
+    next_outgoing: u64,
+    unacknowledged: VecDeque<Unacknowledged>,
+
+    next_incoming: u64,
+
+    /// Messages that arrived ahead of `next_incoming`, waiting for the gap to fill.
+    out_of_order: BTreeMap<u64, Vec<u8>>,
+
+    /// Total length of the messages in `out_of_order`.
+    out_of_order_len: usize,
+}
+
+impl ReliableChannel {
+    pub fn new() -> ReliableChannel {
+        ReliableChannel {
+            next_outgoing: 0,
+            unacknowledged: VecDeque::new(),
+            next_incoming: 0,
+            out_of_order: BTreeMap::new(),
+            out_of_order_len: 0,
+        }
+    }
+
+    /// Assign the next sequence number to `body` and hold on to it until it is acknowledged.
+    pub fn push_outgoing(&mut self, body: &[u8], now: u64) -> Result<u64, HandleError> {
+        if self.unacknowledged.len() as u64 >= WINDOW {
+            return Err(HandleError::WindowFull);
+        }
+
+        let sequence = self.next_outgoing;
+        self.next_outgoing += 1;
+        self.unacknowledged.push_back(Unacknowledged{
+            sequence: sequence,
+            body: body.to_vec(),
+            last_sent: now,
+        });
+        Ok(sequence)
+    }
+
+    /// Undo the last `push_outgoing`, for a message that could not be sent at all.
+    pub fn cancel_latest(&mut self) {
+        if self.unacknowledged.pop_back().is_some() {
+            self.next_outgoing -= 1;
+        }
+    }
+
+    /// Stop retransmitting everything before `next_expected`, returning the sequence numbers
+    /// that were newly acknowledged.
+    pub fn acknowledge(&mut self, next_expected: u64) -> Vec<u64> {
+        let mut acknowledged = Vec::new();
+        while self.unacknowledged.front().map(|message| message.sequence < next_expected).unwrap_or(false) {
+            acknowledged.push(self.unacknowledged.pop_front().unwrap().sequence);
+        }
+        acknowledged
+    }
+
+    /// Messages that have gone `interval` without being acknowledged, as (sequence, body) pairs.
+    /// They are treated as sent again as of `now`.
+    pub fn due_for_retransmission(&mut self, now: u64, interval: u64) -> Vec<(u64, Vec<u8>)> {
+        let mut due = Vec::new();
+        for message in self.unacknowledged.iter_mut() {
+            if now.saturating_sub(message.last_sent) >= interval {
+                message.last_sent = now;
+                due.push((message.sequence, message.body.clone()));
+            }
+        }
+        due
+    }
+
+    /// Take in a reliable message, returning whatever can now be delivered, in order.
+    pub fn receive(&mut self, sequence: u64, body: &[u8]) -> Vec<Vec<u8>> {
+        if sequence < self.next_incoming || sequence >= self.next_incoming + WINDOW {
+            // Either a retransmission of something already delivered, or too far ahead to hold.
+            // The sender will hear `next_expected` and act accordingly.
+            return Vec::new();
+        }
+        if sequence != self.next_incoming {
+            if self.out_of_order.contains_key(&sequence) || self.out_of_order_len + body.len() > MAX_OUT_OF_ORDER_LEN {
+                return Vec::new();
+            }
+            self.out_of_order.insert(sequence, body.to_vec());
+            self.out_of_order_len += body.len();
+            return Vec::new();
+        }
+
+        let mut deliverable = vec![body.to_vec()];
+        self.next_incoming += 1;
+        while let Some(body) = self.out_of_order.remove(&self.next_incoming) {
+            self.out_of_order_len -= body.len();
+            deliverable.push(body);
+            self.next_incoming += 1;
+        }
+        deliverable
+    }
+
+    /// The acknowledgement to send after receiving a reliable message.
+    pub fn next_expected(&self) -> u64 {
+        self.next_incoming
+    }
+
+    /// Write the sequence numbers and unacknowledged messages. Messages that arrived out of
+    /// order are left out, since their sender will retransmit them.
+    pub fn export(&self, out: &mut Vec<u8>) {
+        snapshot::write_u64(out, self.next_outgoing);
+        snapshot::write_u64(out, self.next_incoming);
+        snapshot::write_u64(out, self.unacknowledged.len() as u64);
+        for message in self.unacknowledged.iter() {
+            snapshot::write_u64(out, message.sequence);
+            snapshot::write_u64(out, message.body.len() as u64);
+            out.extend_from_slice(&message.body[..]);
+        }
+    }
+
+    /// Rebuild a channel written by `export`. Its unacknowledged messages are due for
+    /// retransmission straight away.
+    pub fn import(bs: &mut &[u8]) -> Result<ReliableChannel, SnapshotError> {
+        let mut channel = ReliableChannel::new();
+        channel.next_outgoing = try!(snapshot::read_u64(bs));
+        channel.next_incoming = try!(snapshot::read_u64(bs));
+        let count = try!(snapshot::read_u64(bs));
+        if count > WINDOW {
+            return Err(SnapshotError::Malformed);
+        }
+        for _ in 0..count {
+            let sequence = try!(snapshot::read_u64(bs));
+            let len = try!(snapshot::read_u64(bs));
+            if len > bs.len() as u64 || sequence >= channel.next_outgoing {
+                return Err(SnapshotError::Malformed);
+            }
+            channel.unacknowledged.push_back(Unacknowledged{
+                sequence: sequence,
+                body: try!(snapshot::read_slice(bs, len as usize)).to_vec(),
+                last_sent: 0,
+            });
+        }
+        Ok(channel)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ReliableChannel, MAX_OUT_OF_ORDER_LEN, WINDOW};
+
+    #[test]
+    fn in_order_delivery() {
+        let mut channel = ReliableChannel::new();
+        assert_eq!(channel.receive(1, b"b"), Vec::<Vec<u8>>::new());
+        assert_eq!(channel.receive(2, b"c"), Vec::<Vec<u8>>::new());
+        assert_eq!(channel.next_expected(), 0);
+        assert_eq!(channel.receive(0, b"a"), vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(channel.next_expected(), 3);
+
+        // Duplicates and messages beyond the window are dropped.
+        assert_eq!(channel.receive(1, b"b"), Vec::<Vec<u8>>::new());
+        assert_eq!(channel.receive(3 + WINDOW, b"z"), Vec::<Vec<u8>>::new());
+        assert_eq!(channel.receive(3, b"d"), vec![b"d".to_vec()]);
+    }
+
+    #[test]
+    fn out_of_order_len_limited() {
+        let mut channel = ReliableChannel::new();
+        let big = vec![7u8; MAX_OUT_OF_ORDER_LEN / 2];
+        assert!(channel.receive(1, &big[..]).is_empty());
+        assert!(channel.receive(2, &big[..]).is_empty());
+
+        // No room for this one until the others have been delivered.
+        assert!(channel.receive(3, b"d").is_empty());
+        assert_eq!(channel.receive(0, b"a"), vec![b"a".to_vec(), big.clone(), big.clone()]);
+        assert_eq!(channel.receive(4, b"e"), Vec::<Vec<u8>>::new());
+        assert_eq!(channel.receive(3, b"d"), vec![b"d".to_vec(), b"e".to_vec()]);
+    }
+
+    #[test]
+    fn retransmission() {
+        let mut channel = ReliableChannel::new();
+        assert_eq!(channel.push_outgoing(b"a", 100).ok(), Some(0));
+        assert_eq!(channel.push_outgoing(b"b", 101).ok(), Some(1));
+        assert_eq!(channel.push_outgoing(b"c", 102).ok(), Some(2));
+
+        assert_eq!(channel.due_for_retransmission(102, 2), vec![(0, b"a".to_vec())]);
+        assert_eq!(channel.due_for_retransmission(103, 2), vec![(1, b"b".to_vec())]);
+
+        assert_eq!(channel.acknowledge(2), vec![0, 1]);
+        assert_eq!(channel.acknowledge(2), Vec::<u64>::new());
+        assert_eq!(channel.due_for_retransmission(110, 2), vec![(2, b"c".to_vec())]);
+    }
+
+    #[test]
+    fn window_full() {
+        let mut channel = ReliableChannel::new();
+        for _ in 0..WINDOW {
+            channel.push_outgoing(b"x", 0).ok().expect("window filled early");
+        }
+        assert!(channel.push_outgoing(b"x", 0).is_err());
+        channel.acknowledge(1);
+        assert_eq!(channel.push_outgoing(b"x", 0).ok(), Some(WINDOW));
+    }
+}
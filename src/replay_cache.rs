@@ -0,0 +1,131 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use ip_address_port::IpAddressPort;
+
+/// Remembers the ephemeral public keys of recently accepted initiation packets, so that a
+/// captured packet cannot be replayed while its timestamp is still fresh.
+///
+/// The cache is bounded. If more than `capacity` packets arrive within one acceptance window,
+/// the oldest are forgotten early and could be replayed until their timestamps go stale. Since
+/// anyone can mint identities to sign initiations with, each source address may only hold
+/// `per_source` entries, so that flushing the cache takes packets from many addresses.
+pub struct ReplayCache {
+    capacity: usize,
+    per_source: usize,
+    seen: HashSet<[u8; 32]>,
+
+    /// Keys in `seen`, oldest first, with the timestamps and source addresses of the packets
+    /// that carried them.
+    order: VecDeque<([u8; 32], u64, [u8; 16])>,
+
+    /// How many entries in `order` came from each source address.
+    by_source: HashMap<[u8; 16], usize>,
+}
+
+/// Why `ReplayCache::insert` turned a key away.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Rejection {
+    /// The key has already been used.
+    Replayed,
+
+    /// The source address already holds its share of the cache.
+    SourceFull,
+}
+
+impl ReplayCache {
+    pub fn new(capacity: usize, per_source: usize) -> ReplayCache {
+        ReplayCache {
+            capacity: capacity,
+            per_source: per_source,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+            by_source: HashMap::new(),
+        }
+    }
+
+    /// Record `key`, from a packet sent from `source`, as used.
+    ///
+    /// Keys from packets older than `oldest_acceptable` are dropped, since those packets would
+    /// now be rejected for their timestamps anyway.
+    pub fn insert(&mut self, key: &[u8; 32], source: &IpAddressPort, timestamp: u64, oldest_acceptable: u64) -> Result<(), Rejection> {
+        while let Some(&(oldest_key, oldest_timestamp, oldest_source)) = self.order.front() {
+            if oldest_timestamp >= oldest_acceptable && self.order.len() < self.capacity {
+                break;
+            }
+            self.seen.remove(&oldest_key);
+            self.order.pop_front();
+            let emptied = {
+                let count = self.by_source.get_mut(&oldest_source).unwrap();
+                *count -= 1;
+                *count == 0
+            };
+            if emptied {
+                self.by_source.remove(&oldest_source);
+            }
+        }
+
+        if self.seen.contains(key) {
+            return Err(Rejection::Replayed);
+        }
+        if self.capacity == 0 || self.by_source.get(&source.address).cloned().unwrap_or(0) >= self.per_source {
+            return Err(Rejection::SourceFull);
+        }
+        *self.by_source.entry(source.address).or_insert(0) += 1;
+        self.seen.insert(*key);
+        self.order.push_back((*key, timestamp, source.address));
+        Ok( () )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Rejection, ReplayCache};
+    use ip_address_port::IpAddressPort;
+
+    fn source(n: u8) -> IpAddressPort {
+        IpAddressPort{ address: [n; 16], port: 5000 }
+    }
+
+    #[test]
+    fn rejects_repeats() {
+        let mut cache = ReplayCache::new(10, 10);
+        assert_eq!(cache.insert(&[1; 32], &source(1), 100, 0), Ok( () ));
+        assert_eq!(cache.insert(&[2; 32], &source(1), 100, 0), Ok( () ));
+        assert_eq!(cache.insert(&[1; 32], &source(2), 101, 0), Err(Rejection::Replayed));
+    }
+
+    #[test]
+    fn forgets_stale() {
+        let mut cache = ReplayCache::new(10, 10);
+        assert_eq!(cache.insert(&[1; 32], &source(1), 100, 0), Ok( () ));
+        assert_eq!(cache.insert(&[2; 32], &source(1), 200, 150), Ok( () ));
+        assert_eq!(cache.insert(&[1; 32], &source(1), 201, 150), Ok( () ));
+        assert_eq!(cache.insert(&[2; 32], &source(1), 202, 150), Err(Rejection::Replayed));
+    }
+
+    #[test]
+    fn bounded() {
+        let mut cache = ReplayCache::new(2, 2);
+        assert_eq!(cache.insert(&[1; 32], &source(1), 100, 0), Ok( () ));
+        assert_eq!(cache.insert(&[2; 32], &source(2), 100, 0), Ok( () ));
+        assert_eq!(cache.insert(&[3; 32], &source(2), 100, 0), Ok( () ));
+        assert_eq!(cache.order.len(), 2);
+        assert_eq!(cache.insert(&[1; 32], &source(1), 100, 0), Ok( () ));
+    }
+
+    #[test]
+    fn bounded_per_source() {
+        let mut cache = ReplayCache::new(10, 2);
+        assert_eq!(cache.insert(&[1; 32], &source(1), 100, 0), Ok( () ));
+        for key in 2..4 {
+            assert_eq!(cache.insert(&[key; 32], &source(2), 100, 0), Ok( () ));
+        }
+
+        // The flood from source 2 cannot push out the key from source 1.
+        assert_eq!(cache.insert(&[4; 32], &IpAddressPort{ port: 5001, ..source(2) }, 100, 0), Err(Rejection::SourceFull));
+        assert_eq!(cache.insert(&[1; 32], &source(2), 100, 0), Err(Rejection::Replayed));
+
+        // Once its entries go stale, the source has room again.
+        assert_eq!(cache.insert(&[4; 32], &source(2), 200, 150), Ok( () ));
+        assert_eq!(cache.by_source.len(), 1);
+    }
+}
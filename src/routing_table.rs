@@ -0,0 +1,209 @@
+use identity::Identity;
+use ip_address_port::IpAddressPort;
+
+/// Default for how many contacts each bucket holds.
+pub const DEFAULT_BUCKET_SIZE: usize = 20;
+
+/// Default for `RoutingTable::failure_limit`.
+pub const DEFAULT_FAILURE_LIMIT: u32 = 3;
+
+/// An identity we know how to reach, whether or not it is currently a neighbor.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Contact {
+    pub identity: Identity,
+    pub address: IpAddressPort,
+
+    /// Timestamp of when we last heard from it.
+    pub last_seen: u64,
+
+    /// Times in a row it has failed to respond since it was last seen.
+    pub failures: u32,
+}
+
+/// What `RoutingTable::observe` did with a contact.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Observation {
+    Added,
+
+    /// Already known. It is now the most recently seen in its bucket.
+    Refreshed,
+
+    /// Its bucket was full, so it took the place of this contact, which had been failing.
+    Replaced(Identity),
+
+    /// Its bucket was full of contacts that are still responding, so it was left out. Long-lived
+    /// contacts are preferred, since they tend to stay up.
+    BucketFull,
+
+    /// Our own identity, which is never added.
+    Ignored,
+}
+
+/// Known identities, organized into k-buckets by their XOR distance from our own identity.
+///
+/// Bucket `i` holds identities whose distance from ours has `i` leading zero bits, so each
+/// bucket covers half the distance of the one before it. Within a bucket, contacts are kept in
+/// order of when they were last seen, least recent first.
+pub struct RoutingTable {
+    own_identity: Identity,
+    bucket_size: usize,
+
+    /// A contact that has failed to respond this many times in a row is dropped.
+    pub failure_limit: u32,
+    buckets: Vec<Vec<Contact>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_identity: &Identity, bucket_size: usize) -> RoutingTable {
+        RoutingTable{
+            own_identity: *own_identity,
+            bucket_size: bucket_size,
+            failure_limit: DEFAULT_FAILURE_LIMIT,
+            buckets: (0..256).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    /// Which bucket `identity` belongs in, or `None` if it is our own.
+    fn bucket_index(&self, identity: &Identity) -> Option<usize> {
+        let distance = self.own_identity.distance(identity);
+        distance.iter().position(|b| *b != 0).map(|idx| idx * 8 + distance[idx].leading_zeros() as usize)
+    }
+
+    /// Note that `identity` has been heard from at `address`.
+    pub fn observe(&mut self, identity: &Identity, address: &IpAddressPort, now: u64) -> Observation {
+        let index = if let Some(index) = self.bucket_index(identity) { index } else { return Observation::Ignored };
+        let bucket_size = self.bucket_size;
+        let bucket = &mut self.buckets[index];
+        let contact = Contact{ identity: *identity, address: *address, last_seen: now, failures: 0 };
+
+        if let Some(position) = bucket.iter().position(|known| known.identity == *identity) {
+            bucket.remove(position);
+            bucket.push(contact);
+            return Observation::Refreshed;
+        }
+        if bucket.len() < bucket_size {
+            bucket.push(contact);
+            return Observation::Added;
+        }
+
+        if let Some(position) = bucket.iter().position(|known| known.failures > 0) {
+            let replaced = bucket.remove(position);
+            bucket.push(contact);
+            Observation::Replaced(replaced.identity)
+        } else {
+            Observation::BucketFull
+        }
+    }
+
+    /// Note that `identity` did not respond when it should have. It is dropped once it has
+    /// failed `failure_limit` times in a row.
+    pub fn record_failure(&mut self, identity: &Identity) {
+        let failure_limit = self.failure_limit;
+        if let Some(index) = self.bucket_index(identity) {
+            let bucket = &mut self.buckets[index];
+            if let Some(position) = bucket.iter().position(|known| known.identity == *identity) {
+                bucket[position].failures += 1;
+                if bucket[position].failures >= failure_limit {
+                    bucket.remove(position);
+                }
+            }
+        }
+    }
+
+    pub fn remove(&mut self, identity: &Identity) {
+        if let Some(index) = self.bucket_index(identity) {
+            self.buckets[index].retain(|known| known.identity != *identity);
+        }
+    }
+
+    pub fn get(&self, identity: &Identity) -> Option<&Contact> {
+        self.bucket_index(identity).and_then(|index| self.buckets[index].iter().find(|known| known.identity == *identity))
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.len()).sum()
+    }
+
+    /// Up to `count` known contacts, closest to `target` first.
+    pub fn closest(&self, target: &Identity, count: usize) -> Vec<Contact> {
+        let mut contacts: Vec<Contact> = self.buckets.iter().flat_map(|bucket| bucket.iter().cloned()).collect();
+        contacts.sort_by_key(|contact| contact.identity.distance(target));
+        contacts.truncate(count);
+        contacts
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Observation, RoutingTable};
+    use identity::Identity;
+    use ip_address_port::IpAddressPort;
+
+    fn identity(first: u8, last: u8) -> Identity {
+        let mut bs = [0u8; 32];
+        bs[0] = first;
+        bs[31] = last;
+        Identity::from_bytes(&bs)
+    }
+
+    fn address(port: u16) -> IpAddressPort {
+        IpAddressPort{ address: [7; 16], port: port }
+    }
+
+    #[test]
+    fn buckets_by_distance() {
+        let table = RoutingTable::new(&identity(0, 0), 2);
+        assert_eq!(table.bucket_index(&identity(0, 0)), None);
+        assert_eq!(table.bucket_index(&identity(0x80, 0)), Some(0));
+        assert_eq!(table.bucket_index(&identity(0x7f, 5)), Some(1));
+        assert_eq!(table.bucket_index(&identity(0, 1)), Some(255));
+    }
+
+    #[test]
+    fn full_buckets() {
+        let mut table = RoutingTable::new(&identity(0, 0), 2);
+        assert_eq!(table.observe(&identity(0x80, 1), &address(1), 10), Observation::Added);
+        assert_eq!(table.observe(&identity(0x80, 2), &address(2), 11), Observation::Added);
+        assert_eq!(table.observe(&identity(0x80, 3), &address(3), 12), Observation::BucketFull);
+        assert_eq!(table.observe(&identity(0x40, 3), &address(3), 12), Observation::Added);
+
+        assert_eq!(table.observe(&identity(0x80, 1), &address(4), 13), Observation::Refreshed);
+        assert_eq!(table.get(&identity(0x80, 1)).unwrap().address, address(4));
+
+        // A contact that has stopped responding gives up its place.
+        table.record_failure(&identity(0x80, 2));
+        assert_eq!(table.observe(&identity(0x80, 3), &address(3), 14), Observation::Replaced(identity(0x80, 2)));
+        assert!(table.get(&identity(0x80, 2)).is_none());
+        assert_eq!(table.len(), 3);
+    }
+
+    #[test]
+    fn failures() {
+        let mut table = RoutingTable::new(&identity(0, 0), 2);
+        table.observe(&identity(0x80, 1), &address(1), 10);
+        for _ in 0..2 {
+            table.record_failure(&identity(0x80, 1));
+        }
+        assert_eq!(table.get(&identity(0x80, 1)).unwrap().failures, 2);
+
+        // Hearing from it again clears the failures.
+        table.observe(&identity(0x80, 1), &address(1), 11);
+        for _ in 0..2 {
+            table.record_failure(&identity(0x80, 1));
+        }
+        assert!(table.get(&identity(0x80, 1)).is_some());
+        table.record_failure(&identity(0x80, 1));
+        assert!(table.get(&identity(0x80, 1)).is_none());
+    }
+
+    #[test]
+    fn closest() {
+        let mut table = RoutingTable::new(&identity(0, 0), 20);
+        for first in [0x01u8, 0x10, 0x11, 0x80, 0xf0].iter() {
+            table.observe(&identity(*first, 0), &address(*first as u16), 0);
+        }
+        let closest: Vec<Identity> = table.closest(&identity(0x11, 9), 3).iter().map(|contact| contact.identity).collect();
+        assert_eq!(closest, vec![identity(0x11, 0), identity(0x10, 0), identity(0x01, 0)]);
+        assert_eq!(table.closest(&identity(0, 0), 100).len(), 5);
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use rand::Rng;
+use vm::{SideEffectEngine, Ticks};
+use agent::{AgentEnvironment, NeighborState};
+use identity::Identity;
+use ip_address_port::IpAddressPort;
+use ledger::Ledger;
+use routing_table::RoutingTable;
+
+/// Storage tag for spent `EXECUTE_AS` counters. The evaluator itself uses 0 for values stored
+/// by key and 1 for values stored by hash, so these cannot collide with anything a program stores.
+const COUNTER_TAG: u8 = 2;
+
+/// Gives a program being run on behalf of a neighbor access to this agent.
+///
+/// Requests that need the agent itself, rather than just its environment, are queued here and
+/// carried out by the agent once evaluation has finished.
+pub struct AgentSideEffectEngine<'a, E: 'a> {
+    environment: &'a mut E,
+    ledger: &'a mut Ledger,
+    requestor: Identity,
+    
+    /// The requestor's allowance, as given by the ledger when evaluation started. Everything the
+    /// program does is paid for out of it, including as the identities it executes as.
+    requestor_ticks: u64,
+    own_identity: &'a Identity,
+    neighbors: &'a HashMap<Identity, NeighborState>,
+    routing_table: &'a RoutingTable,
+    secret: &'a [u8; 32],
+    pub neighboring_requests: Vec<(Identity, IpAddressPort)>,
+    pub outgoing: Vec<(Identity, Vec<u8>)>,
+}
+
+impl<'a, E: AgentEnvironment + Rng> AgentSideEffectEngine<'a, E> {
+    pub fn new(
+        environment: &'a mut E,
+        ledger: &'a mut Ledger,
+        requestor: &Identity,
+        own_identity: &'a Identity,
+        neighbors: &'a HashMap<Identity, NeighborState>,
+        routing_table: &'a RoutingTable,
+        secret: &'a [u8; 32]
+    ) -> AgentSideEffectEngine<'a, E> {
+        let now = environment.get_current_timestamp();
+        let requestor_ticks = ledger.ticks_for(requestor, now);
+        AgentSideEffectEngine{
+            environment: environment,
+            ledger: ledger,
+            requestor: *requestor,
+            requestor_ticks: requestor_ticks,
+            own_identity: own_identity,
+            neighbors: neighbors,
+            routing_table: routing_table,
+            secret: secret,
+            neighboring_requests: Vec::new(),
+            outgoing: Vec::new(),
+        }
+    }
+    
+    pub fn requestor_ticks(&self) -> u64 {
+        self.requestor_ticks
+    }
+}
+
+impl<'a, E: AgentEnvironment + Rng> SideEffectEngine for AgentSideEffectEngine<'a, E> {
+    fn nearest_neighbors(&mut self, near: &[u8; 32], count: usize) -> Vec<[u8; 32]> {
+        self.routing_table.closest(&Identity::from_bytes(near), count).iter().map(|contact| contact.identity.as_bytes()).collect()
+    }
+
+    fn random(&mut self, dest: &mut [u8]) {
+        self.environment.fill_bytes(dest);
+    }
+
+    fn load(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.environment.load(key)
+    }
+
+    fn store(&mut self, key: &[u8], value: &[u8]) {
+        // Overwriting a value only holds as many more bytes as the new one is longer. Shrinking
+        // one earns nothing back, since the old value may not have been the requestor's.
+        let previous_len = self.environment.load(key).map(|previous| previous.len()).unwrap_or(0);
+        let now = self.environment.get_current_timestamp();
+        self.ledger.record_storage_held(&self.requestor, value.len().saturating_sub(previous_len) as u64, now);
+        self.environment.store(key, value);
+    }
+
+    fn send(&mut self, destination: &[u8; 32], message: &[u8], _local_cost: u64) -> bool {
+        // The evaluator has already taken `local_cost` out of the requestor's ticks.
+        let destination = Identity::from_bytes(destination);
+        let deliverable = self.neighbors.get(&destination).map(|neighbor_state| {
+            neighbor_state.streams.is_ready()
+        }).unwrap_or(false);
+        
+        if deliverable {
+            self.outgoing.push((destination, message.to_vec()));
+        }
+        deliverable
+    }
+
+    fn secret(&self) -> &[u8; 32] {
+        self.secret
+    }
+
+    fn ticks_for(&self, _executor: &[u8; 32]) -> Ticks {
+        Ticks::new(self.requestor_ticks)
+    }
+
+    fn consume_counter(&mut self, counter: &[u8; 8], public_key: &[u8; 32]) -> bool {
+        let mut key = public_key.to_vec();
+        key.extend_from_slice(&counter[..]);
+        key.push(COUNTER_TAG);
+
+        if self.environment.load(&key[..]).is_some() {
+            return false;
+        }
+        self.environment.store(&key[..], &[]);
+        true
+    }
+
+    fn start_neighboring(&mut self, identity: &[u8; 32], address: &[u8; 16], port: u16) -> bool {
+        let identity = Identity::from_bytes(identity);
+        if identity == *self.own_identity
+            || self.neighbors.contains_key(&identity)
+            || self.neighboring_requests.iter().any(|&(requested, _)| requested == identity) {
+            return false;
+        }
+
+        self.neighboring_requests.push((identity, IpAddressPort{ address: *address, port: port }));
+        true
+    }
+}
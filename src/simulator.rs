@@ -0,0 +1,476 @@
+//! A deterministic, discrete-event simulation of many agents sharing a network.
+//!
+//! Time is virtual and advances only as events are processed, so a run depends on nothing but
+//! its seed. Datagrams can be lost, duplicated, delayed and reordered, and agents can be
+//! partitioned from one another.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::net::{IpAddr, Ipv4Addr};
+use rand::chacha::ChaChaRng;
+use rand::{Rng, SeedableRng};
+use agent::{Agent, Task};
+use ip_address_port::IpAddressPort;
+use testing::MemoryEnvironment;
+
+/// Agents see the virtual clock, which starts at zero, as seconds since this timestamp.
+pub const SIMULATED_EPOCH: u64 = 1_500_000_000;
+
+/// Default for `Simulator::upkeep_interval`, in milliseconds.
+pub const DEFAULT_UPKEEP_INTERVAL: u64 = 1000;
+
+/// How the simulated network treats each datagram. Latencies are in milliseconds.
+#[derive(Debug, Copy, Clone)]
+pub struct NetworkConditions {
+    /// A datagram takes between these to arrive, chosen uniformly. When they differ, datagrams
+    /// can overtake each other.
+    pub min_latency: u64,
+    pub max_latency: u64,
+
+    /// Probability that a datagram is lost.
+    pub loss: f64,
+
+    /// Probability that a datagram is delivered twice, each copy with its own latency.
+    pub duplication: f64,
+}
+
+impl Default for NetworkConditions {
+    /// A perfect network, with a fixed latency.
+    fn default() -> NetworkConditions {
+        NetworkConditions{
+            min_latency: 10,
+            max_latency: 10,
+            loss: 0.0,
+            duplication: 0.0,
+        }
+    }
+}
+
+/// Counts of what happened to the datagrams agents sent.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct NetworkStats {
+    pub sent: u64,
+    pub delivered: u64,
+    pub lost: u64,
+    pub duplicated: u64,
+
+    /// Dropped because the sender and receiver were partitioned when it arrived.
+    pub partitioned: u64,
+
+    /// Sent to an address no agent has.
+    pub undeliverable: u64,
+}
+
+/// A datagram in flight.
+struct Delivery {
+    at: u64,
+
+    /// Breaks ties between deliveries due at the same time, in the order they were scheduled.
+    order: u64,
+    source: IpAddressPort,
+    from: usize,
+    to: usize,
+    packet: Vec<u8>,
+}
+
+impl PartialEq for Delivery {
+    fn eq(&self, other: &Delivery) -> bool {
+        (self.at, self.order) == (other.at, other.order)
+    }
+}
+
+impl Eq for Delivery {}
+
+impl PartialOrd for Delivery {
+    fn partial_cmp(&self, other: &Delivery) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delivery {
+    /// Reversed, so that the `BinaryHeap` gives the earliest first.
+    fn cmp(&self, other: &Delivery) -> Ordering {
+        (other.at, other.order).cmp(&(self.at, self.order))
+    }
+}
+
+/// Called with each agent's index and each task it is handed, as it is handed it.
+pub type TaskHook = Box<dyn FnMut(usize, &Task)>;
+
+/// Many agents on one simulated network. Agents are referred to by the order they were added in.
+pub struct Simulator {
+    rng: ChaChaRng,
+    agents: Vec<Agent<MemoryEnvironment>>,
+    addresses: HashMap<IpAddressPort, usize>,
+
+    /// Agents can only reach others in the same group.
+    groups: Vec<u32>,
+    next_group: u32,
+
+    in_flight: BinaryHeap<Delivery>,
+    next_order: u64,
+
+    /// Agents that may have sent datagrams or been handed tasks since we last looked.
+    touched: Vec<usize>,
+
+    /// How many of each agent's tasks have been passed to the task hook.
+    reported_tasks: Vec<usize>,
+
+    /// Virtual time, in milliseconds.
+    now: u64,
+    next_upkeep: u64,
+
+    pub conditions: NetworkConditions,
+
+    /// How often each agent rekeys, retransmits, continues its lookups, and expires its
+    /// requests, in milliseconds.
+    pub upkeep_interval: u64,
+    pub stats: NetworkStats,
+    task_hook: Option<TaskHook>,
+}
+
+impl Simulator {
+    pub fn new(seed: u32, conditions: NetworkConditions) -> Simulator {
+        Simulator{
+            rng: ChaChaRng::from_seed(&[seed]),
+            agents: Vec::new(),
+            addresses: HashMap::new(),
+            groups: Vec::new(),
+            next_group: 1,
+            in_flight: BinaryHeap::new(),
+            next_order: 0,
+            touched: Vec::new(),
+            reported_tasks: Vec::new(),
+            now: 0,
+            next_upkeep: DEFAULT_UPKEEP_INTERVAL,
+            conditions: conditions,
+            upkeep_interval: DEFAULT_UPKEEP_INTERVAL,
+            stats: NetworkStats::default(),
+            task_hook: None,
+        }
+    }
+
+    /// Add an agent with an identity and address of its own, returning its index.
+    pub fn add_agent(&mut self) -> usize {
+        let index = self.agents.len();
+        let octets = [10, (index >> 16) as u8, (index >> 8) as u8, index as u8];
+        let location = IpAddressPort::new(IpAddr::V4(Ipv4Addr::from(octets)), 5000 + (index >> 24) as u16);
+
+        let mut identity_seed = [0u8; 32];
+        self.rng.fill_bytes(&mut identity_seed[..]);
+        let mut environment = MemoryEnvironment::new(self.rng.next_u32(), location);
+        environment.now = self.timestamp();
+
+        self.agents.push(Agent::new(&identity_seed, environment));
+        self.addresses.insert(location, index);
+        self.groups.push(0);
+        self.reported_tasks.push(0);
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.agents.len()
+    }
+
+    pub fn agent(&self, index: usize) -> &Agent<MemoryEnvironment> {
+        &self.agents[index]
+    }
+
+    /// The agent at `index`, to be acted on directly. Whatever it sends goes out when the
+    /// simulation next runs.
+    pub fn agent_mut(&mut self, index: usize) -> &mut Agent<MemoryEnvironment> {
+        self.agents[index].environment.now = self.timestamp();
+        self.touched.push(index);
+        &mut self.agents[index]
+    }
+
+    /// Have `from` start streaming with `to`.
+    pub fn connect(&mut self, from: usize, to: usize) {
+        let (identity, location) = (self.agents[to].identity(), self.agents[to].environment.location);
+        self.agent_mut(from).initiate_stream_with(&identity, &location).ok().expect("initiate_stream_with failed");
+    }
+
+    /// Take the tasks `index` has been handed and not yet run.
+    pub fn take_tasks(&mut self, index: usize) -> Vec<Task> {
+        self.reported_tasks[index] = 0;
+        ::std::mem::replace(&mut self.agents[index].environment.tasks, Vec::new())
+    }
+
+    pub fn set_task_hook(&mut self, hook: TaskHook) {
+        self.task_hook = Some(hook);
+    }
+
+    /// Virtual time, in milliseconds.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// What agents' clocks read at the current virtual time.
+    fn timestamp(&self) -> u64 {
+        SIMULATED_EPOCH + self.now / 1000
+    }
+
+    /// Cut `agents` off from everyone else, including any they were partitioned with before.
+    pub fn partition(&mut self, agents: &[usize]) {
+        let group = self.next_group;
+        self.next_group += 1;
+        for index in agents.iter() {
+            self.groups[*index] = group;
+        }
+    }
+
+    /// Undo every partition.
+    pub fn heal(&mut self) {
+        for group in self.groups.iter_mut() {
+            *group = 0;
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Put what the touched agents sent on the network, and report the tasks they were handed.
+    fn collect(&mut self) {
+        let mut touched = ::std::mem::replace(&mut self.touched, Vec::new());
+        touched.sort();
+        touched.dedup();
+        for index in touched.into_iter() {
+            let outgoing = ::std::mem::replace(&mut self.agents[index].environment.outgoing, Vec::new());
+            let source = self.agents[index].environment.location;
+            for (destination, packet) in outgoing.into_iter() {
+                self.transmit(index, source, destination, packet);
+            }
+
+            let tasks = &self.agents[index].environment.tasks;
+            if let Some(ref mut hook) = self.task_hook {
+                for task in tasks.iter().skip(self.reported_tasks[index]) {
+                    hook(index, task);
+                }
+            }
+            self.reported_tasks[index] = tasks.len();
+        }
+    }
+
+    fn transmit(&mut self, from: usize, source: IpAddressPort, destination: IpAddressPort, packet: Vec<u8>) {
+        self.stats.sent += 1;
+        let to = if let Some(to) = self.addresses.get(&destination) { *to } else {
+            self.stats.undeliverable += 1;
+            return;
+        };
+        if self.rng.gen::<f64>() < self.conditions.loss {
+            self.stats.lost += 1;
+            return;
+        }
+
+        let copies = if self.rng.gen::<f64>() < self.conditions.duplication {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let latency = self.conditions.min_latency + self.rng.gen_range(0, self.conditions.max_latency.saturating_sub(self.conditions.min_latency) + 1);
+            self.in_flight.push(Delivery{
+                at: self.now + latency,
+                order: self.next_order,
+                source: source,
+                from: from,
+                to: to,
+                packet: packet.clone(),
+            });
+            self.next_order += 1;
+        }
+    }
+
+    fn deliver(&mut self, delivery: Delivery) {
+        if self.groups[delivery.from] != self.groups[delivery.to] {
+            self.stats.partitioned += 1;
+            return;
+        }
+        self.stats.delivered += 1;
+        self.agent_mut(delivery.to).handle_packet(&delivery.source, &delivery.packet[..]);
+        self.collect();
+    }
+
+    fn upkeep(&mut self) {
+        for index in 0..self.agents.len() {
+            let agent = self.agent_mut(index);
+            agent.rekey_due_neighbors();
+            agent.retransmit_unacknowledged();
+            agent.continue_lookups();
+            agent.expire_requests();
+        }
+        self.collect();
+    }
+
+    /// Process every event due up to and including `deadline`, then leave the clock there.
+    pub fn run_until(&mut self, deadline: u64) {
+        self.collect();
+        loop {
+            let next_delivery = self.in_flight.peek().map(|delivery| delivery.at);
+            if self.next_upkeep <= deadline && next_delivery.map(|at| self.next_upkeep <= at).unwrap_or(true) {
+                self.now = self.next_upkeep;
+                self.next_upkeep += self.upkeep_interval;
+                self.upkeep();
+                continue;
+            }
+            match next_delivery {
+                Some(at) if at <= deadline => {
+                    self.now = at;
+                    let delivery = self.in_flight.pop().unwrap();
+                    self.deliver(delivery);
+                }
+                _ => { break; }
+            }
+        }
+        self.now = deadline;
+    }
+
+    pub fn run_for(&mut self, duration: u64) {
+        let deadline = self.now + duration;
+        self.run_until(deadline);
+    }
+
+    /// Run until no datagrams are in flight, giving up at `deadline`. Returns whether the
+    /// network went quiet in time.
+    pub fn run_until_quiet(&mut self, deadline: u64) -> bool {
+        self.collect();
+        while let Some(at) = self.in_flight.peek().map(|delivery| delivery.at) {
+            if at > deadline {
+                self.run_until(deadline);
+                return false;
+            }
+            self.run_until(at);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{NetworkConditions, NetworkStats, Simulator};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use vm::{opcode, AsNoun, Noun};
+
+    fn lossy() -> NetworkConditions {
+        NetworkConditions{
+            min_latency: 5,
+            max_latency: 80,
+            loss: 0.2,
+            duplication: 0.1,
+        }
+    }
+
+    /// Connect two agents over a lossy network and send reliably between them, returning what
+    /// was delivered and what the network did.
+    fn lossy_run(seed: u32) -> (Vec<Noun>, NetworkStats) {
+        let mut simulator = Simulator::new(seed, NetworkConditions::default());
+        let (a, b) = (simulator.add_agent(), simulator.add_agent());
+        simulator.connect(a, b);
+        assert!(simulator.run_until_quiet(1000));
+        simulator.conditions = lossy();
+
+        let b_identity = simulator.agent(b).identity();
+        for idx in 0..20 {
+            let program = (idx, opcode::LITERAL, idx).as_noun();
+            simulator.agent_mut(a).send_program_reliably(&b_identity, &program).ok().expect("send_program_reliably failed");
+        }
+        simulator.run_for(60_000);
+        assert_eq!(simulator.agent(a).environment.acknowledged.len(), 20);
+
+        let delivered = simulator.take_tasks(b).into_iter().map(|task| task.program).collect();
+        (delivered, simulator.stats)
+    }
+
+    #[test]
+    fn reliable_over_lossy_network() {
+        let (delivered, stats) = lossy_run(7);
+        let expected: Vec<Noun> = (0..20).map(|idx| (idx, opcode::LITERAL, idx).as_noun()).collect();
+        assert_eq!(delivered, expected);
+        assert!(stats.lost > 0);
+        assert!(stats.duplicated > 0);
+
+        // The same seed gives the same run.
+        assert_eq!(lossy_run(7).1, stats);
+        assert!(lossy_run(8).1 != stats);
+    }
+
+    #[test]
+    fn partitions() {
+        let mut simulator = Simulator::new(3, NetworkConditions::default());
+        let (a, b) = (simulator.add_agent(), simulator.add_agent());
+        simulator.connect(a, b);
+        assert!(simulator.run_until_quiet(10_000));
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let hook_seen = seen.clone();
+        simulator.set_task_hook(Box::new(move |index, task| hook_seen.borrow_mut().push((index, task.program.clone()))));
+
+        simulator.partition(&[b]);
+        let b_identity = simulator.agent(b).identity();
+        let program = (1, opcode::LITERAL, 2).as_noun();
+        simulator.agent_mut(a).send_program_reliably(&b_identity, &program).ok().expect("send_program_reliably failed");
+        simulator.run_for(10_000);
+        assert!(seen.borrow().is_empty());
+        assert!(simulator.stats.partitioned > 1);
+
+        // Retransmission gets it through once the partition heals.
+        simulator.heal();
+        simulator.run_for(5_000);
+        assert_eq!(*seen.borrow(), vec![(b, program)]);
+        assert_eq!(simulator.agent(a).environment.acknowledged, vec![(b_identity, 0)]);
+    }
+
+    /// Connect `count` agents in a ring, then have each send programs to the next over a lossy
+    /// network, checking they all arrive in order.
+    fn ring(seed: u32, count: usize) {
+        let mut simulator = Simulator::new(seed, NetworkConditions{ min_latency: 1, max_latency: 50, .. NetworkConditions::default() });
+        for _ in 0..count {
+            simulator.add_agent();
+        }
+        for index in 0..count {
+            simulator.connect(index, (index + 1) % count);
+        }
+        assert!(simulator.run_until_quiet(10_000));
+        for index in 0..count {
+            let next = simulator.agent((index + 1) % count).identity();
+            assert!(simulator.agent(index).is_neighbor(&next));
+            assert_eq!(simulator.agent(index).routing_table.len(), 2);
+        }
+
+        // Everyone sends a few programs around the ring over a lossy network.
+        simulator.conditions = lossy();
+        let received = Rc::new(RefCell::new(vec![Vec::new(); count]));
+        let hook_received = received.clone();
+        simulator.set_task_hook(Box::new(move |index, task| hook_received.borrow_mut()[index].push(task.program.clone())));
+        for index in 0..count {
+            let next = simulator.agent((index + 1) % count).identity();
+            for round in 0..3 {
+                let program = (Noun::from_u64_compact(index as u64), opcode::LITERAL, round).as_noun();
+                simulator.agent_mut(index).send_program_reliably(&next, &program).ok().expect("send_program_reliably failed");
+            }
+        }
+        simulator.run_for(60_000);
+
+        for (index, programs) in received.borrow().iter().enumerate() {
+            let previous = (index + count - 1) % count;
+            let expected: Vec<Noun> = (0..3).map(|round| (Noun::from_u64_compact(previous as u64), opcode::LITERAL, round).as_noun()).collect();
+            assert_eq!(*programs, expected);
+        }
+        assert!(simulator.stats.lost > 0);
+    }
+
+    #[test]
+    fn many_agents() {
+        ring(11, 200);
+    }
+
+    /// Takes most of a minute in a debug build, so run it with `cargo test --release -- --ignored`.
+    #[test]
+    #[ignore]
+    fn thousands_of_agents() {
+        ring(12, 2000);
+    }
+}
@@ -0,0 +1,147 @@
+use std::fmt;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use rand::Rng;
+
+const MAGIC: &'static [u8; 8] = b"hoplsnap";
+pub const VERSION: u8 = 1;
+
+const HEADER_LEN: usize = 8 + 1 + 8;
+const TAG_LEN: usize = 16;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SnapshotError {
+    /// Not a snapshot, or one that does not decode.
+    Malformed,
+    UnsupportedVersion(u8),
+
+    /// Made by some other identity, or altered since.
+    NotAuthentic,
+
+    /// Not the latest snapshot, or already imported.
+    Stale,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SnapshotError::Malformed => f.write_str("not an agent snapshot"),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "unsupported snapshot version {}", version),
+            SnapshotError::NotAuthentic => f.write_str("snapshot was made by another identity, or has been altered"),
+            SnapshotError::Stale => f.write_str("snapshot has been superseded or already imported"),
+        }
+    }
+}
+
+/// Encrypt `plaintext` under `key`. The header, which carries a random nonce, is authenticated
+/// along with it.
+pub fn seal<R: Rng>(key: &[u8; 32], plaintext: &[u8], rng: &mut R) -> Vec<u8> {
+    let mut sealed = vec![0u8; HEADER_LEN + plaintext.len() + TAG_LEN];
+    {
+        let (header, rest) = sealed.split_at_mut(HEADER_LEN);
+        header[..8].copy_from_slice(&MAGIC[..]);
+        header[8] = VERSION;
+        rng.fill_bytes(&mut header[9..]);
+
+        let (encrypted, tag) = rest.split_at_mut(plaintext.len());
+        ChaCha20Poly1305::new(&key[..], &header[9..], &header[..]).encrypt(plaintext, encrypted, tag);
+    }
+    sealed
+}
+
+/// Reverse `seal`.
+pub fn unseal(key: &[u8; 32], sealed: &[u8]) -> Result<Vec<u8>, SnapshotError> {
+    if sealed.len() < 9 || sealed[..8] != MAGIC[..] {
+        return Err(SnapshotError::Malformed);
+    }
+    if sealed[8] != VERSION {
+        return Err(SnapshotError::UnsupportedVersion(sealed[8]));
+    }
+    if sealed.len() < HEADER_LEN + TAG_LEN {
+        return Err(SnapshotError::Malformed);
+    }
+
+    let (header, rest) = sealed.split_at(HEADER_LEN);
+    let (encrypted, tag) = rest.split_at(rest.len() - TAG_LEN);
+    let mut plaintext = vec![0u8; encrypted.len()];
+    if !ChaCha20Poly1305::new(&key[..], &header[9..], &header[..]).decrypt(encrypted, &mut plaintext[..], tag) {
+        return Err(SnapshotError::NotAuthentic);
+    }
+    Ok(plaintext)
+}
+
+pub fn write_u64(out: &mut Vec<u8>, x: u64) {
+    out.write_u64::<LittleEndian>(x).unwrap();
+}
+
+pub fn write_option(out: &mut Vec<u8>, bs: &Option<[u8; 32]>) {
+    match *bs {
+        Some(ref bs) => {
+            out.push(1);
+            out.extend_from_slice(&bs[..]);
+        }
+        None => out.push(0),
+    }
+}
+
+pub fn read_u8(bs: &mut &[u8]) -> Result<u8, SnapshotError> {
+    bs.read_u8().map_err(|_| SnapshotError::Malformed)
+}
+
+pub fn read_u64(bs: &mut &[u8]) -> Result<u64, SnapshotError> {
+    bs.read_u64::<LittleEndian>().map_err(|_| SnapshotError::Malformed)
+}
+
+pub fn read_bool(bs: &mut &[u8]) -> Result<bool, SnapshotError> {
+    match try!(read_u8(bs)) {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(SnapshotError::Malformed),
+    }
+}
+
+/// Take `len` bytes off the front of `bs`.
+pub fn read_slice<'a>(bs: &mut &'a [u8], len: usize) -> Result<&'a [u8], SnapshotError> {
+    if bs.len() < len {
+        return Err(SnapshotError::Malformed);
+    }
+    let (taken, rest) = bs.split_at(len);
+    *bs = rest;
+    Ok(taken)
+}
+
+pub fn read_32(bs: &mut &[u8]) -> Result<[u8; 32], SnapshotError> {
+    let mut read = [0u8; 32];
+    read.copy_from_slice(try!(read_slice(bs, 32)));
+    Ok(read)
+}
+
+pub fn read_option(bs: &mut &[u8]) -> Result<Option<[u8; 32]>, SnapshotError> {
+    if try!(read_bool(bs)) {
+        Ok(Some(try!(read_32(bs))))
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{seal, unseal, SnapshotError};
+    use rand::{SeedableRng, XorShiftRng};
+
+    #[test]
+    fn seal_round_trip() {
+        let mut rng = XorShiftRng::from_seed([5, 6, 7, 8]);
+        let sealed = seal(&[3; 32], b"neighbors", &mut rng);
+        assert_eq!(unseal(&[3; 32], &sealed[..]), Ok(b"neighbors".to_vec()));
+        assert_eq!(unseal(&[4; 32], &sealed[..]), Err(SnapshotError::NotAuthentic));
+
+        let mut altered = sealed.clone();
+        altered[10] ^= 1;
+        assert_eq!(unseal(&[3; 32], &altered[..]), Err(SnapshotError::NotAuthentic));
+        altered[8] = 9;
+        assert_eq!(unseal(&[3; 32], &altered[..]), Err(SnapshotError::UnsupportedVersion(9)));
+        assert_eq!(unseal(&[3; 32], &sealed[..20]), Err(SnapshotError::Malformed));
+    }
+}
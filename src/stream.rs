@@ -9,6 +9,14 @@ use crypto::aead::{AeadDecryptor};
 use agent::HandleError;
 use identity::Identity;
 use expected_packet_set::{ExpectedPacket, ExpectedPacketSet};
+use snapshot::{self, SnapshotError};
+
+/// Overwrite key material that is no longer needed, in a way the compiler will not optimize out.
+pub fn wipe(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        unsafe { ::std::ptr::write_volatile(byte, 0) };
+    }
+}
 
 pub enum Direction {
     Incoming,
@@ -17,6 +25,11 @@ pub enum Direction {
 
 pub struct Stream{
     pub key: [u8; 32],
+    
+    /// Distinguishes this stream from the others with the same neighbor, so that expected
+    /// packets can refer to it without copying the key.
+    pub id: u64,
+    
     pub neighbor_is_lexico_later: bool,
     
     // It happens to be efficient to generate 8 message identifiers at a time, so we store 
@@ -30,17 +43,24 @@ pub struct Stream{
 }
 
 impl Stream {
-    pub fn maybe_new(own_seed: &Option<[u8; 32]>, neighbor_key_material: &Option<[u8; 32]>, stream_with: &Identity, neighbor_is_lexico_later: bool, upcoming_packets: &mut ExpectedPacketSet) -> Option<Stream> {
+    fn with_key_material(id: u64, own_seed: &[u8; 32], neighbor_key_material: &[u8; 32], neighbor_is_lexico_later: bool) -> Stream {
+        let (mut stream_private, _stream_public) = ed25519::keypair(&own_seed[..]);
+        let stream = Stream {
+            key: ed25519::exchange(&neighbor_key_material[..], &stream_private[..]),
+            id: id,
+            outgoing_message_identifiers: [0u64; 8],
+            outgoing_message_index: 0,
+            neighbor_is_lexico_later: neighbor_is_lexico_later,
+            incoming_message_mask_start: 0,
+            incoming_message_mask: 0xffff_ffff_ffff_ffff,
+        };
+        wipe(&mut stream_private[..]);
+        stream
+    }
+
+    pub fn maybe_new(id: u64, own_seed: &Option<[u8; 32]>, neighbor_key_material: &Option<[u8; 32]>, stream_with: &Identity, neighbor_is_lexico_later: bool, upcoming_packets: &mut ExpectedPacketSet) -> Option<Stream> {
         if let (Some(ref own_seed), Some(ref neighbor_key_material)) = (*own_seed, *neighbor_key_material) {
-            let (stream_private, _stream_public) = ed25519::keypair(&own_seed[..]);
-            let stream = Stream {
-                key: ed25519::exchange(&neighbor_key_material[..], &stream_private[..]),
-                outgoing_message_identifiers: [0u64; 8],
-                outgoing_message_index: 0,
-                neighbor_is_lexico_later: neighbor_is_lexico_later,
-                incoming_message_mask_start: 0,
-                incoming_message_mask: 0xffff_ffff_ffff_ffff,
-            };
+            let stream = Stream::with_key_material(id, own_seed, neighbor_key_material, neighbor_is_lexico_later);
             
             let mut some_identifiers = [0u64; 64];
             stream.generate_identifiers(Direction::Incoming, 0, &mut some_identifiers);
@@ -51,7 +71,7 @@ impl Stream {
                 upcoming_packets.add(
                     ExpectedPacket{
                         stream_with: *stream_with,
-                        stream_key: stream.key,
+                        stream: stream.id,
                         packet_number: idx as u64,
                     },
                     *identifier
@@ -64,6 +84,53 @@ impl Stream {
         }
     }
 
+    /// Rebuild a stream written by `export`, expecting the packets it was still expecting.
+    fn import(id: u64, own_seed: &[u8; 32], neighbor_key_material: &[u8; 32], neighbor_is_lexico_later: bool, stream_with: &Identity, bs: &mut &[u8], upcoming_packets: &mut ExpectedPacketSet) -> Result<Stream, SnapshotError> {
+        let mut stream = Stream::with_key_material(id, own_seed, neighbor_key_material, neighbor_is_lexico_later);
+        stream.outgoing_message_index = try!(snapshot::read_u64(bs));
+        stream.incoming_message_mask_start = try!(snapshot::read_u64(bs));
+        stream.incoming_message_mask = try!(snapshot::read_u64(bs));
+
+        let batch_start = stream.outgoing_message_index - stream.outgoing_message_index % 8;
+        let mut identifiers_temp = [0; 8];
+        stream.generate_identifiers(Direction::Outgoing, batch_start, &mut identifiers_temp);
+        stream.outgoing_message_identifiers = identifiers_temp;
+
+        let mut identifiers = [0u64; 64];
+        stream.generate_identifiers(Direction::Incoming, stream.incoming_message_mask_start, &mut identifiers);
+        for (idx, identifier) in identifiers.iter().enumerate() {
+            if stream.incoming_message_mask & (1u64 << idx) != 0 {
+                upcoming_packets.add(ExpectedPacket{
+                    stream_with: *stream_with,
+                    stream: stream.id,
+                    packet_number: stream.incoming_message_mask_start + (idx as u64),
+                }, *identifier);
+            }
+        }
+        Ok(stream)
+    }
+
+    /// Write the counters that, with the seed and key material it was made from, describe this stream.
+    fn export(&self, out: &mut Vec<u8>) {
+        snapshot::write_u64(out, self.outgoing_message_index);
+        snapshot::write_u64(out, self.incoming_message_mask_start);
+        snapshot::write_u64(out, self.incoming_message_mask);
+    }
+
+    /// Stop expecting packets on this stream. Everything `upcoming_packets` holds for it lies
+    /// within the 64 packet numbers starting at `incoming_message_mask_start`.
+    pub fn retire(&self, stream_with: &Identity, upcoming_packets: &mut ExpectedPacketSet) {
+        let mut identifiers = [0u64; 64];
+        self.generate_identifiers(Direction::Incoming, self.incoming_message_mask_start, &mut identifiers);
+        for (idx, identifier) in identifiers.iter().enumerate() {
+            upcoming_packets.remove(&ExpectedPacket{
+                stream_with: *stream_with,
+                stream: self.id,
+                packet_number: self.incoming_message_mask_start + (idx as u64),
+            }, *identifier);
+        }
+    }
+
     pub fn generate_identifiers(
         &self,
         direction: Direction,
@@ -151,7 +218,7 @@ impl Stream {
             for (idx, incoming_identifier) in incoming_identifiers.iter().enumerate() {
                 expected_packet_set.add(ExpectedPacket{
                     stream_with: packet.stream_with,
-                    stream_key: self.key,
+                    stream: self.id,
                     packet_number: self.incoming_message_mask_start + 64 + (idx as u64),
                 }, *incoming_identifier);
             }
@@ -164,7 +231,7 @@ impl Stream {
                 for (idx, abandoned_incoming_identifier) in abandoned_incoming_identifiers.iter().enumerate() {
                     expected_packet_set.remove(&ExpectedPacket{
                         stream_with: packet.stream_with,
-                        stream_key: self.key,
+                        stream: self.id,
                         packet_number: self.incoming_message_mask_start + (idx as u64),
                     }, *abandoned_incoming_identifier);
                 }
@@ -177,6 +244,11 @@ impl Stream {
 
 }
 
+impl Drop for Stream {
+    fn drop(&mut self) {
+        wipe(&mut self.key);
+    }
+}
 
 pub struct StreamCluster {
     neighbor: Identity,
@@ -212,6 +284,9 @@ pub struct StreamCluster {
     own_current_neighbor_previous: Option<Stream>,
     own_previous_neighbor_current: Option<Stream>,
     own_previous_neighbor_previous: Option<Stream>,
+    
+    /// The id the next stream made will get.
+    next_stream_id: u64,
 }
 
 impl StreamCluster {
@@ -230,29 +305,115 @@ impl StreamCluster {
             own_current_neighbor_previous: None,
             own_previous_neighbor_current: None,
             own_previous_neighbor_previous: None,
+            
+            next_stream_id: 0,
+        }
+    }
+    
+    fn take_stream_id(&mut self) -> u64 {
+        self.next_stream_id += 1;
+        self.next_stream_id - 1
+    }
+    
+    fn retire(&self, stream: Option<Stream>, upcoming_packets: &mut ExpectedPacketSet) {
+        if let Some(stream) = stream {
+            stream.retire(&self.neighbor, upcoming_packets);
         }
     }
     
     pub fn push_own_seed(&mut self, seed: &[u8; 32], upcoming_packets: &mut ExpectedPacketSet) {
+        if let Some(ref mut forgotten) = self.own_previous_seed {
+            wipe(forgotten);
+        }
         self.own_previous_seed = self.own_current_seed.take();
         self.own_current_seed = Some(*seed);
+        self.own_current_acknowledged = false;
         
-        self.own_previous_neighbor_current = self.own_current_neighbor_current.take();
-        self.own_previous_neighbor_previous = self.own_current_neighbor_previous.take();
+        let replaced_current = ::std::mem::replace(&mut self.own_previous_neighbor_current, self.own_current_neighbor_current.take());
+        let replaced_previous = ::std::mem::replace(&mut self.own_previous_neighbor_previous, self.own_current_neighbor_previous.take());
+        self.retire(replaced_current, upcoming_packets);
+        self.retire(replaced_previous, upcoming_packets);
         
-        self.own_current_neighbor_current = Stream::maybe_new(&self.own_current_seed, &self.neighbor_current_key_material, &self.neighbor, self.neighbor_is_lexico_later, upcoming_packets);
-        self.own_current_neighbor_previous = Stream::maybe_new(&self.own_current_seed, &self.neighbor_previous_key_material, &self.neighbor, self.neighbor_is_lexico_later, upcoming_packets);
+        let (current_id, previous_id) = (self.take_stream_id(), self.take_stream_id());
+        self.own_current_neighbor_current = Stream::maybe_new(current_id, &self.own_current_seed, &self.neighbor_current_key_material, &self.neighbor, self.neighbor_is_lexico_later, upcoming_packets);
+        self.own_current_neighbor_previous = Stream::maybe_new(previous_id, &self.own_current_seed, &self.neighbor_previous_key_material, &self.neighbor, self.neighbor_is_lexico_later, upcoming_packets);
     }
     
     pub fn push_neighbor_key_material(&mut self, neighbor_key_material: &[u8; 32], upcoming_packets: &mut ExpectedPacketSet) {
+        if self.neighbor_current_key_material == Some(*neighbor_key_material) {
+            // A retransmitted initiation packet. Rebuilding the streams would reuse their nonces.
+            return;
+        }
+        
         self.neighbor_previous_key_material = self.neighbor_current_key_material.take();
         self.neighbor_current_key_material = Some(*neighbor_key_material);
         
-        self.own_current_neighbor_previous = self.own_current_neighbor_current.take();
-        self.own_previous_neighbor_previous = self.own_previous_neighbor_current.take();
+        let replaced_own_current = ::std::mem::replace(&mut self.own_current_neighbor_previous, self.own_current_neighbor_current.take());
+        let replaced_own_previous = ::std::mem::replace(&mut self.own_previous_neighbor_previous, self.own_previous_neighbor_current.take());
+        self.retire(replaced_own_current, upcoming_packets);
+        self.retire(replaced_own_previous, upcoming_packets);
         
-        self.own_current_neighbor_current = Stream::maybe_new(&self.own_current_seed, &self.neighbor_current_key_material, &self.neighbor,  self.neighbor_is_lexico_later, upcoming_packets);
-        self.own_previous_neighbor_current = Stream::maybe_new(&self.own_previous_seed, &self.neighbor_current_key_material, &self.neighbor, self.neighbor_is_lexico_later, upcoming_packets);
+        let (current_id, previous_id) = (self.take_stream_id(), self.take_stream_id());
+        self.own_current_neighbor_current = Stream::maybe_new(current_id, &self.own_current_seed, &self.neighbor_current_key_material, &self.neighbor,  self.neighbor_is_lexico_later, upcoming_packets);
+        self.own_previous_neighbor_current = Stream::maybe_new(previous_id, &self.own_previous_seed, &self.neighbor_current_key_material, &self.neighbor, self.neighbor_is_lexico_later, upcoming_packets);
+    }
+    
+    /// Write everything needed to rebuild this cluster with `import`, seeds included.
+    pub fn export(&self, out: &mut Vec<u8>) {
+        out.push(self.own_current_acknowledged as u8);
+        snapshot::write_option(out, &self.own_current_seed);
+        snapshot::write_option(out, &self.own_previous_seed);
+        snapshot::write_option(out, &self.neighbor_current_key_material);
+        snapshot::write_option(out, &self.neighbor_previous_key_material);
+        for stream in [&self.own_current_neighbor_current, &self.own_current_neighbor_previous,
+                       &self.own_previous_neighbor_current, &self.own_previous_neighbor_previous].iter() {
+            if let Some(ref stream) = **stream {
+                stream.export(out);
+            }
+        }
+    }
+
+    /// Rebuild a cluster written by `export`, adding the packets its streams expect to `upcoming_packets`.
+    pub fn import(neighbor: &Identity, neighbor_is_lexico_later: bool, bs: &mut &[u8], upcoming_packets: &mut ExpectedPacketSet) -> Result<StreamCluster, SnapshotError> {
+        let mut cluster = StreamCluster::new(neighbor, neighbor_is_lexico_later);
+        cluster.own_current_acknowledged = try!(snapshot::read_bool(bs));
+        cluster.own_current_seed = try!(snapshot::read_option(bs));
+        cluster.own_previous_seed = try!(snapshot::read_option(bs));
+        cluster.neighbor_current_key_material = try!(snapshot::read_option(bs));
+        cluster.neighbor_previous_key_material = try!(snapshot::read_option(bs));
+
+        // Which streams exist follows from which seeds and key material do.
+        let pairs = [
+            (cluster.own_current_seed, cluster.neighbor_current_key_material),
+            (cluster.own_current_seed, cluster.neighbor_previous_key_material),
+            (cluster.own_previous_seed, cluster.neighbor_current_key_material),
+            (cluster.own_previous_seed, cluster.neighbor_previous_key_material),
+        ];
+        let mut streams = Vec::with_capacity(4);
+        for pair in pairs.iter() {
+            let id = cluster.take_stream_id();
+            streams.push(match *pair {
+                (Some(ref own_seed), Some(ref neighbor_key_material)) =>
+                    Some(try!(Stream::import(id, own_seed, neighbor_key_material, neighbor_is_lexico_later, neighbor, bs, upcoming_packets))),
+                _ => None,
+            });
+        }
+        let mut streams = streams.into_iter();
+        cluster.own_current_neighbor_current = streams.next().unwrap();
+        cluster.own_current_neighbor_previous = streams.next().unwrap();
+        cluster.own_previous_neighbor_current = streams.next().unwrap();
+        cluster.own_previous_neighbor_previous = streams.next().unwrap();
+        Ok(cluster)
+    }
+    
+    /// Our current seed, if the neighbor has not yet shown that it received it.
+    pub fn unacknowledged_seed(&self) -> Option<[u8; 32]> {
+        if self.own_current_acknowledged { None } else { self.own_current_seed }
+    }
+    
+    /// Whether `produce_outgoing_identifier` has a stream to use.
+    pub fn is_ready(&self) -> bool {
+        self.own_current_neighbor_current.is_some() || self.own_previous_neighbor_current.is_some()
     }
     
     pub fn produce_outgoing_identifier(&mut self) -> Result<(u64, ChaCha20Poly1305), HandleError> {
@@ -270,7 +431,7 @@ impl StreamCluster {
         }
     }
     
-    pub fn decrypt_incoming_payload(&mut self, stream_key: &[u8; 32], packet_number: u64, payload: &[u8], checksum: &[u8]) -> Result<Vec<u8>, HandleError> {
+    pub fn decrypt_incoming_payload(&mut self, stream_id: u64, packet_number: u64, payload: &[u8], checksum: &[u8]) -> Result<Vec<u8>, HandleError> {
         let mut streams = [
             &mut self.own_current_neighbor_current,
             &mut self.own_current_neighbor_previous,
@@ -279,7 +440,7 @@ impl StreamCluster {
         ];
         for ref mut stream in streams.iter_mut() {
             if let Some(stream) = stream.as_mut() {
-                if *stream_key == stream.key {
+                if stream_id == stream.id {
                     return stream.decrypt_incoming_payload(packet_number, payload, checksum);
                 }
             }
@@ -302,13 +463,96 @@ impl StreamCluster {
         
         for stream in streams.iter_mut() {
             if let Some(stream) = stream.as_mut() {
-                if stream.key == packet.stream_key {
+                if stream.id == packet.stream {
                     stream.got_incoming_packet(packet, upcoming)
                 }
             }
         }
         
         upcoming.remove(packet, packet_identifier);
+        
+        if !self.own_current_acknowledged {
+            let on_own_current = [&self.own_current_neighbor_current, &self.own_current_neighbor_previous].iter().any(|stream| {
+                stream.as_ref().map(|stream| stream.id == packet.stream).unwrap_or(false)
+            });
+            if on_own_current {
+                self.acknowledge_own_current(upcoming);
+            }
+        }
+    }
+    
+    /// The neighbor has used our current key material, so it has our latest initiation packet
+    /// and we can stop using the streams keyed by our previous seed.
+    fn acknowledge_own_current(&mut self, upcoming: &mut ExpectedPacketSet) {
+        self.own_current_acknowledged = true;
+        if let Some(ref mut forgotten) = self.own_previous_seed {
+            wipe(forgotten);
+        }
+        self.own_previous_seed = None;
+        
+        let retired_current = self.own_previous_neighbor_current.take();
+        let retired_previous = self.own_previous_neighbor_previous.take();
+        self.retire(retired_current, upcoming);
+        self.retire(retired_previous, upcoming);
     }
 }
 
+
+impl Drop for StreamCluster {
+    fn drop(&mut self) {
+        for seed in [&mut self.own_current_seed, &mut self.own_previous_seed].iter_mut() {
+            if let Some(ref mut seed) = **seed {
+                wipe(seed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::StreamCluster;
+    use crypto::ed25519;
+    use expected_packet_set::ExpectedPacketSet;
+    use identity::Identity;
+
+    fn key_material(seed: &[u8; 32]) -> [u8; 32] {
+        ed25519::keypair(&seed[..]).1
+    }
+
+    /// Have `from` send a packet and `to` take it in, returning the id of the stream it arrived on.
+    fn deliver(from: &mut StreamCluster, to: &mut StreamCluster, to_upcoming: &mut ExpectedPacketSet) -> u64 {
+        let (identifier, _) = from.produce_outgoing_identifier().ok().expect("no outgoing stream");
+        let expected = *to_upcoming.iter(identifier).next().expect("packet was not expected");
+        to.got_incoming_packet(&expected, identifier, to_upcoming);
+        expected.stream
+    }
+
+    #[test]
+    fn switches_after_acknowledgement() {
+        let a = Identity::from_bytes(&[1; 32]);
+        let b = Identity::from_bytes(&[2; 32]);
+        let (mut a_upcoming, mut b_upcoming) = (ExpectedPacketSet::new(), ExpectedPacketSet::new());
+        let mut a_streams = StreamCluster::new(&b, true);
+        let mut b_streams = StreamCluster::new(&a, false);
+
+        a_streams.push_own_seed(&[11; 32], &mut a_upcoming);
+        b_streams.push_neighbor_key_material(&key_material(&[11; 32]), &mut b_upcoming);
+        b_streams.push_own_seed(&[22; 32], &mut b_upcoming);
+        a_streams.push_neighbor_key_material(&key_material(&[22; 32]), &mut a_upcoming);
+        let old_stream = deliver(&mut a_streams, &mut b_streams, &mut b_upcoming);
+
+        // Until b shows it has the new key material, a keeps sending on the old stream.
+        a_streams.push_own_seed(&[33; 32], &mut a_upcoming);
+        assert_eq!(deliver(&mut a_streams, &mut b_streams, &mut b_upcoming), old_stream);
+        assert!(a_upcoming.count_for_stream(old_stream) > 0);
+
+        b_streams.push_neighbor_key_material(&key_material(&[33; 32]), &mut b_upcoming);
+        let new_stream = deliver(&mut b_streams, &mut a_streams, &mut a_upcoming);
+        assert!(new_stream != old_stream);
+        assert!(a_streams.own_current_acknowledged);
+        assert!(a_streams.own_previous_neighbor_current.is_none());
+        assert_eq!(a_upcoming.count_for_stream(old_stream), 0);
+
+        assert_eq!(deliver(&mut a_streams, &mut b_streams, &mut b_upcoming), new_stream);
+    }
+}
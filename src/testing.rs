@@ -0,0 +1,223 @@
+//! Helpers for testing agents against each other in memory, in lockstep rounds. Enabled by the
+//! `testing` feature.
+//!
+//! In each round of `exchange`, every datagram the agents have sent is handed to whichever agent
+//! has its destination address. For lossy networks and virtual time, see `simulator`.
+
+use std::borrow::BorrowMut;
+use std::collections::HashMap;
+use rand::chacha::ChaChaRng;
+use rand::{Rng, SeedableRng};
+use agent::{Agent, AgentEnvironment, RequestError, Task};
+use identity::Identity;
+use ip_address_port::IpAddressPort;
+use vm::Noun;
+
+/// Timestamp a `MemoryEnvironment` starts its clock at.
+pub const START_TIME: u64 = 123456;
+
+/// An `AgentEnvironment` that keeps everything in memory for a test to inspect and alter.
+/// Its clock only moves when `now` is changed, as `simulator` does along with virtual time.
+pub struct MemoryEnvironment {
+    rng: ChaChaRng,
+    pub location: IpAddressPort,
+
+    /// Datagrams sent and not yet delivered, by destination.
+    pub outgoing: Vec<(IpAddressPort, Vec<u8>)>,
+
+    /// Tasks handed to `execute`, oldest first.
+    pub tasks: Vec<Task>,
+    pub storage: HashMap<Vec<u8>, Vec<u8>>,
+    pub now: u64,
+
+    /// Every call to `acknowledged`, in order.
+    pub acknowledged: Vec<(Identity, u64)>,
+
+    /// Every call to `responded`, in order.
+    pub responses: Vec<(Identity, u64, Result<Noun, RequestError>)>,
+}
+
+impl MemoryEnvironment {
+    pub fn new(seed: u32, location: IpAddressPort) -> MemoryEnvironment {
+        MemoryEnvironment{
+            rng: ChaChaRng::from_seed(&[seed]),
+            outgoing: Vec::new(),
+            location: location,
+            tasks: Vec::new(),
+            storage: HashMap::new(),
+            now: START_TIME,
+            acknowledged: Vec::new(),
+            responses: Vec::new(),
+        }
+    }
+
+    pub fn take_tasks(&mut self) -> Vec<Task> {
+        ::std::mem::replace(&mut self.tasks, Vec::new())
+    }
+}
+
+impl Rng for MemoryEnvironment {
+    fn next_u32(&mut self) -> u32 {
+        self.rng.next_u32()
+    }
+}
+
+impl AgentEnvironment for MemoryEnvironment {
+    fn get_current_timestamp(&self) -> u64 {
+        self.now
+    }
+
+    fn send(&mut self, dest: &IpAddressPort, packet: &[u8]) {
+        self.outgoing.push((*dest, packet.to_vec()))
+    }
+
+    fn execute(&mut self, task: Task) {
+        self.tasks.push(task);
+    }
+
+    fn load(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.storage.get(key).cloned()
+    }
+
+    fn store(&mut self, key: &[u8], value: &[u8]) {
+        self.storage.insert(key.to_vec(), value.to_vec());
+    }
+
+    fn acknowledged(&mut self, neighbor: &Identity, sequence: u64) {
+        self.acknowledged.push((*neighbor, sequence));
+    }
+
+    fn responded(&mut self, neighbor: &Identity, request: u64, result: Result<Noun, RequestError>) {
+        self.responses.push((*neighbor, request, result));
+    }
+}
+
+/// The address `agents` gives the agent at `index`.
+pub fn location(index: usize) -> IpAddressPort {
+    let mut address = [0u8; 16];
+    address[0] = 0xfd;
+    address[8..16].copy_from_slice(&[0, 0, 0, 0, (index >> 24) as u8, (index >> 16) as u8, (index >> 8) as u8, index as u8]);
+    IpAddressPort{ address: address, port: 5000 }
+}
+
+/// Make `count` agents, each with its own identity and `location(index)`. The same `seed`
+/// always makes the same agents.
+pub fn agents(count: usize, seed: u32) -> Vec<Agent<MemoryEnvironment>> {
+    let mut rng = ChaChaRng::from_seed(&[seed]);
+    (0..count).map(|index| {
+        let mut identity_seed = [0u8; 32];
+        rng.fill_bytes(&mut identity_seed[..]);
+        Agent::new(&identity_seed, MemoryEnvironment::new(rng.next_u32(), location(index)))
+    }).collect()
+}
+
+/// Deliver every datagram the agents have sent so far to the agents at their destinations.
+/// Datagrams sent in response wait for the next call. Returns how many were delivered.
+pub fn exchange<A: BorrowMut<Agent<MemoryEnvironment>>>(agents: &mut [A]) -> usize {
+    let mut in_flight = Vec::new();
+    for agent in agents.iter_mut() {
+        let environment = &mut agent.borrow_mut().environment;
+        let source = environment.location;
+        for (destination, packet) in environment.outgoing.drain(..) {
+            in_flight.push((source, destination, packet));
+        }
+    }
+
+    let mut delivered = 0;
+    for (source, destination, packet) in in_flight.into_iter() {
+        for agent in agents.iter_mut() {
+            let agent = agent.borrow_mut();
+            if agent.environment.location == destination {
+                agent.handle_packet(&source, &packet[..]);
+                delivered += 1;
+            }
+        }
+    }
+    delivered
+}
+
+/// Exchange datagrams until none are left, for at most `max_rounds`. Returns whether that
+/// happened in time.
+pub fn run_until_quiet<A: BorrowMut<Agent<MemoryEnvironment>>>(agents: &mut [A], max_rounds: usize) -> bool {
+    for _ in 0..max_rounds {
+        if is_quiet(agents) {
+            return true;
+        }
+        exchange(agents);
+    }
+    is_quiet(agents)
+}
+
+fn is_quiet<A: BorrowMut<Agent<MemoryEnvironment>>>(agents: &mut [A]) -> bool {
+    agents.iter_mut().all(|agent| agent.borrow_mut().environment.outgoing.is_empty())
+}
+
+/// Have the agent at `from` start streaming with the one at `to`. The streams are ready once
+/// the initiation packets have been exchanged.
+pub fn connect<A: BorrowMut<Agent<MemoryEnvironment>>>(agents: &mut [A], from: usize, to: usize) {
+    let (identity, location) = {
+        let to = agents[to].borrow_mut();
+        (to.identity(), to.environment.location)
+    };
+    agents[from].borrow_mut().initiate_stream_with(&identity, &location).ok().expect("initiate_stream_with failed");
+}
+
+/// Connect every pair of agents, and run until their streams are ready.
+pub fn connect_all<A: BorrowMut<Agent<MemoryEnvironment>>>(agents: &mut [A]) {
+    for from in 0..agents.len() {
+        for to in from + 1..agents.len() {
+            connect(agents, from, to);
+        }
+    }
+    assert!(run_until_quiet(agents, 10), "agents did not finish connecting");
+}
+
+/// Throw away every agent's pending tasks.
+pub fn drain_tasks<A: BorrowMut<Agent<MemoryEnvironment>>>(agents: &mut [A]) {
+    for agent in agents.iter_mut() {
+        agent.borrow_mut().environment.tasks.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{agents, connect, connect_all, exchange, run_until_quiet};
+    use vm::{opcode, AsNoun};
+
+    #[test]
+    fn connects_agents() {
+        let mut agents = agents(4, 9);
+        connect_all(&mut agents[..]);
+        for a in 0..4 {
+            for b in 0..4 {
+                let identity = agents[b].identity();
+                assert_eq!(agents[a].is_neighbor(&identity), a != b);
+            }
+        }
+
+        let program = (1, opcode::LITERAL, 2).as_noun();
+        let identity = agents[3].identity();
+        agents[0].send_program(&identity, &program).ok().expect("send_program failed");
+        assert_eq!(exchange(&mut agents[..]), 1);
+        let tasks = agents[3].environment.take_tasks();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].requestor, agents[0].identity());
+        assert_eq!(tasks[0].program, program);
+        assert!(run_until_quiet(&mut agents[..], 1));
+    }
+
+    #[test]
+    fn deterministic() {
+        let (mut first, second) = (agents(3, 1), agents(3, 1));
+        for (a, b) in first.iter().zip(second.iter()) {
+            assert_eq!(a.identity(), b.identity());
+        }
+        assert!(agents(1, 2)[0].identity() != first[0].identity());
+
+        // Agents can be passed by reference, too.
+        let (left, right) = first.split_at_mut(1);
+        connect(&mut [&mut left[0], &mut right[1]], 1, 0);
+        assert!(run_until_quiet(&mut [&mut left[0], &mut right[1]], 3));
+        assert!(left[0].is_neighbor(&right[1].identity()));
+    }
+}
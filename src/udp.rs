@@ -0,0 +1,106 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{SystemTime, UNIX_EPOCH};
+use rand::{OsRng, Rng};
+use agent::{AgentEnvironment, RequestError, Task};
+use identity::Identity;
+use ip_address_port::IpAddressPort;
+use vm::Noun;
+
+/// Largest datagram `receive` will accept.
+pub const MAX_RECEIVED_LEN: usize = 65536;
+
+/// An `AgentEnvironment` that reaches neighbors over UDP. Tasks and responses are queued for the
+/// caller to take, and storage is kept in memory.
+pub struct UdpEnvironment {
+    socket: UdpSocket,
+    rng: OsRng,
+    tasks: VecDeque<Task>,
+    responses: VecDeque<(Identity, u64, Result<Noun, RequestError>)>,
+    storage: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl UdpEnvironment {
+    pub fn bind(address: &SocketAddr) -> io::Result<UdpEnvironment> {
+        Ok(UdpEnvironment{
+            socket: try!(UdpSocket::bind(address)),
+            rng: try!(OsRng::new()),
+            tasks: VecDeque::new(),
+            responses: VecDeque::new(),
+            storage: HashMap::new(),
+        })
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    pub fn local_address(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Wait, for as long as the socket's read timeout allows, for a datagram. Returns `None` if
+    /// nothing arrived in time.
+    pub fn receive(&self, buffer: &mut [u8]) -> io::Result<Option<(usize, IpAddressPort)>> {
+        match self.socket.recv_from(buffer) {
+            Ok((len, source)) => Ok(Some((len, IpAddressPort::from(source)))),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The oldest task handed to `execute` that has not been taken yet.
+    pub fn next_task(&mut self) -> Option<Task> {
+        self.tasks.pop_front()
+    }
+
+    /// The oldest response passed to `responded` that has not been taken yet.
+    pub fn next_response(&mut self) -> Option<(Identity, u64, Result<Noun, RequestError>)> {
+        self.responses.pop_front()
+    }
+}
+
+impl Rng for UdpEnvironment {
+    fn next_u32(&mut self) -> u32 {
+        self.rng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.rng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng.fill_bytes(dest)
+    }
+}
+
+impl AgentEnvironment for UdpEnvironment {
+    fn get_current_timestamp(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+    }
+
+    fn send(&mut self, address: &IpAddressPort, packet: &[u8]) {
+        // Datagrams can be lost anyway, so a failure here is no different from one in transit.
+        let _ = self.socket.send_to(packet, address);
+    }
+
+    fn execute(&mut self, task: Task) {
+        self.tasks.push_back(task);
+    }
+
+    fn load(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.storage.get(key).cloned()
+    }
+
+    fn store(&mut self, key: &[u8], value: &[u8]) {
+        self.storage.insert(key.to_vec(), value.to_vec());
+    }
+
+    fn acknowledged(&mut self, _neighbor: &Identity, _sequence: u64) {
+    }
+
+    fn responded(&mut self, neighbor: &Identity, request: u64, result: Result<Noun, RequestError>) {
+        self.responses.push_back((*neighbor, request, result));
+    }
+}
@@ -0,0 +1,154 @@
+/// Prices, in ticks, for the work an evaluation does. Lengths are in bytes.
+pub trait CostModel {
+    /// Evaluating one formula. Charged before the formula is even looked at.
+    fn step(&self) -> u64;
+
+    /// Carrying out `opcode`, on top of `step` and the costs of the work it does. Charged once
+    /// the opcode is known, before its argument is evaluated.
+    fn opcode(&self, opcode: u8) -> u64;
+
+    /// Comparing one pair of nouns, for IS_EQUAL.
+    fn compare(&self) -> u64;
+
+    /// Measuring one noun, for SHAPE.
+    fn measure(&self) -> u64;
+
+    /// Copying one byte of data into place, for RESHAPE.
+    fn reshape_byte(&self) -> u64;
+
+    /// ADD, LESS, XOR or INVERT, with operands up to `len` long.
+    fn arithmetic(&self, len: usize) -> u64;
+
+    /// Serializing and hashing a noun, for HASH and STORE_BY_HASH. `len` is the length of the
+    /// serialization.
+    fn hash(&self, len: usize) -> u64;
+
+    /// Writing a value to storage, for STORE_BY_HASH and STORE_BY_KEY. Includes serializing the
+    /// key and value.
+    fn store(&self, key_len: usize, value_len: usize) -> u64;
+
+    /// Reading from storage, for RETRIEVE_BY_HASH and RETRIEVE_BY_KEY. Includes serializing the
+    /// key and deserializing the value. `value_len` is 0 if nothing was found.
+    fn load(&self, key_len: usize, value_len: usize) -> u64;
+
+    /// Generating `len` random bytes, for RANDOM and GENERATE_KEYPAIR.
+    fn random(&self, len: usize) -> u64;
+
+    /// Deriving the private key for an atom of a public key.
+    fn derive_key_atom(&self, len: usize) -> u64;
+
+    /// Deriving the private key for a cell of a public key, from those of its children.
+    fn derive_key_cell(&self) -> u64;
+
+    /// Serializing and encrypting a noun, for ENCRYPT and EXUCRYPT. `len` is the length of the
+    /// serialization.
+    fn encrypt(&self, len: usize) -> u64;
+
+    /// Decrypting and deserializing a ciphertext, for DECRYPT, EXUCRYPT and EXECUTE_AS.
+    fn decrypt(&self, ciphertext_len: usize) -> u64;
+
+    /// Sending a message to a neighbor. `len` is the length of its serialization.
+    fn send(&self, len: usize) -> u64;
+
+    /// Asking to start neighboring with another agent.
+    fn start_neighboring(&self) -> u64;
+
+    /// Listing one neighbor, for NEIGHBORS_NEAR.
+    fn list_neighbor(&self) -> u64;
+}
+
+/// The schedule agents charge by default:
+///
+/// | Work                      | Ticks                     |
+/// |---------------------------|---------------------------|
+/// | each formula              | 1                         |
+/// | opcodes themselves        | 0                         |
+/// | comparing a pair          | 1                         |
+/// | measuring a noun          | 1                         |
+/// | reshaping a byte          | 1                         |
+/// | arithmetic                | length of longest operand |
+/// | hashing                   | 20 + length               |
+/// | storing                   | key + value length        |
+/// | loading                   | key + value length        |
+/// | random bytes              | length                    |
+/// | deriving a key, per atom  | atom length               |
+/// | deriving a key, per cell  | 128                       |
+/// | encrypting                | length                    |
+/// | decrypting                | ciphertext length         |
+/// | sending                   | 100 + length              |
+/// | starting to neighbor      | 1000                      |
+/// | listing a neighbor        | 32                        |
+#[derive(Debug, Copy, Clone, Default)]
+pub struct DefaultCostModel;
+
+impl CostModel for DefaultCostModel {
+    fn step(&self) -> u64 {
+        1
+    }
+
+    fn opcode(&self, _opcode: u8) -> u64 {
+        0
+    }
+
+    fn compare(&self) -> u64 {
+        1
+    }
+
+    fn measure(&self) -> u64 {
+        1
+    }
+
+    fn reshape_byte(&self) -> u64 {
+        1
+    }
+
+    fn arithmetic(&self, len: usize) -> u64 {
+        len as u64
+    }
+
+    fn hash(&self, len: usize) -> u64 {
+        20 + len as u64
+    }
+
+    fn store(&self, key_len: usize, value_len: usize) -> u64 {
+        (key_len + value_len) as u64
+    }
+
+    fn load(&self, key_len: usize, value_len: usize) -> u64 {
+        (key_len + value_len) as u64
+    }
+
+    fn random(&self, len: usize) -> u64 {
+        len as u64
+    }
+
+    fn derive_key_atom(&self, len: usize) -> u64 {
+        len as u64
+    }
+
+    fn derive_key_cell(&self) -> u64 {
+        128
+    }
+
+    fn encrypt(&self, len: usize) -> u64 {
+        len as u64
+    }
+
+    fn decrypt(&self, ciphertext_len: usize) -> u64 {
+        ciphertext_len as u64
+    }
+
+    fn send(&self, len: usize) -> u64 {
+        100 + len as u64
+    }
+
+    fn start_neighboring(&self) -> u64 {
+        // Starting a stream costs the host a key exchange and a signature, so it is priced well
+        // above an ordinary step.
+        1000
+    }
+
+    fn list_neighbor(&self) -> u64 {
+        32
+    }
+}
@@ -87,13 +87,28 @@ impl<'a> Deserializer<'a> {
     }
 
     fn deserialize_noun(&mut self) -> DeserializeResult<Noun> {
-        let is_cell = self.consume_structure_bit()?;
-        if is_cell {
-            let left = self.deserialize_noun()?;
-            let right = self.deserialize_noun()?;
-            Ok(Noun::new_cell(left, right))
-        } else {
-            self.deserialize_atom()
+        // Cells whose children are still being read. Each holds its left child once that's done.
+        let mut open_cells: Vec<Option<Noun>> = Vec::new();
+        loop {
+            let is_cell = self.consume_structure_bit()?;
+            if is_cell {
+                open_cells.push(None);
+                continue;
+            }
+
+            let mut completed = self.deserialize_atom()?;
+            loop {
+                match open_cells.pop() {
+                    None => { return Ok(completed); }
+                    Some(None) => {
+                        open_cells.push(Some(completed));
+                        break;
+                    }
+                    Some(Some(left)) => {
+                        completed = Noun::new_cell(left, completed);
+                    }
+                }
+            }
         }
     }
 
@@ -150,6 +165,7 @@ mod test {
     use as_noun::AsNoun;
     use deserialize::deserialize;
     use noun::Noun;
+    use serialize::serialize;
 
     #[test]
     fn byte_atom() {
@@ -196,4 +212,27 @@ mod test {
         .collect();
         assert_eq!(deserialize(&encoding[..]), Ok(Noun::from_vec(atom)));
     }
+
+    #[test]
+    fn long_round_trip() {
+        for len in [200, 1000, 65536].iter() {
+            let noun = (1, build_buffer(*len), (2, 3)).as_noun();
+            let serialized = serialize(&noun, 100_000).unwrap();
+            assert_eq!(deserialize(&serialized[..]), Ok(noun));
+        }
+    }
+
+    #[test]
+    fn deep_round_trip() {
+        let mut noun = Noun::from_u8(0);
+        for idx in 0..1_000_000 {
+            noun = if idx % 2 == 0 {
+                Noun::new_cell(noun, Noun::from_u8(1))
+            } else {
+                Noun::new_cell(Noun::from_u8(2), noun)
+            };
+        }
+        let serialized = serialize(&noun, 10_000_000).unwrap();
+        assert!(deserialize(&serialized[..]) == Ok(noun));
+    }
 }
@@ -2,26 +2,38 @@ use noun::Noun;
 use ticks::{CostResult, Ticks};
 
 /// Compare two `Noun`s for value equality. That is, they would have identical serializations.
-/// Since a noun could be of unbounded size, this computation is limited with a tick count.
-pub fn equal(a: &Noun, b: &Noun, ticks: &mut Ticks) -> CostResult<bool> {
-    ticks.incur(1)?;
-    Ok(match (a, b) {
-        (&Noun::Cell(ref a, ref b), &Noun::Cell(ref x, ref y)) => {
-            equal(a, x, ticks)? && equal(b, y, ticks)?
+/// Since a noun could be of unbounded size, this computation is limited with a tick count,
+/// charging `pair_cost` for each pair compared.
+///
+/// Pairs are compared depth first, left to right, stopping at the first difference.
+pub fn equal(a: &Noun, b: &Noun, ticks: &mut Ticks, pair_cost: u64) -> CostResult<bool> {
+    let mut pending = vec![(a, b)];
+    while let Some((a, b)) = pending.pop() {
+        ticks.incur(pair_cost)?;
+        let same = match (a, b) {
+            (&Noun::Cell(ref a, ref b), &Noun::Cell(ref x, ref y)) => {
+                pending.push((b, y));
+                pending.push((a, x));
+                true
+            }
+            (
+                &Noun::SmallAtom {
+                    value: value_a,
+                    length: length_a,
+                },
+                &Noun::SmallAtom {
+                    value: value_b,
+                    length: length_b,
+                },
+            ) => (value_a, length_a) == (value_b, length_b),
+            (&Noun::Atom(ref a), &Noun::Atom(ref x)) => a == x,
+            _ => false, // Nouns that can be SmallAtoms will be SmallAtoms. Doing otherwise would complicate constant-time guarantees.
+        };
+        if !same {
+            return Ok(false);
         }
-        (
-            &Noun::SmallAtom {
-                value: value_a,
-                length: length_a,
-            },
-            &Noun::SmallAtom {
-                value: value_b,
-                length: length_b,
-            },
-        ) => (value_a, length_a) == (value_b, length_b),
-        (&Noun::Atom(ref a), &Noun::Atom(ref x)) => a == x,
-        _ => false, // Nouns that can be SmallAtoms will be SmallAtoms. Doing otherwise would complicate constant-time guarantees.
-    })
+    }
+    Ok(true)
 }
 
 #[cfg(test)]
@@ -40,7 +52,7 @@ mod test {
             a = Noun::new_cell(a.clone(), a.clone());
         }
 
-        assert!(equal(&a, &a, &mut Ticks::new(1000)).is_err());
+        assert!(equal(&a, &a, &mut Ticks::new(1000), 1).is_err());
     }
 
     #[test]
@@ -49,19 +61,34 @@ mod test {
             equal(
                 &(6, 7, &b"element three"[..]).as_noun(),
                 &(6, (7, &b"element three"[..])).as_noun(),
-                &mut Ticks::new(1000)
+                &mut Ticks::new(1000),
+                1
             ),
             Ok(true)
         );
     }
 
+    #[test]
+    fn deep_equality() {
+        let mut a = Noun::from_u8(0);
+        let mut b = Noun::from_u8(0);
+        for _ in 0..1_000_000 {
+            a = Noun::new_cell(Noun::from_u8(1), a);
+            b = Noun::new_cell(Noun::from_u8(1), b);
+        }
+
+        assert_eq!(equal(&a, &b, &mut Ticks::new(10_000_000), 1), Ok(true));
+        assert!(a == b);
+    }
+
     #[test]
     fn not_equal() {
         assert_eq!(
             equal(
                 &(6, 7, &b"element three"[..]).as_noun(),
                 &(6, (9, &b"element three"[..])).as_noun(),
-                &mut Ticks::new(1000)
+                &mut Ticks::new(1000),
+                1
             ),
             Ok(false)
         );
@@ -7,18 +7,20 @@ use deserialize::deserialize;
 use equal::equal;
 use noun::{Noun, NounKind};
 use opcode::*;
-use std::cmp::max;
+use std::cmp::{max, min};
 use serialize::{self, SerializationError};
-use shape::{reshape, length};
+use shape::{reshape, length, ShapeError};
 use std::convert::From;
 use ticks::{CostError, Ticks};
+use cost::{CostModel, DefaultCostModel};
 use math::{add, invert, less, xor};
 use as_noun::AsNoun;
 use chacha::{ChaCha, KeyStream};
 use std::collections::HashMap;
 use std::convert::TryInto;
+use std::mem::{replace, size_of};
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum EvalError {
     Something,
     CellAsIndex,
@@ -40,6 +42,65 @@ pub enum EvalError {
     NonAtomicMath,
     BadExecuteAsBody,
     BadExecuteAsCounter,
+
+    /// The program did not deserialize, so it never got as far as evaluation.
+    MalformedProgram,
+}
+
+impl EvalError {
+    /// A fixed-size encoding, for telling whoever asked for an evaluation why it failed.
+    pub fn encode(&self) -> [u8; 2] {
+        match *self {
+            EvalError::Something => [0, 0],
+            EvalError::CellAsIndex => [1, 0],
+            EvalError::IndexOutOfRange => [2, 0],
+            EvalError::InvalidLength => [3, 0],
+            EvalError::NotAnOpcode => [4, 0],
+            EvalError::BadOpcode(opcode) => [5, opcode],
+            EvalError::BadRecurseArgument => [6, 0],
+            EvalError::BadEqualsArgument => [7, 0],
+            EvalError::BadArgument => [8, 0],
+            EvalError::BadIfCondition => [9, 0],
+            EvalError::TickLimitExceeded => [10, 0],
+            EvalError::AtomicFormula => [11, 0],
+            EvalError::MemoryExceeded => [12, 0],
+            EvalError::StorageCorrupt => [13, 0],
+            EvalError::EvalOnAtom => [14, 0],
+            EvalError::BadShape => [15, 0],
+            EvalError::DecryptionFailed => [16, 0],
+            EvalError::NonAtomicMath => [17, 0],
+            EvalError::BadExecuteAsBody => [18, 0],
+            EvalError::BadExecuteAsCounter => [19, 0],
+            EvalError::MalformedProgram => [20, 0],
+        }
+    }
+
+    pub fn decode(bs: [u8; 2]) -> Option<EvalError> {
+        Some(match bs {
+            [0, 0] => EvalError::Something,
+            [1, 0] => EvalError::CellAsIndex,
+            [2, 0] => EvalError::IndexOutOfRange,
+            [3, 0] => EvalError::InvalidLength,
+            [4, 0] => EvalError::NotAnOpcode,
+            [5, opcode] => EvalError::BadOpcode(opcode),
+            [6, 0] => EvalError::BadRecurseArgument,
+            [7, 0] => EvalError::BadEqualsArgument,
+            [8, 0] => EvalError::BadArgument,
+            [9, 0] => EvalError::BadIfCondition,
+            [10, 0] => EvalError::TickLimitExceeded,
+            [11, 0] => EvalError::AtomicFormula,
+            [12, 0] => EvalError::MemoryExceeded,
+            [13, 0] => EvalError::StorageCorrupt,
+            [14, 0] => EvalError::EvalOnAtom,
+            [15, 0] => EvalError::BadShape,
+            [16, 0] => EvalError::DecryptionFailed,
+            [17, 0] => EvalError::NonAtomicMath,
+            [18, 0] => EvalError::BadExecuteAsBody,
+            [19, 0] => EvalError::BadExecuteAsCounter,
+            [20, 0] => EvalError::MalformedProgram,
+            _ => { return None; }
+        })
+    }
 }
 
 fn double_arg(noun: Noun) -> Result<(Noun, Noun), EvalError> {
@@ -60,6 +121,21 @@ fn bytes_arg(noun: &Noun) -> Result<&[u8], EvalError> {
         Err(EvalError::BadArgument)
     }
 }
+fn address_arg(noun: &Noun) -> Result<[u8; 16], EvalError> {
+    let bytes = bytes_arg(noun)?;
+    let mut address = [0u8; 16];
+    if bytes.len() == 16 {
+        address.copy_from_slice(bytes);
+        Ok(address)
+    } else {
+        Err(EvalError::BadArgument)
+    }
+}
+fn port_arg(noun: &Noun) -> Result<u16, EvalError> {
+    noun.as_usize()
+        .and_then(|port| port.try_into().ok())
+        .ok_or(EvalError::BadArgument)
+}
 fn key_arg(noun: &Noun) -> Result<[u8; 32], EvalError> {
     let bytes = bytes_arg(noun)?;
     let mut key = [0u8; 32];
@@ -73,22 +149,139 @@ fn key_arg(noun: &Noun) -> Result<[u8; 32], EvalError> {
 
 pub type EvalResult = Result<Noun, EvalError>;
 
+/// XOR of two identities. Compared as byte arrays, these order identities by closeness to `b`,
+/// which is what "closest" means for `SideEffectEngine::nearest_neighbors`.
+pub fn xor_distance(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let mut distance = [0u8; 32];
+    for (d, (x, y)) in distance.iter_mut().zip(a.iter().zip(b.iter())) {
+        *d = *x ^ *y;
+    }
+    distance
+}
+
 pub trait SideEffectEngine {
-    fn nearest_neighbor(&mut self, near: &[u8; 32]) -> [u8; 32];
+    /// Up to `count` known identities, closest to `near` first.
+    fn nearest_neighbors(&mut self, near: &[u8; 32], count: usize) -> Vec<[u8; 32]>;
     fn random(&mut self, _: &mut [u8]);
     fn load(&mut self, key: &[u8]) -> Option<Vec<u8>>;
     fn store(&mut self, key: &[u8], value: &[u8]);
-    fn send(&mut self, destination: &[u8; 32], message: &[u8], local_cost: u64);
+    /// Send `message` to `destination` once it can be. `local_cost` is what the sender has
+    /// already been charged for it. Returns false if the destination cannot be reached.
+    fn send(&mut self, destination: &[u8; 32], message: &[u8], local_cost: u64) -> bool;
     fn secret(&self) -> &[u8; 32];
+    /// Runners never spend more than the ticks the evaluation was given, whatever this allows.
     fn ticks_for(&self, executor: &[u8; 32]) -> Ticks; // How much "credit" an account has, at least that the engine is willing to spend right now
     fn consume_counter(&mut self, counter: &[u8; 8], private_key: &[u8; 32]) -> bool;
+    /// Ask the host to begin a stream with `identity`, which is reachable at the 16-byte
+    /// (IPv6, or IPv4-mapped) `address` and `port`. Returns whether the request was accepted.
+    fn start_neighboring(&mut self, identity: &[u8; 32], address: &[u8; 16], port: u16) -> bool;
+}
+
+/// What to do next while evaluating.
+enum Step {
+    /// Evaluate a formula against a subject.
+    Eval(Noun, Noun),
+
+    /// Hand a result to the innermost `Continuation`.
+    Return(Noun),
+}
+
+/// What remains to be done with a result once it has been computed. Each holds whatever the
+/// recursive definition of its opcode would have kept on the native stack.
+enum Continuation {
+    /// The left formula of a distribution is being evaluated. Evaluate the right one against
+    /// this subject next.
+    DistributeRight(Noun, Noun),
+
+    /// The right formula of a distribution is being evaluated. Pair the result with this one.
+    DistributeJoin(Noun),
+
+    /// RECURSE's new subject is being computed. Compute its formula against this subject next.
+    RecurseFormula(Noun, Noun),
+
+    /// RECURSE's formula is being computed. Evaluate it against this subject.
+    RecurseInto(Noun),
+
+    /// IF's condition is being evaluated. Then evaluate one of these against the subject.
+    Branch(Noun, Noun, Noun),
+
+    /// COMPOSE's new subject is being computed. Evaluate this formula against it.
+    Compose(Noun),
+
+    /// DEFINE's new head of the subject is being computed.
+    Define(Noun, Noun),
+
+    /// CALL's core is being computed. Evaluate the formula at this axis of it against it.
+    Call(Noun),
+
+    /// An opcode's argument is being evaluated. Some opcodes need the subject too.
+    Apply(u8, Option<Noun>),
+
+    /// Something retrieved from storage is being evaluated.
+    Found,
+
+    /// EXUCRYPT's program is being evaluated. Encrypt the result under this key.
+    EncryptResult([u8; 32]),
+
+    /// EXECUTE_AS's body is being evaluated. Switch back to the runner it interrupted afterwards.
+    RestoreRunner(Suspended),
+}
+
+/// A runner interrupted by EXECUTE_AS.
+struct Suspended {
+    executing_as: Option<[u8; 32]>,
+    ticks: Ticks,
+    attributed: u64,
 }
 
-struct Computation<'a, S: 'a> {
+/// What an evaluation consumed, so that the host can settle accounts.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ExecutionReport {
+    /// Ticks consumed outside of any EXECUTE_AS.
+    pub requestor_ticks: u64,
+
+    /// Ticks consumed while executing as each EXECUTE_AS runner, by the runner's public key. A
+    /// runner's ticks do not include those of runners it switched to in turn.
+    pub runner_ticks: HashMap<[u8; 32], u64>,
+
+    /// Bytes written to storage, keys and values both.
+    pub bytes_stored: u64,
+
+    /// Messages the side effector accepted for sending, and their total length.
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+}
+
+impl ExecutionReport {
+    /// Every tick consumed, by the requestor and runners alike.
+    pub fn total_ticks(&self) -> u64 {
+        self.runner_ticks.values().fold(self.requestor_ticks, |total, ticks| total.saturating_add(*ticks))
+    }
+}
+
+struct Computation<'a, S: 'a, C: 'a + ?Sized> {
+    /// Ticks left to whoever is executing. A runner never gets more than the executor that
+    /// switched to it had left, and what it consumes is taken out of those when it is switched
+    /// back from, so everything is paid for out of the caller's ticks.
     ticks_remaining: Ticks,
-    executing_as: [u8; 32],
+
+    /// How much of what `ticks_remaining` has consumed is already in the report.
+    attributed: u64,
+    costs: &'a C,
+
+    /// Bytes left to allocate, shared by every runner.
+    memory_remaining: Ticks,
+
+    /// The runner executing, or None for the requestor.
+    executing_as: Option<[u8; 32]>,
     side_effector: &'a mut S,
+
+    /// What is left of each runner's own allowance, from `SideEffectEngine::ticks_for`.
     ticks_for: HashMap<[u8; 32], Ticks>,
+
+    /// What has been consumed so far. Ticks are added whenever executors are switched between,
+    /// and once evaluation is over.
+    report: ExecutionReport,
 }
 
 impl From<CostError> for EvalError {
@@ -100,38 +293,118 @@ impl From<CostError> for EvalError {
 const SYMMETRIC_NONCE_LEN: usize = 8;
 const SYMMETRIC_TAG_LEN: usize = 16;
 
-impl<'a, S: SideEffectEngine> Computation<'a, S> {
-    pub fn retrieve_with_tag(
+/// The most identities a single NEIGHBORS_NEAR may ask for.
+const NEIGHBORS_NEAR_MAX: usize = 256;
+
+/// Longest serialization evaluation will make, whatever its memory budget, so that whatever a
+/// program sends or stores fits within what hosts will accept from the network.
+pub const MAX_SERIALIZED_LEN: usize = 1_000_000;
+
+/// Memory charged for each cell built, for the two nouns it points to.
+const CELL_BYTES: u64 = 2 * size_of::<Noun>() as u64;
+
+impl<'a, S: SideEffectEngine, C: CostModel + ?Sized> Computation<'a, S, C> {
+    fn allocate(&mut self, bytes: u64) -> Result<(), EvalError> {
+        self.memory_remaining.incur(bytes).map_err(|_| EvalError::MemoryExceeded)
+    }
+
+    fn new_cell(&mut self, left: Noun, right: Noun) -> EvalResult {
+        self.allocate(CELL_BYTES)?;
+        Ok(Noun::new_cell(left, right))
+    }
+
+    fn new_atom(&mut self, bytes: &[u8]) -> EvalResult {
+        self.allocate(bytes.len() as u64)?;
+        Ok(Noun::from_slice(bytes))
+    }
+
+    /// Charge for a noun that was built without going through `new_cell` and `new_atom`, such
+    /// as one that was deserialized. Its size was already bounded by the ticks spent on it.
+    fn allocated(&mut self, noun: &Noun) -> Result<(), EvalError> {
+        let mut pending = vec![noun];
+        while let Some(noun) = pending.pop() {
+            match noun.as_kind() {
+                NounKind::Atom(xs) => self.allocate(xs.len() as u64)?,
+                NounKind::Cell(left, right) => {
+                    self.allocate(CELL_BYTES)?;
+                    pending.push(right);
+                    pending.push(left);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn retrieve_with_tag(
         &mut self,
         subject: Noun,
         mut key: Vec<u8>,
         tag: u8,
-    ) -> Result<Noun, EvalError> {
+        stack: &mut Vec<Continuation>,
+    ) -> Result<Step, EvalError> {
         key.push(tag);
 
         // TODO: It might be better to always return a cell.
-        if let Some(xs) = self.side_effector.load(&key[..]) {
+        let loaded = self.side_effector.load(&key[..]);
+        let loaded_len = loaded.as_ref().map(|xs| xs.len()).unwrap_or(0);
+        self.ticks_remaining.incur(self.costs.load(key.len(), loaded_len))?;
+        if let Some(xs) = loaded {
             let retrieved = deserialize(&xs[..]).map_err(|_| EvalError::StorageCorrupt)?;
-            Ok(Noun::new_cell(
-                Noun::from_bool(true),
-                self.eval_on(subject, retrieved)?,
-            ))
+            self.allocated(&retrieved)?;
+            stack.push(Continuation::Found);
+            Ok(Step::Eval(subject, retrieved))
         } else {
-            Ok(Noun::from_bool(false))
+            Ok(Step::Return(Noun::from_bool(false)))
+        }
+    }
+
+    /// Report the ticks consumed since the last call as spent by whoever is executing, taking
+    /// them out of a runner's own allowance.
+    fn attribute(&mut self) -> Result<(), EvalError> {
+        let consumed = self.ticks_remaining.get_consumed() - self.attributed;
+        self.attributed += consumed;
+        match self.executing_as {
+            Some(runner) => {
+                *self.report.runner_ticks.entry(runner).or_insert(0) += consumed;
+                if let Some(allowance) = self.ticks_for.get_mut(&runner) {
+                    allowance.incur(consumed)?;
+                }
+            }
+            None => {
+                self.report.requestor_ticks += consumed;
+            }
         }
+        Ok(())
     }
 
-    fn switch_to_runner(&mut self, private_key: &[u8; 32]) -> [u8; 32] {
-        let was_running_as = self.executing_as;
-        self.ticks_for.insert(self.executing_as, self.ticks_remaining.clone());
-        self.executing_as = *private_key;
+    fn switch_to_runner(&mut self, runner: &[u8; 32]) -> Result<Suspended, EvalError> {
+        self.attribute()?;
+        let allowance = {
+            let ref mut side_effector = self.side_effector;
+            self.ticks_for.entry(*runner).or_insert_with(|| {
+                side_effector.ticks_for(runner)
+            }).remaining()
+        };
+        let ticks = Ticks::new(min(allowance, self.ticks_remaining.remaining()));
+        Ok(Suspended {
+            executing_as: replace(&mut self.executing_as, Some(*runner)),
+            ticks: replace(&mut self.ticks_remaining, ticks),
+            attributed: replace(&mut self.attributed, 0),
+        })
+    }
 
-        let ref mut ticks_for = self.ticks_for;
-        let ref mut side_effector = self.side_effector;
-        self.ticks_remaining = ticks_for.entry(*private_key).or_insert_with(|| {
-            side_effector.ticks_for(private_key)
-        }).clone();
-        was_running_as
+    /// Switch back to `suspended`, charging it for everything the runner consumed. It is
+    /// switched back to even if the runner overspent its own allowance.
+    fn restore_runner(&mut self, suspended: Suspended) -> Result<(), EvalError> {
+        let attributed = self.attribute();
+        let consumed = self.ticks_remaining.get_consumed();
+        self.executing_as = suspended.executing_as;
+        self.ticks_remaining = suspended.ticks;
+        self.attributed = suspended.attributed + consumed;
+
+        // Cannot fail, since the runner's ticks were no more than these had left.
+        self.ticks_remaining.incur(consumed)?;
+        attributed
     }
 
 
@@ -139,39 +412,58 @@ impl<'a, S: SideEffectEngine> Computation<'a, S> {
     /// The private key corresponding to an atom is only computable by the secret holder.
     /// the private key corresponding to a cell is computable by anyone, given knownledge
     /// of the private key of its right child.
+    ///
+    /// Keys are computed depth first, left to right, but with a stack of our own, since a
+    /// public key can be nested deeper than the native stack.
     fn private_symmetric_key_for(
         &mut self,
         public: &Noun,
-        branched_right: bool,
     ) -> Result<[u8; 32], EvalError> {
-        match public.as_kind() {
-            NounKind::Atom(xs) => {
-                self.ticks_remaining.incur(xs.len() as u64)?;
-                let mut result = [0u8; 32];
-
-                Blake2b::blake2b(
-                    &mut result[..],
-                    &xs,
-                    if branched_right {
-                        &self.side_effector.secret()[..]
-                    } else {
-                        &[][..]
-                    },
-                );
-                Ok(result)
-            }
-            NounKind::Cell(left, right) => {
-                self.ticks_remaining.incur(128)?;
-                let left_hash = self.private_symmetric_key_for(left, false)?;
-                let right_hash = self.private_symmetric_key_for(right, true)?;
-                let mut hasher = Blake2b::new(32);
-                hasher.input(&left_hash[..]);
-                hasher.input(&right_hash[..]);
-                let mut output = [0u8; 32];
-                hasher.result(&mut output[..]);
-                Ok(output)
+        enum KeyStep<'n> {
+            Visit(&'n Noun, bool),
+            Combine,
+        }
+
+        let mut steps = vec![KeyStep::Visit(public, false)];
+        let mut hashes: Vec<[u8; 32]> = Vec::new();
+        while let Some(step) = steps.pop() {
+            match step {
+                KeyStep::Visit(noun, branched_right) => match noun.as_kind() {
+                    NounKind::Atom(xs) => {
+                        self.ticks_remaining.incur(self.costs.derive_key_atom(xs.len()))?;
+                        let mut result = [0u8; 32];
+
+                        Blake2b::blake2b(
+                            &mut result[..],
+                            &xs,
+                            if branched_right {
+                                &self.side_effector.secret()[..]
+                            } else {
+                                &[][..]
+                            },
+                        );
+                        hashes.push(result);
+                    }
+                    NounKind::Cell(left, right) => {
+                        self.ticks_remaining.incur(self.costs.derive_key_cell())?;
+                        steps.push(KeyStep::Combine);
+                        steps.push(KeyStep::Visit(right, true));
+                        steps.push(KeyStep::Visit(left, false));
+                    }
+                },
+                KeyStep::Combine => {
+                    let right_hash = hashes.pop().unwrap();
+                    let left_hash = hashes.pop().unwrap();
+                    let mut hasher = Blake2b::new(32);
+                    hasher.input(&left_hash[..]);
+                    hasher.input(&right_hash[..]);
+                    let mut output = [0u8; 32];
+                    hasher.result(&mut output[..]);
+                    hashes.push(output);
+                }
             }
         }
+        Ok(hashes.pop().unwrap())
     }
 
     pub fn decrypt(
@@ -186,7 +478,7 @@ impl<'a, S: SideEffectEngine> Computation<'a, S> {
         let tag = &ciphertext[SYMMETRIC_NONCE_LEN..SYMMETRIC_NONCE_LEN + SYMMETRIC_TAG_LEN];
         let decryption_ciphertext = &ciphertext[SYMMETRIC_NONCE_LEN + SYMMETRIC_TAG_LEN..];
 
-        self.ticks_remaining.incur(ciphertext.len() as u64)?;
+        self.ticks_remaining.incur(self.costs.decrypt(ciphertext.len()))?;
 
         let mut decryptor = ChaCha20Poly1305::new(&key[..], &nonce[..], &[][..]);
         let mut plaintext_buffer = vec![0u8; decryption_ciphertext.len()];
@@ -196,12 +488,16 @@ impl<'a, S: SideEffectEngine> Computation<'a, S> {
 
         Ok(match deserialize(&plaintext_buffer[..]) {
             Err(_) => None,
-            Ok(result) => Some(result),
+            Ok(result) => {
+                self.allocated(&result)?;
+                Some(result)
+            }
         })
     }
 
     pub fn encrypt(&mut self, key: &[u8; 32], plaintext: &Noun) -> EvalResult {
         let result_buffer = self.serialize(&plaintext)?;
+        self.ticks_remaining.incur(self.costs.encrypt(result_buffer.len()))?;
 
         let mut serialized =
             vec![0u8; SYMMETRIC_NONCE_LEN + SYMMETRIC_TAG_LEN + result_buffer.len()];
@@ -216,308 +512,446 @@ impl<'a, S: SideEffectEngine> Computation<'a, S> {
             encryptor.encrypt(&result_buffer[..], ciphertext, tag);
         }
 
-        Ok(Noun::from_vec(serialized))
+        self.new_atom(&serialized)
     }
 
-    pub fn eval_on(&mut self, mut subject: Noun, mut formula: Noun) -> EvalResult {
-        'tail_recurse: loop {
-            self.ticks_remaining.incur(1)?;
+    /// Evaluate `formula` against `subject`.
+    ///
+    /// Programs can nest far deeper than the native stack, so rather than recursing, this keeps
+    /// what remains to be done with each intermediate result on a heap-allocated stack of
+    /// `Continuation`s.
+    pub fn eval_on(&mut self, subject: Noun, formula: Noun) -> EvalResult {
+        let mut stack = Vec::new();
+        let mut step = Ok(Step::Eval(subject, formula));
+        loop {
+            step = match step {
+                Ok(Step::Eval(subject, formula)) => self.begin(subject, formula, &mut stack),
+                Ok(Step::Return(value)) => match stack.pop() {
+                    Some(continuation) => self.resume(continuation, value, &mut stack),
+                    None => { return Ok(value); }
+                },
+                Err(e) => {
+                    // Nothing catches errors, but runners switched to by EXECUTE_AS are still
+                    // switched back from on the way out.
+                    while let Some(continuation) = stack.pop() {
+                        if let Continuation::RestoreRunner(suspended) = continuation {
+                            let _ = self.restore_runner(suspended);
+                        }
+                    }
+                    return Err(e);
+                }
+            };
+        }
+    }
 
-            let (opcode_noun, argument) = formula.into_cell().ok_or(EvalError::AtomicFormula)?;
-            if opcode_noun.is_cell() {
-                // Distribute. The opcode and argument are actually both formulas.
-                let lhs = self.eval_on(subject.clone(), opcode_noun)?;
-                let rhs = self.eval_on(subject, argument)?;
-                return Ok(Noun::new_cell(lhs, rhs));
+    /// Start evaluating `formula` against `subject`, returning the first thing to do.
+    fn begin(&mut self, subject: Noun, formula: Noun, stack: &mut Vec<Continuation>) -> Result<Step, EvalError> {
+        self.ticks_remaining.incur(self.costs.step())?;
+
+        let (opcode_noun, argument) = formula.into_cell().ok_or(EvalError::AtomicFormula)?;
+        if opcode_noun.is_cell() {
+            // Distribute. The opcode and argument are actually both formulas.
+            stack.push(Continuation::DistributeRight(subject.clone(), argument));
+            return Ok(Step::Eval(subject, opcode_noun));
+        }
+
+        let opcode = opcode_noun.as_u8().ok_or(EvalError::NotAnOpcode)?;
+        self.ticks_remaining.incur(self.costs.opcode(opcode))?;
+
+        match opcode {
+            AXIS => subject.axis(&argument).map(Step::Return),
+            LITERAL => Ok(Step::Return(argument)),
+            RECURSE => {
+                let (b, c) = argument.into_cell().ok_or(EvalError::BadRecurseArgument)?;
+                stack.push(Continuation::RecurseFormula(subject.clone(), c));
+                Ok(Step::Eval(subject, b))
+            }
+            IF => {
+                let (b, c, d) = triple_arg(argument)?;
+                stack.push(Continuation::Branch(subject.clone(), c, d));
+                Ok(Step::Eval(subject, b))
+            }
+            COMPOSE => {
+                let (b, c) = argument.into_cell().ok_or(EvalError::BadArgument)?;
+                stack.push(Continuation::Compose(c));
+                Ok(Step::Eval(subject, b))
+            }
+            DEFINE => {
+                let (b, c) = argument.into_cell().ok_or(EvalError::BadArgument)?;
+                stack.push(Continuation::Define(subject.clone(), c));
+                Ok(Step::Eval(subject, b))
             }
+            CALL => {
+                let (b, c) = argument.into_cell().ok_or(EvalError::BadArgument)?;
+                stack.push(Continuation::Call(b));
+                Ok(Step::Eval(subject, c))
+            }
+            RETRIEVE_BY_HASH | RETRIEVE_BY_KEY | EXUCRYPT => {
+                // These go on to evaluate something else against the same subject.
+                stack.push(Continuation::Apply(opcode, Some(subject.clone())));
+                Ok(Step::Eval(subject, argument))
+            }
+            IS_CELL | IS_EQUAL | HASH | STORE_BY_HASH | STORE_BY_KEY | RANDOM | RESHAPE | SHAPE
+                | ADD | LESS | XOR | INVERT | GENERATE_KEYPAIR | DECRYPT | ENCRYPT | SEND
+                | EXECUTE_AS | START_NEIGHBORING | NEIGHBORS_NEAR => {
+                stack.push(Continuation::Apply(opcode, None));
+                Ok(Step::Eval(subject, argument))
+            }
+            _ => Err(EvalError::BadOpcode(opcode)),
+        }
+    }
 
-            let opcode = opcode_noun.as_u8().ok_or(EvalError::NotAnOpcode)?;
+    /// Carry on with `continuation` now that the evaluation it was waiting on produced `value`.
+    fn resume(&mut self, continuation: Continuation, value: Noun, stack: &mut Vec<Continuation>) -> Result<Step, EvalError> {
+        match continuation {
+            Continuation::DistributeRight(subject, formula) => {
+                stack.push(Continuation::DistributeJoin(value));
+                Ok(Step::Eval(subject, formula))
+            }
+            Continuation::DistributeJoin(lhs) => Ok(Step::Return(self.new_cell(lhs, value)?)),
+            Continuation::RecurseFormula(subject, c) => {
+                stack.push(Continuation::RecurseInto(value));
+                Ok(Step::Eval(subject, c))
+            }
+            Continuation::RecurseInto(b_result) => Ok(Step::Eval(b_result, value)),
+            Continuation::Branch(subject, c, d) => match value.as_u8() {
+                Some(1) => Ok(Step::Eval(subject, c)),
+                Some(0) => Ok(Step::Eval(subject, d)),
+                _ => Err(EvalError::BadIfCondition),
+            },
+            Continuation::Compose(c) => Ok(Step::Eval(value, c)),
+            Continuation::Define(subject, c) => Ok(Step::Eval(self.new_cell(value, subject)?, c)),
+            Continuation::Call(b) => {
+                let inner_formula = value.axis(&b)?;
+                Ok(Step::Eval(value, inner_formula))
+            }
+            Continuation::Found => Ok(Step::Return(self.new_cell(Noun::from_bool(true), value)?)),
+            Continuation::EncryptResult(private_key) => {
+                let ciphertext = self.encrypt(&private_key, &value)?;
+                Ok(Step::Return(self.new_cell(Noun::from_bool(true), ciphertext)?))
+            }
+            Continuation::RestoreRunner(suspended) => {
+                self.restore_runner(suspended)?;
+                Ok(Step::Return(value))
+            }
+            Continuation::Apply(opcode, subject) => self.apply(opcode, subject, value, stack),
+        }
+    }
 
-            return match opcode {
-                AXIS => subject.axis(&argument),
-                LITERAL => Ok(argument),
-                RECURSE => {
-                    if let Some((b, c)) = argument.into_cell() {
-                        let b_result = self.eval_on(subject.clone(), b)?;
-                        let c_result = self.eval_on(subject, c)?;
-                        subject = b_result;
-                        formula = c_result;
-                        continue 'tail_recurse;
-                    } else {
-                        Err(EvalError::BadRecurseArgument)
-                    }
-                }
-                IS_CELL => {
-                    // cell test
-                    Ok(Noun::from_bool(self.eval_on(subject, argument)?.is_cell()))
-                }
-                IS_EQUAL => {
-                    if let Some((lhs, rhs)) = self.eval_on(subject, argument)?.as_cell() {
-                        Ok(Noun::from_bool(equal(lhs, rhs, &mut self.ticks_remaining)?))
-                    } else {
-                        Err(EvalError::BadEqualsArgument)
-                    }
-                }
-                IF => {
-                    let (b, c, d) = triple_arg(argument)?;
-                    let condition = self.eval_on(subject.clone(), b)?;
-                    match condition.as_u8() {
-                        Some(1) => {
-                            formula = c;
-                            continue 'tail_recurse;
-                        }
-                        Some(0) => {
-                            formula = d;
-                            continue 'tail_recurse;
-                        }
-                        _ => Err(EvalError::BadIfCondition),
-                    }
-                }
-                COMPOSE => {
-                    if let Some((b, c)) = argument.into_cell() {
-                        let b_of_x = self.eval_on(subject, b)?;
-                        subject = b_of_x;
-                        formula = c;
-                        continue 'tail_recurse;
-                    } else {
-                        Err(EvalError::BadArgument)
-                    }
-                }
-                DEFINE => {
-                    if let Some((b, c)) = argument.into_cell() {
-                        let subject_prime = self.eval_on(subject.clone(), b)?;
-                        subject = Noun::new_cell(subject_prime, subject);
-                        formula = c;
-                        continue 'tail_recurse;
-                    } else {
-                        Err(EvalError::BadArgument)
-                    }
-                }
-                CALL => {
-                    if let Some((b, c)) = argument.into_cell() {
-                        let core = self.eval_on(subject, c)?;
-                        let inner_formula = core.axis(&b)?;
-                        subject = core;
-                        formula = inner_formula;
-                        continue 'tail_recurse;
-                    } else {
-                        Err(EvalError::BadArgument)
-                    }
-                }
-                HASH => {
-                    // hash
-                    let hash_target = self.eval_on(subject, argument)?;
-                    let buffer = self.serialize(&hash_target)?;
-                    self.ticks_remaining.incur(20 + (buffer.len() as u64))?;
-                    let mut result = [0u8; 64];
-                    Blake2b::blake2b(&mut result[..], &buffer, &[][..]);
-                    Ok(Noun::from_slice(&result[..]))
-                }
-                STORE_BY_HASH => {
-                    // store by hash
-                    let hash_target = self.eval_on(subject, argument)?;
-                    let buffer = self.serialize(&hash_target)?;
-                    self.ticks_remaining.incur(20 + (buffer.len() as u64))?;
-                    let mut result = [0u8; 64 + 1];
-                    result[64] = 1;
-                    Blake2b::blake2b(&mut result[..64], &buffer, &[][..]);
-                    self.side_effector.store(&result[..], &buffer[..]);
-                    Ok(Noun::from_bool(true)) // TODO: It might be better to return the hash
-                }
-                RETRIEVE_BY_HASH => {
-                    // retrieve by hash
-                    let hash = self.eval_on(subject.clone(), argument)?;
-                    if let Some(hash_bytes) = hash.into_vec() {
-                        self.retrieve_with_tag(subject, hash_bytes, 1)
-                    } else {
-                        Ok(Noun::from_bool(false))
-                    }
-                }
-                STORE_BY_KEY => {
-                    if let Some((key, value)) = self.eval_on(subject, argument)?.into_cell() {
-                        let mut storage_key = self.serialize(&key)?;
-                        storage_key.push(0);
-                        let storage_value = self.serialize(&value)?;
-                        self.side_effector
-                            .store(&storage_key[..], &storage_value[..]);
-                        Ok(Noun::from_bool(true))
-                    } else {
-                        Err(EvalError::BadArgument)
-                    }
-                }
-                RETRIEVE_BY_KEY => {
-                    let key = self.eval_on(subject.clone(), argument)?;
-                    let key_bytes = self.serialize(&key)?;
-                    self.retrieve_with_tag(subject, key_bytes, 0)
-                }
-                RANDOM => {
-                    let length = self
-                        .eval_on(subject, argument)?
-                        .as_usize()
-                        .ok_or(EvalError::InvalidLength)?;
-                    if length > 1_000_000 {
-                        return Err(EvalError::InvalidLength);
-                    }
-                    let mut xs = vec![0u8; length];
-                    self.side_effector.random(&mut xs);
-                    Ok(Noun::from_vec(xs))
+    /// Finish an opcode whose argument has been evaluated to `argument`. `subject` is kept only
+    /// for the opcodes that go on to evaluate something against it.
+    fn apply(&mut self, opcode: u8, subject: Option<Noun>, argument: Noun, stack: &mut Vec<Continuation>) -> Result<Step, EvalError> {
+        match opcode {
+            IS_CELL => {
+                // cell test
+                Ok(Noun::from_bool(argument.is_cell()))
+            }
+            IS_EQUAL => {
+                if let Some((lhs, rhs)) = argument.as_cell() {
+                    Ok(Noun::from_bool(equal(lhs, rhs, &mut self.ticks_remaining, self.costs.compare())?))
+                } else {
+                    Err(EvalError::BadEqualsArgument)
                 }
-                RESHAPE => {
-                    if let Some((data, structure)) = self.eval_on(subject, argument)?.into_cell() {
-                        reshape(&data, &structure, &mut self.ticks_remaining, 10_000_000)
-                            .map_err(|_| EvalError::BadShape)
-                    } else {
-                        Err(EvalError::BadArgument)
-                    }
+            }
+            HASH => {
+                // hash
+                let hash_target = argument;
+                let buffer = self.serialize(&hash_target)?;
+                self.ticks_remaining.incur(self.costs.hash(buffer.len()))?;
+                let mut result = [0u8; 64];
+                Blake2b::blake2b(&mut result[..], &buffer, &[][..]);
+                self.new_atom(&result[..])
+            }
+            STORE_BY_HASH => {
+                // store by hash
+                let hash_target = argument;
+                let buffer = self.serialize(&hash_target)?;
+                self.ticks_remaining.incur(self.costs.hash(buffer.len()))?;
+                let mut result = [0u8; 64 + 1];
+                result[64] = 1;
+                Blake2b::blake2b(&mut result[..64], &buffer, &[][..]);
+                self.ticks_remaining.incur(self.costs.store(result.len(), buffer.len()))?;
+                self.side_effector.store(&result[..], &buffer[..]);
+                self.report.bytes_stored += (result.len() + buffer.len()) as u64;
+                Ok(Noun::from_bool(true)) // TODO: It might be better to return the hash
+            }
+            RETRIEVE_BY_HASH => {
+                // retrieve by hash
+                let hash = argument;
+                if let Some(hash_bytes) = hash.into_vec() {
+                    return self.retrieve_with_tag(subject.unwrap(), hash_bytes, 1, stack);
+                } else {
+                    Ok(Noun::from_bool(false))
                 }
-                SHAPE => {
-                    let data = self.eval_on(subject, argument)?;
-                    Ok(length(&data, &mut self.ticks_remaining)?)
+            }
+            STORE_BY_KEY => {
+                if let Some((key, value)) = argument.into_cell() {
+                    let mut storage_key = self.serialize(&key)?;
+                    storage_key.push(0);
+                    let storage_value = self.serialize(&value)?;
+                    self.ticks_remaining.incur(self.costs.store(storage_key.len(), storage_value.len()))?;
+                    self.side_effector
+                        .store(&storage_key[..], &storage_value[..]);
+                    self.report.bytes_stored += (storage_key.len() + storage_value.len()) as u64;
+                    Ok(Noun::from_bool(true))
+                } else {
+                    Err(EvalError::BadArgument)
                 }
-                ADD => {
-                    if let Some((lhs, rhs)) = self.eval_on(subject, argument)?.into_cell() {
-                        self.ticks_remaining.incur(max(lhs.atom_len().unwrap_or(0), rhs.atom_len().unwrap_or(0)) as u64)?;
-                        add(&lhs, &rhs).ok_or(EvalError::NonAtomicMath)
-                    } else {
-                        Err(EvalError::BadArgument)
-                    }
+            }
+            RETRIEVE_BY_KEY => {
+                let key = argument;
+                let key_bytes = self.serialize(&key)?;
+                return self.retrieve_with_tag(subject.unwrap(), key_bytes, 0, stack);
+            }
+            RANDOM => {
+                let length = argument
+                    .as_usize()
+                    .ok_or(EvalError::InvalidLength)?;
+                if length > 1_000_000 {
+                    return Err(EvalError::InvalidLength);
                 }
-                LESS => {
-                    if let Some((lhs, rhs)) = self.eval_on(subject, argument)?.into_cell() {
-                        self.ticks_remaining.incur(max(lhs.atom_len().unwrap_or(0), rhs.atom_len().unwrap_or(0)) as u64)?;
-                        less(&lhs, &rhs).map(Noun::from_bool).ok_or(EvalError::NonAtomicMath)
-                    } else {
-                        Err(EvalError::BadArgument)
-                    }
+                self.ticks_remaining.incur(self.costs.random(length))?;
+                self.allocate(length as u64)?;
+                let mut xs = vec![0u8; length];
+                self.side_effector.random(&mut xs);
+                Ok(Noun::from_vec(xs))
+            }
+            RESHAPE => {
+                if let Some((data, structure)) = argument.into_cell() {
+                    reshape(&data, &structure, &mut self.ticks_remaining, self.costs.reshape_byte(), &mut self.memory_remaining)
+                        .map_err(|e| match e {
+                            ShapeError::AllocationBoundExceeded => EvalError::MemoryExceeded,
+                            ShapeError::DataTooShort => EvalError::BadShape,
+                        })
+                } else {
+                    Err(EvalError::BadArgument)
                 }
-                XOR => {
-                    if let Some((lhs, rhs)) = self.eval_on(subject, argument)?.into_cell() {
-                        self.ticks_remaining.incur(max(lhs.atom_len().unwrap_or(0), rhs.atom_len().unwrap_or(0)) as u64)?;
-                        xor(&lhs, &rhs).ok_or(EvalError::NonAtomicMath)
-                    } else {
-                        Err(EvalError::BadArgument)
-                    }
+            }
+            SHAPE => {
+                let data = argument;
+                let shape = length(&data, &mut self.ticks_remaining, self.costs.measure())?;
+                self.allocated(&shape)?;
+                Ok(shape)
+            }
+            ADD => {
+                if let Some((lhs, rhs)) = argument.into_cell() {
+                    self.ticks_remaining.incur(self.costs.arithmetic(max(lhs.atom_len().unwrap_or(0), rhs.atom_len().unwrap_or(0))))?;
+                    let sum = add(&lhs, &rhs).ok_or(EvalError::NonAtomicMath)?;
+                    self.allocated(&sum)?;
+                    Ok(sum)
+                } else {
+                    Err(EvalError::BadArgument)
                 }
-                INVERT => {
-                    let data = self.eval_on(subject, argument)?;
-                    self.ticks_remaining.incur(data.atom_len().unwrap_or(0) as u64)?;
-                    invert(&data).ok_or(EvalError::NonAtomicMath)
+            }
+            LESS => {
+                if let Some((lhs, rhs)) = argument.into_cell() {
+                    self.ticks_remaining.incur(self.costs.arithmetic(max(lhs.atom_len().unwrap_or(0), rhs.atom_len().unwrap_or(0))))?;
+                    less(&lhs, &rhs).map(Noun::from_bool).ok_or(EvalError::NonAtomicMath)
+                } else {
+                    Err(EvalError::BadArgument)
                 }
-                GENERATE_KEYPAIR => {
-                    let provided_seed = self.eval_on(subject, argument)?;
-                    let mut random_seed = vec![0u8; 32];
-                    self.side_effector.random(&mut random_seed[..]);
-                    let public = Noun::new_cell(provided_seed, Noun::from_vec(random_seed));
-                    let private =
-                        Noun::from_slice(&self.private_symmetric_key_for(&public, false)?[..]);
-                    Ok(Noun::new_cell(private, public))
+            }
+            XOR => {
+                if let Some((lhs, rhs)) = argument.into_cell() {
+                    self.ticks_remaining.incur(self.costs.arithmetic(max(lhs.atom_len().unwrap_or(0), rhs.atom_len().unwrap_or(0))))?;
+                    let result = xor(&lhs, &rhs).ok_or(EvalError::NonAtomicMath)?;
+                    self.allocated(&result)?;
+                    Ok(result)
+                } else {
+                    Err(EvalError::BadArgument)
                 }
-                DECRYPT => {
-                    let (private_key, ciphertext) = double_arg(self.eval_on(subject, argument)?)?;
-
-                    if let Some(plaintext) =
-                        self.decrypt(&key_arg(&private_key)?, bytes_arg(&ciphertext)?)?
-                    {
-                        Ok(Noun::new_cell(Noun::from_bool(true), plaintext))
-                    } else {
-                        Ok(Noun::from_bool(false))
-                    }
+            }
+            INVERT => {
+                let data = argument;
+                self.ticks_remaining.incur(self.costs.arithmetic(data.atom_len().unwrap_or(0)))?;
+                let result = invert(&data).ok_or(EvalError::NonAtomicMath)?;
+                self.allocated(&result)?;
+                Ok(result)
+            }
+            GENERATE_KEYPAIR => {
+                let provided_seed = argument;
+                let mut random_seed = [0u8; 32];
+                self.ticks_remaining.incur(self.costs.random(random_seed.len()))?;
+                self.side_effector.random(&mut random_seed[..]);
+                let random_seed = self.new_atom(&random_seed[..])?;
+                let public = self.new_cell(provided_seed, random_seed)?;
+                let private = self.private_symmetric_key_for(&public)?;
+                let private = self.new_atom(&private[..])?;
+                self.new_cell(private, public)
+            }
+            DECRYPT => {
+                let (private_key, ciphertext) = double_arg(argument)?;
+
+                if let Some(plaintext) =
+                    self.decrypt(&key_arg(&private_key)?, bytes_arg(&ciphertext)?)?
+                {
+                    self.new_cell(Noun::from_bool(true), plaintext)
+                } else {
+                    Ok(Noun::from_bool(false))
                 }
-                ENCRYPT => {
-                    let (private_key, plaintext) = double_arg(self.eval_on(subject, argument)?)?;
-                    self.encrypt(&key_arg(&private_key)?, &plaintext)
+            }
+            ENCRYPT => {
+                let (private_key, plaintext) = double_arg(argument)?;
+                self.encrypt(&key_arg(&private_key)?, &plaintext)
+            }
+            EXUCRYPT => {
+                let (public_key, request_ciphertext) = double_arg(argument)?;
+                let private_key = self.private_symmetric_key_for(&public_key)?;
+
+                // Decryption
+                let program = if let Some(program) =
+                    self.decrypt(&private_key, bytes_arg(&request_ciphertext)?)?
+                {
+                    program
+                } else {
+                    return Ok(Step::Return(Noun::from_bool(false)));
+                };
+
+                // Evaluation, after which the result is encrypted
+                stack.push(Continuation::EncryptResult(private_key));
+                return Ok(Step::Eval(subject.unwrap(), program));
+            }
+            SEND => {
+                let (recipient, message) = double_arg(argument)?;
+                let recipient = key_arg(&recipient)?;
+                let message = self.serialize(&message)?;
+                let local_cost = self.costs.send(message.len());
+                self.ticks_remaining.incur(local_cost)?;
+                let sent = self.side_effector.send(&recipient, &message, local_cost);
+                if sent {
+                    self.report.messages_sent += 1;
+                    self.report.bytes_sent += message.len() as u64;
                 }
-                EXUCRYPT => {
-                    let (public_key, request_ciphertext) =
-                        double_arg(self.eval_on(subject.clone(), argument)?)?;
-                    let private_key = self.private_symmetric_key_for(&public_key, false)?;
-
-                    // Decryption
-                    let program = if let Some(program) =
-                        self.decrypt(&private_key, bytes_arg(&request_ciphertext)?)?
-                    {
-                        program
-                    } else {
-                        return Ok(Noun::from_bool(false));
-                    };
-
-                    // Evaluation
-                    let result = self.eval_on(subject, program)?;
-
-                    Ok(Noun::new_cell(
-                        Noun::from_bool(true),
-                        self.encrypt(&private_key, &result)?,
-                    ))
+                Ok(Noun::from_bool(sent))
+            }
+            EXECUTE_AS => {
+                let (new_subject, runner_public_key, counter_and_body) = triple_arg(argument)?;
+                let runner_public_key_bytes = key_arg(&runner_public_key)?;
+                let (counter, body_ciphertext) = double_arg(counter_and_body)?;
+                let body_ciphertext = bytes_arg(&body_ciphertext)?;
+                let counter: &[u8; 8] = bytes_arg(&counter)?.try_into().map_err(|_| EvalError::BadExecuteAsCounter)?;
+
+                let runner_private_key = self.private_symmetric_key_for(&runner_public_key)?;
+                let body = self.decrypt(&runner_private_key, &body_ciphertext)?.ok_or(EvalError::BadExecuteAsBody)?;
+
+                if !self.side_effector.consume_counter(counter, &runner_public_key_bytes) {
+                    return Err(EvalError::BadExecuteAsCounter);
                 }
-                SEND => {
-                    let (recipient, message) = double_arg(self.eval_on(subject, argument)?)?;
-                    let recipient = key_arg(&recipient)?;
-                    let message = self.serialize(&message)?;
-                    self.side_effector.send(&recipient, &message, 0);
-                    Ok(Noun::from_u8(0)) // Is there anything that SEND should return? 
+
+                let suspended = self.switch_to_runner(&runner_public_key_bytes)?;
+                stack.push(Continuation::RestoreRunner(suspended));
+                return Ok(Step::Eval(new_subject, body));
+            }
+            //SET_REPLY_ADDRESS => {
+            //    self.side_effector.set_reply_address(&self.executing_as);
+            //    Ok(Noun::from_bool(true))
+            //}
+            START_NEIGHBORING => {
+                let (identity, address, port) = triple_arg(argument)?;
+                let identity = key_arg(&identity)?;
+                let address = address_arg(&address)?;
+                let port = port_arg(&port)?;
+                self.ticks_remaining.incur(self.costs.start_neighboring())?;
+                Ok(Noun::from_bool(self.side_effector.start_neighboring(&identity, &address, port)))
+            }
+            NEIGHBORS_NEAR => {
+                let (near, count) = double_arg(argument)?;
+                let near = key_arg(&near)?;
+                let count = count.as_usize().ok_or(EvalError::InvalidLength)?;
+                if count > NEIGHBORS_NEAR_MAX {
+                    return Err(EvalError::InvalidLength);
                 }
-                EXECUTE_AS => {
-                    let (new_subject, runner_public_key, counter_and_body) = triple_arg(self.eval_on(subject, argument)?)?;
-                    let runner_public_key_bytes = key_arg(&runner_public_key)?;
-                    let (counter, body_ciphertext) = double_arg(counter_and_body)?;
-                    let body_ciphertext = bytes_arg(&body_ciphertext)?;
-                    let counter: &[u8; 8] = bytes_arg(&counter)?.try_into().map_err(|_| EvalError::BadExecuteAsCounter)?;
-
-                    let runner_private_key = self.private_symmetric_key_for(&runner_public_key, false)?;
-                    let body = self.decrypt(&runner_private_key, &body_ciphertext)?.ok_or(EvalError::BadExecuteAsBody)?;
-
-                    if !self.side_effector.consume_counter(counter, &runner_public_key_bytes) {
-                        return Err(EvalError::BadExecuteAsCounter);
-                    }
 
-                    let old_runner = self.switch_to_runner(&runner_public_key_bytes);
-                    let ret = self.eval_on(new_subject, body);
-                    self.switch_to_runner(&old_runner);
-                    ret
+                let neighbors = self.side_effector.nearest_neighbors(&near, count);
+                let mut list = Noun::from_u8(0);
+                for neighbor in neighbors.iter().take(count).rev() {
+                    self.ticks_remaining.incur(self.costs.list_neighbor())?;
+                    let neighbor = self.new_atom(&neighbor[..])?;
+                    list = self.new_cell(neighbor, list)?;
                 }
-                //SET_REPLY_ADDRESS => {
-                //    self.side_effector.set_reply_address(&self.executing_as);
-                //    Ok(Noun::from_bool(true))
-                //}
-                //START_NEIGHBORING => {
-                //}
-                //NEIGHBORS_NEAR => {
-                //}
-                _ => Err(EvalError::BadOpcode(opcode)),
-            };
-        }
+                Ok(list)
+            }
+            _ => Err(EvalError::BadOpcode(opcode)),
+        }.map(Step::Return)
     }
 
+    /// Serialize `noun`, charging the memory budget for the buffer.
     fn serialize(&mut self, noun: &Noun) -> Result<Vec<u8>, EvalError> {
-        match serialize::serialize(noun, 1_000_000) {
-            Ok(x) => Ok(x),
+        let maximum_length = min(MAX_SERIALIZED_LEN as u64, self.memory_remaining.remaining()) as usize;
+        match serialize::serialize(noun, maximum_length) {
+            Ok(x) => {
+                self.allocate(x.len() as u64)?;
+                Ok(x)
+            }
             Err(SerializationError::OverlongAtom) => Err(EvalError::BadArgument),
             Err(SerializationError::MaximumLengthExceeded) => Err(EvalError::MemoryExceeded),
         }
     }
 }
 
-pub fn eval<S: SideEffectEngine>(
+/// Evaluate `expression`, a cell of subject and formula, pricing its work with `costs`.
+/// Evaluation fails once it has taken `tick_limit` ticks, or allocated `memory_limit` bytes.
+pub fn eval<S: SideEffectEngine, C: CostModel + ?Sized>(
     expression: Noun,
     side_effector: &mut S,
+    costs: &C,
     tick_limit: u64,
+    memory_limit: u64,
+) -> EvalResult {
+    eval_metered(expression, side_effector, costs, &mut Ticks::new(tick_limit), &mut Ticks::new(memory_limit))
+}
+
+/// Like `eval`, but spends from `ticks` and `memory`, so the caller can see how much of each
+/// was consumed.
+pub fn eval_metered<S: SideEffectEngine, C: CostModel + ?Sized>(
+    expression: Noun,
+    side_effector: &mut S,
+    costs: &C,
+    ticks: &mut Ticks,
+    memory: &mut Ticks,
 ) -> EvalResult {
+    eval_with_report(expression, side_effector, costs, ticks, memory).0
+}
+
+/// Like `eval_metered`, but also reports what the evaluation consumed, with ticks broken down by
+/// who spent them. All of them come out of `ticks`, EXECUTE_AS runners' included.
+pub fn eval_with_report<S: SideEffectEngine, C: CostModel + ?Sized>(
+    expression: Noun,
+    side_effector: &mut S,
+    costs: &C,
+    ticks: &mut Ticks,
+    memory: &mut Ticks,
+) -> (EvalResult, ExecutionReport) {
     if let Some((subject, formula)) = expression.into_cell() {
-        Computation {
-            ticks_remaining: Ticks::new(tick_limit),
+        let mut computation = Computation {
+            costs: costs,
+            ticks_remaining: ticks.clone(),
+            attributed: 0,
+            memory_remaining: memory.clone(),
             side_effector: side_effector,
-            executing_as: [0u8; 32],
-            ticks_for: HashMap::new(),  
-        }
-        .eval_on(subject, formula)
+            executing_as: None,
+            ticks_for: HashMap::new(),
+            report: ExecutionReport::default(),
+        };
+        let result = computation.eval_on(subject, formula);
+
+        // Every runner has been switched back from, so this is the requestor, who cannot
+        // overspend an allowance of their own.
+        let _ = computation.attribute();
+        *ticks = computation.ticks_remaining;
+        *memory = computation.memory_remaining;
+        (result, computation.report)
     } else {
-        Err(EvalError::EvalOnAtom)
+        (Err(EvalError::EvalOnAtom), ExecutionReport::default())
     }
 }
 
 struct TestSideEffectEngine {
     storage: HashMap<Vec<u8>, Vec<u8>>,
     rng: ChaCha,
+    neighbors: Vec<[u8; 32]>,
+    neighboring_requests: Vec<([u8; 32], [u8; 16], u16)>,
+    sent: Vec<([u8; 32], Vec<u8>, u64)>,
 }
 
 impl TestSideEffectEngine {
@@ -525,13 +959,19 @@ impl TestSideEffectEngine {
 	TestSideEffectEngine {
 	    storage: HashMap::new(),
 	    rng: ChaCha::new_chacha20(&[1u8; 32], &[0u8; 8]),
+	    neighbors: Vec::new(),
+	    neighboring_requests: Vec::new(),
+	    sent: Vec::new(),
 	}
     }
 }
 
 impl SideEffectEngine for TestSideEffectEngine {
-    fn nearest_neighbor(&mut self, _near: &[u8; 32]) -> [u8; 32] {
-	[0u8; 32]
+    fn nearest_neighbors(&mut self, near: &[u8; 32], count: usize) -> Vec<[u8; 32]> {
+        let mut neighbors = self.neighbors.clone();
+        neighbors.sort_by_key(|neighbor| xor_distance(neighbor, near));
+        neighbors.truncate(count);
+        neighbors
     }
     fn random(&mut self, dest: &mut [u8]) {
 	for b in dest.iter_mut() {
@@ -548,7 +988,13 @@ impl SideEffectEngine for TestSideEffectEngine {
     fn store(&mut self, key: &[u8], value: &[u8]) {
 	self.storage.insert(key.into(), value.into());
     }
-    fn send(&mut self, _destination: &[u8; 32], _message: &[u8], _local_cost: u64) {}
+    fn send(&mut self, destination: &[u8; 32], message: &[u8], local_cost: u64) -> bool {
+        if !self.neighbors.contains(destination) {
+            return false;
+        }
+        self.sent.push((*destination, message.to_vec(), local_cost));
+        true
+    }
     fn secret(&self) -> &[u8; 32] {
 	b"this is a thirty-two byte secret"
     }
@@ -558,11 +1004,18 @@ impl SideEffectEngine for TestSideEffectEngine {
     fn consume_counter(&mut self, _counter: &[u8; 8], _private_key: &[u8; 32]) -> bool {
         true
     }
+    fn start_neighboring(&mut self, identity: &[u8; 32], address: &[u8; 16], port: u16) -> bool {
+        if self.neighbors.contains(identity) {
+            return false;
+        }
+        self.neighboring_requests.push((*identity, *address, port));
+        true
+    }
 }
 
 pub fn eval_simple<E: AsNoun>(expression: E) -> Noun {
     let mut engine = TestSideEffectEngine::new();
-    eval(expression.as_noun(), &mut engine, 1000000)
+    eval(expression.as_noun(), &mut engine, &DefaultCostModel, 1000000, 10_000_000)
 	.expect("eval_simple expression got an error")
 }
 
@@ -573,7 +1026,7 @@ fn expect_eval_with<E: AsNoun, R: AsNoun>(
     result: R,
 ) {
     assert_eq!(
-	eval(expression.as_noun(), engine, 1000000),
+	eval(expression.as_noun(), engine, &DefaultCostModel, 1000000, 10_000_000),
 	Ok(result.as_noun())
     );
 }
@@ -589,7 +1042,10 @@ fn expect_eval<E: AsNoun, R: AsNoun>(expression: E, result: R) -> TestSideEffect
 pub mod test {
     use as_noun::AsNoun;
     use crypto::blake2b::Blake2b;
-    use eval::{expect_eval, eval_simple, expect_eval_with};
+    use cost::{CostModel, DefaultCostModel};
+    use eval::{eval, eval_metered, eval_with_report, expect_eval, eval_simple, expect_eval_with, EvalError, ExecutionReport, TestSideEffectEngine, CELL_BYTES};
+    use std::collections::HashMap;
+    use ticks::Ticks;
     use noun::Noun;
     use opcode::*;
     use serialize;
@@ -814,6 +1270,161 @@ pub mod test {
         expect_eval((iterate_hash(42), runner.clone()), &b"correct"[..]);
     }
 
+    #[test]
+    fn neighbors_near() {
+        let mut engine = TestSideEffectEngine::new();
+        engine.neighbors = vec![[0x80; 32], [0x01; 32], [0x03; 32], [0x40; 32]];
+        expect_eval_with(
+            &mut engine,
+            ([0x02u8; 32].to_vec(), NEIGHBORS_NEAR, (AXIS, 1), (LITERAL, 3)),
+            ([0x03u8; 32].to_vec(), [0x01u8; 32].to_vec(), [0x40u8; 32].to_vec(), 0),
+        );
+        expect_eval_with(
+            &mut engine,
+            ([0x02u8; 32].to_vec(), NEIGHBORS_NEAR, (AXIS, 1), (LITERAL, 0)),
+            0,
+        );
+    }
+
+    #[test]
+    fn neighbors_near_none_known() {
+        expect_eval(([0x02u8; 32].to_vec(), NEIGHBORS_NEAR, (AXIS, 1), (LITERAL, 5)), 0);
+    }
+
+    #[test]
+    fn start_neighboring() {
+        let mut engine = TestSideEffectEngine::new();
+        engine.neighbors = vec![[0x80; 32]];
+        let address = [0x22u8; 16];
+        expect_eval_with(
+            &mut engine,
+            ([0x11u8; 32].to_vec(), START_NEIGHBORING, (AXIS, 1), (LITERAL, address.to_vec()), (LITERAL, &[0x88, 0x13][..])),
+            true,
+        );
+        assert_eq!(engine.neighboring_requests, vec![([0x11u8; 32], address, 5000)]);
+
+        // Already a neighbor, so the engine declines.
+        expect_eval_with(
+            &mut engine,
+            ([0x80u8; 32].to_vec(), START_NEIGHBORING, (AXIS, 1), (LITERAL, address.to_vec()), (LITERAL, 7)),
+            false,
+        );
+        assert_eq!(engine.neighboring_requests.len(), 1);
+    }
+
+    #[test]
+    fn send() {
+        let mut engine = TestSideEffectEngine::new();
+        engine.neighbors = vec![[0x80; 32]];
+        expect_eval_with(
+            &mut engine,
+            ([0x80u8; 32].to_vec(), SEND, (AXIS, 1), (LITERAL, (5, 6))),
+            true,
+        );
+        let message = serialize::serialize(&(5, 6).as_noun(), 100).unwrap();
+        assert_eq!(engine.sent, vec![([0x80u8; 32], message.clone(), 100 + message.len() as u64)]);
+
+        // Not a neighbor, so the message cannot be delivered.
+        expect_eval_with(
+            &mut engine,
+            ([0x81u8; 32].to_vec(), SEND, (AXIS, 1), (LITERAL, (5, 6))),
+            false,
+        );
+        assert_eq!(engine.sent.len(), 1);
+    }
+
+    #[test]
+    fn send_charges_ticks() {
+        let mut engine = TestSideEffectEngine::new();
+        engine.neighbors = vec![[0x80; 32]];
+        assert_eq!(
+            eval(([0x80u8; 32].to_vec(), SEND, (AXIS, 1), (LITERAL, 5)).as_noun(), &mut engine, &DefaultCostModel, 100, 10_000_000),
+            Err(EvalError::TickLimitExceeded)
+        );
+        assert!(engine.sent.is_empty());
+    }
+
+    #[test]
+    fn metered() {
+        let mut engine = TestSideEffectEngine::new();
+        let mut ticks = Ticks::new(1000);
+        let mut memory = Ticks::new(1000);
+        assert_eq!(
+            eval_metered((5, (LITERAL, 6), (AXIS, 1)).as_noun(), &mut engine, &DefaultCostModel, &mut ticks, &mut memory),
+            Ok((6, 5).as_noun())
+        );
+        assert_eq!(ticks.get_consumed(), 3);
+        assert_eq!(memory.get_consumed(), CELL_BYTES);
+    }
+
+    #[test]
+    fn send_limited_to_network() {
+        // Plenty of memory, but the message would be too long for the recipient to take.
+        let mut engine = TestSideEffectEngine::new();
+        engine.neighbors = vec![[0x80; 32]];
+        // The longest atom RANDOM makes, 1,000,000 bytes, which serializes to a little more.
+        let message = (RANDOM, LITERAL, &[0x40, 0x42, 0x0f][..]);
+        assert_eq!(
+            eval(([0x80u8; 32].to_vec(), SEND, (AXIS, 1), message).as_noun(), &mut engine, &DefaultCostModel, 10_000_000, 10_000_000),
+            Err(EvalError::MemoryExceeded)
+        );
+        assert!(engine.sent.is_empty());
+    }
+
+    #[test]
+    fn memory_limit() {
+        let mut engine = TestSideEffectEngine::new();
+        assert_eq!(
+            eval((0, RANDOM, LITERAL, &[0xe8, 0x03][..]).as_noun(), &mut engine, &DefaultCostModel, 10_000, 999),
+            Err(EvalError::MemoryExceeded)
+        );
+        assert_eq!(
+            eval((0, RANDOM, LITERAL, &[0xe8, 0x03][..]).as_noun(), &mut engine, &DefaultCostModel, 10_000, 1000).map(|xs| xs.atom_len()),
+            Ok(Some(1000))
+        );
+
+        // Cells built by distribution count too.
+        let pairs = (0, (LITERAL, 1), (LITERAL, 2), (LITERAL, 3)).as_noun();
+        assert_eq!(eval(pairs.clone(), &mut engine, &DefaultCostModel, 1000, 2 * CELL_BYTES), Ok((1, 2, 3).as_noun()));
+        assert_eq!(eval(pairs, &mut engine, &DefaultCostModel, 1000, 2 * CELL_BYTES - 1), Err(EvalError::MemoryExceeded));
+
+        // As do the nouns RESHAPE builds.
+        assert_eq!(
+            eval((&[1, 2, 3, 4][..], RESHAPE, (AXIS, 1), (LITERAL, 2, 2)).as_noun(), &mut engine, &DefaultCostModel, 1000, 10),
+            Err(EvalError::MemoryExceeded)
+        );
+    }
+
+    #[test]
+    fn start_neighboring_bad_port() {
+        let mut engine = TestSideEffectEngine::new();
+        assert_eq!(
+            eval(
+                ([0x11u8; 32].to_vec(), START_NEIGHBORING, (AXIS, 1), (LITERAL, [0x22u8; 16].to_vec()), (LITERAL, &[0, 0, 1][..])).as_noun(),
+                &mut engine,
+                &DefaultCostModel,
+                1000000,
+                10_000_000
+            ),
+            Err(EvalError::BadArgument)
+        );
+        assert!(engine.neighboring_requests.is_empty());
+    }
+
+    #[test]
+    fn error_encoding() {
+        for error in [EvalError::Something, EvalError::BadOpcode(0x42), EvalError::TickLimitExceeded, EvalError::BadExecuteAsCounter, EvalError::MalformedProgram].iter() {
+            assert_eq!(EvalError::decode(error.encode()).as_ref(), Some(error));
+        }
+        for code in 0..21 {
+            if code != 5 {
+                assert_eq!(EvalError::decode([code, 0]).map(|error| error.encode()), Some([code, 0]));
+            }
+        }
+        assert_eq!(EvalError::decode([21, 0]), None);
+        assert_eq!(EvalError::decode([0, 1]), None);
+    }
+
     #[test]
     fn encrypt_decrypt() {
         let key: Vec<u8> = (4..36).collect();
@@ -840,4 +1451,167 @@ pub mod test {
             false,
         );
     }
+
+    #[test]
+    fn deep_formulas() {
+        let depth = 1_000_000;
+        let mut engine = TestSideEffectEngine::new();
+
+        let mut formula = (AXIS, 1).as_noun();
+        for _ in 0..depth {
+            formula = Noun::new_cell(Noun::from_u8(IS_CELL), formula);
+        }
+        let mut ticks = Ticks::new(10_000_000);
+        assert_eq!(
+            eval_metered(Noun::new_cell((5, 6).as_noun(), formula), &mut engine, &DefaultCostModel, &mut ticks, &mut Ticks::new(100_000_000)),
+            Ok(Noun::from_bool(false))
+        );
+        assert_eq!(ticks.get_consumed(), depth + 1);
+
+        // Distributions nested to the left, each pairing the one inside it with 8.
+        let mut formula = (LITERAL, 7).as_noun();
+        let mut expected = Noun::from_u8(7);
+        for _ in 0..depth {
+            formula = Noun::new_cell(formula, (LITERAL, 8).as_noun());
+            expected = Noun::new_cell(expected, Noun::from_u8(8));
+        }
+        let mut ticks = Ticks::new(10_000_000);
+        assert!(eval_metered(Noun::new_cell(Noun::from_u8(0), formula), &mut engine, &DefaultCostModel, &mut ticks, &mut Ticks::new(100_000_000)) == Ok(expected));
+        assert_eq!(ticks.get_consumed(), 2 * depth + 1);
+    }
+
+    #[test]
+    fn deep_nouns() {
+        // Shallow enough that its serialization, for HASH, stays within bounds. All its atoms
+        // are one byte long, so it is also its own shape.
+        let mut deep = Noun::from_u8(1);
+        for _ in 0..500_000 {
+            deep = Noun::new_cell(Noun::from_u8(1), deep);
+        }
+        let mut engine = TestSideEffectEngine::new();
+        let subject = Noun::new_cell(deep.clone(), deep.clone());
+
+        assert_eq!(
+            eval(Noun::new_cell(subject.clone(), (IS_EQUAL, AXIS, 1).as_noun()), &mut engine, &DefaultCostModel, 10_000_000, 100_000_000),
+            Ok(Noun::from_bool(true))
+        );
+        assert!(eval(Noun::new_cell(subject.clone(), (SHAPE, AXIS, 2).as_noun()), &mut engine, &DefaultCostModel, 10_000_000, 100_000_000) == Ok(deep.clone()));
+        let buffer = serialize::serialize(&deep, 1_000_000).unwrap();
+        let mut expected_hash = [0u8; 64];
+        Blake2b::blake2b(&mut expected_hash[..], &buffer, &[][..]);
+        assert_eq!(
+            eval(Noun::new_cell(subject, (HASH, AXIS, 3).as_noun()), &mut engine, &DefaultCostModel, 10_000_000, 100_000_000),
+            Ok(Noun::from_slice(&expected_hash[..]))
+        );
+    }
+
+    /// Charges only for steps, and for HASH.
+    struct HashesOnly;
+
+    impl CostModel for HashesOnly {
+        fn step(&self) -> u64 { 1 }
+        fn opcode(&self, opcode: u8) -> u64 { if opcode == HASH { 50 } else { 0 } }
+        fn compare(&self) -> u64 { 0 }
+        fn measure(&self) -> u64 { 0 }
+        fn reshape_byte(&self) -> u64 { 0 }
+        fn arithmetic(&self, _len: usize) -> u64 { 0 }
+        fn hash(&self, _len: usize) -> u64 { 0 }
+        fn store(&self, _key_len: usize, _value_len: usize) -> u64 { 0 }
+        fn load(&self, _key_len: usize, _value_len: usize) -> u64 { 0 }
+        fn random(&self, _len: usize) -> u64 { 0 }
+        fn derive_key_atom(&self, _len: usize) -> u64 { 0 }
+        fn derive_key_cell(&self) -> u64 { 0 }
+        fn encrypt(&self, _len: usize) -> u64 { 0 }
+        fn decrypt(&self, _ciphertext_len: usize) -> u64 { 0 }
+        fn send(&self, _len: usize) -> u64 { 0 }
+        fn start_neighboring(&self) -> u64 { 0 }
+        fn list_neighbor(&self) -> u64 { 0 }
+    }
+
+    fn ticks_consumed<C: CostModel>(costs: &C, expression: Noun) -> u64 {
+        let mut engine = TestSideEffectEngine::new();
+        let mut ticks = Ticks::new(1_000_000);
+        eval_metered(expression, &mut engine, costs, &mut ticks, &mut Ticks::new(1_000_000)).expect("eval failed");
+        ticks.get_consumed()
+    }
+
+    #[test]
+    fn cost_models() {
+        // Two steps, then storing the key 5 and value 6, whose serializations are three bytes
+        // each, plus a byte for the key's tag.
+        let store = (0, STORE_BY_KEY, LITERAL, (5, 6)).as_noun();
+        assert_eq!(ticks_consumed(&DefaultCostModel, store.clone()), 2 + 4 + 3);
+        assert_eq!(ticks_consumed(&HashesOnly, store), 2);
+
+        let hashes = (5, (HASH, AXIS, 1), (RANDOM, LITERAL, 40)).as_noun();
+        assert_eq!(ticks_consumed(&DefaultCostModel, hashes.clone()), 5 + 20 + 3 + 40);
+        assert_eq!(ticks_consumed(&HashesOnly, hashes), 5 + 50);
+    }
+
+    fn report_for(engine: &mut TestSideEffectEngine, expression: Noun) -> (Noun, ExecutionReport, u64) {
+        let mut ticks = Ticks::new(1_000_000);
+        let (result, report) = eval_with_report(expression, engine, &DefaultCostModel, &mut ticks, &mut Ticks::new(1_000_000));
+        (result.expect("eval failed"), report, ticks.get_consumed())
+    }
+
+    #[test]
+    fn report_runner_ticks() {
+        let mut engine = TestSideEffectEngine::new();
+        let runner = [7u8; 32];
+        let mut runner_private_key = [0u8; 32];
+        Blake2b::blake2b(&mut runner_private_key[..], &runner[..], &[][..]);
+        let body = eval(
+            (runner_private_key.to_vec(), ENCRYPT, (AXIS, 1), (LITERAL, (HASH, AXIS, 1))).as_noun(),
+            &mut engine, &DefaultCostModel, 1_000_000, 1_000_000,
+        ).unwrap();
+        let body_len = body.as_bytes().unwrap().len() as u64;
+
+        let (result, report, consumed) = report_for(
+            &mut engine,
+            (0, EXECUTE_AS, LITERAL, (9, runner.to_vec(), [0u8; 8].to_vec(), body.clone())).as_noun(),
+        );
+        assert_eq!(result, hash(9));
+
+        // The requestor spends the two steps, deriving the key and decrypting the body; the
+        // runner two steps and hashing the three byte serialization of 9. It is all paid for out
+        // of the ticks the evaluation was given.
+        assert_eq!(report.requestor_ticks, 2 + 32 + body_len);
+        let mut runner_ticks = HashMap::new();
+        runner_ticks.insert(runner, 2 + 20 + 3);
+        assert_eq!(report.runner_ticks, runner_ticks);
+        assert_eq!(report.total_ticks(), consumed);
+        assert_eq!(consumed, 2 + 32 + body_len + 25);
+
+        // Runners are allowed 1,000,000 ticks each, but cannot spend more than the caller has.
+        let expression = (0, EXECUTE_AS, LITERAL, (9, runner.to_vec(), [0u8; 8].to_vec(), body.clone())).as_noun();
+        let mut ticks = Ticks::new(consumed - 1);
+        let (result, report) = eval_with_report(expression, &mut engine, &DefaultCostModel, &mut ticks, &mut Ticks::new(1_000_000));
+        assert_eq!(result, Err(EvalError::TickLimitExceeded));
+        assert_eq!(report.runner_ticks[&runner], 2);
+        assert_eq!(report.total_ticks(), ticks.get_consumed());
+    }
+
+    #[test]
+    fn report_storage_and_messages() {
+        let mut engine = TestSideEffectEngine::new();
+        engine.neighbors = vec![[0x80; 32]];
+        let (_, report, consumed) = report_for(
+            &mut engine,
+            (
+                [0x80u8; 32].to_vec(),
+                (STORE_BY_KEY, LITERAL, (5, 6)),
+                (SEND, (AXIS, 1), (LITERAL, (5, 6))),
+                (SEND, (LITERAL, [0x81u8; 32].to_vec()), (LITERAL, 7)),
+            ).as_noun(),
+        );
+        let message = serialize::serialize(&(5, 6).as_noun(), 100).unwrap();
+        assert_eq!(report, ExecutionReport{
+            requestor_ticks: consumed,
+            runner_ticks: HashMap::new(),
+            bytes_stored: 4 + 3,
+            messages_sent: 1,
+            bytes_sent: message.len() as u64,
+        });
+        assert_eq!(report.total_ticks(), consumed);
+    }
 }
@@ -12,14 +12,24 @@ mod shape;
 mod ticks;
 mod equal;
 mod math;
+mod cost;
 
 pub use deserialize::deserialize;
 pub use serialize::serialize;
+pub use serialize::maximum_serialized_length;
 pub use noun::Noun;
 pub use noun::NounKind;
 pub use as_noun::AsNoun;
 pub use eval::eval;
+pub use eval::eval_metered;
+pub use eval::eval_with_report;
+pub use eval::ExecutionReport;
 pub use eval::SideEffectEngine;
+pub use eval::xor_distance;
+pub use eval::EvalError;
+pub use eval::MAX_SERIALIZED_LEN;
+pub use ticks::Ticks;
+pub use cost::{CostModel, DefaultCostModel};
 
 pub use eval::eval_simple;
 
@@ -1,5 +1,6 @@
 use std::convert::TryInto;
 use std::cmp::{Eq, PartialEq};
+use std::mem;
 use std::ops::Deref;
 use std::rc::Rc;
 
@@ -17,31 +18,78 @@ pub enum NounKind<'a> {
 
 impl PartialEq for Noun {
     fn eq(&self, other: &Noun) -> bool {
-        match (self, other) {
-            (&Noun::Cell(ref a, ref b), &Noun::Cell(ref x, ref y)) => a == x && b == y,
-            (
-                &Noun::SmallAtom {
-                    value: value_a,
-                    length: length_a,
+        // Pairs of right children still to compare, so that deep nouns do not exhaust the stack.
+        let mut pending = Vec::new();
+        let (mut a, mut x) = (self, other);
+        loop {
+            match (a, x) {
+                (&Noun::Cell(ref a_left, ref a_right), &Noun::Cell(ref x_left, ref x_right)) => {
+                    if !Rc::ptr_eq(a_right, x_right) {
+                        pending.push((&**a_right, &**x_right));
+                    }
+                    if !Rc::ptr_eq(a_left, x_left) {
+                        a = a_left;
+                        x = x_left;
+                        continue;
+                    }
+                }
+                (
+                    &Noun::SmallAtom {
+                        value: value_a,
+                        length: length_a,
+                    },
+                    &Noun::SmallAtom {
+                        value: value_b,
+                        length: length_b,
+                    },
+                ) => if (value_a, length_a) != (value_b, length_b) {
+                    return false;
                 },
-                &Noun::SmallAtom {
-                    value: value_b,
-                    length: length_b,
+                (&Noun::Atom(ref a), &Noun::Atom(ref x)) => if a != x {
+                    return false;
                 },
-            ) => (value_a, length_a) == (value_b, length_b),
-            (&Noun::Atom(ref a), &Noun::Atom(ref x)) => a == x,
-            _ => false, // Nouns that can be SmallAtoms will be SmallAtoms. Doing otherwise would complicate constant-time guarantees.
+                _ => { return false; } // Nouns that can be SmallAtoms will be SmallAtoms. Doing otherwise would complicate constant-time guarantees.
+            }
+
+            match pending.pop() {
+                Some((b, y)) => {
+                    a = b;
+                    x = y;
+                }
+                None => { return true; }
+            }
         }
     }
 }
 impl Eq for Noun {}
 
-fn own(noun: Rc<Noun>) -> Noun {
-    match Rc::try_unwrap(noun) {
-        Ok(x) => x,
-        Err(shared_x) => shared_x.deref().clone(),
+impl Drop for Noun {
+    fn drop(&mut self) {
+        // Dropping a cell drops its children, which would recurse once per level of a deep noun.
+        // Instead, cells only we hold are detached from their parents and dropped one at a time.
+        let mut detached = Vec::new();
+        detach_children(self, &mut detached);
+        while let Some(mut cell) = detached.pop() {
+            detach_children(&mut cell, &mut detached);
+        }
     }
 }
+
+fn detach_children(noun: &mut Noun, detached: &mut Vec<Noun>) {
+    if let Noun::Cell(ref mut left, ref mut right) = *noun {
+        detach(left, detached);
+        detach(right, detached);
+    }
+}
+
+fn detach(child: &mut Rc<Noun>, detached: &mut Vec<Noun>) {
+    if let Some(child) = Rc::get_mut(child) {
+        if child.is_cell() {
+            detached.push(mem::replace(child, Noun::from_u8(0)));
+        }
+    }
+}
+
 fn own_vec(xs: Rc<Vec<u8>>) -> Vec<u8> {
     match Rc::try_unwrap(xs) {
         Ok(x) => x,
@@ -229,7 +277,7 @@ impl Noun {
 
     pub fn into_cell(self) -> Option<(Noun, Noun)> {
         match self {
-            Noun::Cell(a, b) => Some((own(a), own(b))),
+            Noun::Cell(ref a, ref b) => Some((a.deref().clone(), b.deref().clone())),
             _ => None,
         }
     }
@@ -254,10 +302,10 @@ impl Noun {
         }
     }
 
-    pub fn into_vec(self) -> Option<Vec<u8>> {
+    pub fn into_vec(mut self) -> Option<Vec<u8>> {
         match self {
             Noun::SmallAtom { value, length } => Some(value[0..length as usize].to_vec()),
-            Noun::Atom(xs) => Some(own_vec(xs)),
+            Noun::Atom(ref mut xs) => Some(own_vec(mem::replace(xs, Rc::new(Vec::new())))),
             Noun::Cell(_, _) => None,
         }
     }
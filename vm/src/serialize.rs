@@ -61,21 +61,36 @@ impl Serializer {
     }
 
     fn serialize_noun(&mut self, noun: &Noun) -> SerializationResult<()> {
-        match noun.as_kind() {
-            NounKind::Cell(lhs, rhs) => {
-                self.structure.push(true);
-                self.serialize_noun(lhs)?;
-                self.serialize_noun(rhs)?;
-            }
-            NounKind::Atom(bytes) => {
-                self.structure.push(false);
-                self.serialize_atom(bytes)?;
+        // Depth first, left to right, keeping the right children still to visit.
+        let mut pending = vec![noun];
+        while let Some(noun) = pending.pop() {
+            match noun.as_kind() {
+                NounKind::Cell(lhs, rhs) => {
+                    self.structure.push(true);
+                    pending.push(rhs);
+                    pending.push(lhs);
+                }
+                NounKind::Atom(bytes) => {
+                    self.structure.push(false);
+                    self.serialize_atom(bytes)?;
+                }
             }
         }
         Ok(())
     }
 }
 
+/// Most bytes the prefix giving the length of the atom encoding may take.
+const MAXIMUM_LENGTH_ENCODING_LENGTH: usize = 10;
+
+/// Longest output `serialize` can produce for a given `maximum_atom_encoding_length`. Besides the
+/// length prefix and the atoms, there is a structure bit for every noun, and since each atom
+/// takes at least a byte and there is one cell fewer than atoms, there are under two nouns per
+/// byte of atoms.
+pub fn maximum_serialized_length(maximum_atom_encoding_length: usize) -> usize {
+    MAXIMUM_LENGTH_ENCODING_LENGTH + maximum_atom_encoding_length + (2 * maximum_atom_encoding_length + 7) / 8
+}
+
 /// `maximum_atom_encoding_length` sets a rough upper bound on how much memory will be used. It controls
 /// how much space all the atoms in the encoding, combined, may take up.
 pub fn serialize(noun: &Noun, maximum_atom_encoding_length: usize) -> SerializationResult<Vec<u8>> {
@@ -84,8 +99,16 @@ pub fn serialize(noun: &Noun, maximum_atom_encoding_length: usize) -> Serializat
     serializer.serialize_noun(noun)?;
 
     let length_encoding = {
-        let mut length_encoder = Serializer::new(10);
-        length_encoder.serialize_noun(&Noun::from_usize_compact(serializer.atom_encoding.len()))?;
+        // Least significant byte first, as `deserialize` reads it with `Noun::as_usize`.
+        let mut length = serializer.atom_encoding.len();
+        let mut length_bytes = Vec::new();
+        while length != 0 {
+            length_bytes.push(length as u8);
+            length = length >> 8;
+        }
+
+        let mut length_encoder = Serializer::new(MAXIMUM_LENGTH_ENCODING_LENGTH);
+        length_encoder.serialize_noun(&Noun::from_vec(length_bytes))?;
         length_encoder.atom_encoding
     };
 
@@ -130,7 +153,8 @@ impl BitVec {
 #[cfg(test)]
 mod test {
     use as_noun::AsNoun;
-    use serialize::serialize;
+    use noun::Noun;
+    use serialize::{maximum_serialized_length, serialize};
 
     #[test]
     fn serialize_small_byte_atom() {
@@ -201,8 +225,8 @@ mod test {
             serialize(&(&atom[..]).as_noun(), 20000),
             Ok([
                 192,
-                (10925 >> 8) as u8,
                 (10925 & 0xff) as u8,
+                (10925 >> 8) as u8,
                 255,
                 128 | 42,
                 85
@@ -234,4 +258,19 @@ mod test {
             Ok([3, 40, 50, 60, 0x05].to_vec())
         );
     }
+
+    #[test]
+    fn many_small_atoms() {
+        // Single byte atoms need the most structure bits for their length.
+        let mut noun = Noun::from_u8(1);
+        for _ in 1..1000 {
+            noun = Noun::new_cell(Noun::from_u8(1), noun);
+        }
+        let serialized = serialize(&noun, 1000).unwrap();
+        assert_eq!(serialized.len(), 3 + 1000 + 250);
+        assert!(serialized.len() <= maximum_serialized_length(1000));
+
+        let noun = Noun::new_cell(Noun::from_u8(1), noun);
+        assert!(serialize(&noun, 1000).is_err());
+    }
 }
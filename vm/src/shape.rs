@@ -10,49 +10,81 @@ pub enum ShapeError {
     DataTooShort,
 }
 
+/// Work remaining while building a noun depth first, without recursing.
+enum Build<'a> {
+    /// Build the noun for this node.
+    Visit(&'a Noun),
+
+    /// Pair up the two nouns built most recently.
+    Combine,
+}
+
+fn combine(built: &mut Vec<Noun>) {
+    let right = built.pop().unwrap();
+    let left = built.pop().unwrap();
+    built.push(Noun::new_cell(left, right));
+}
+
 fn populate_structure<R: Read>(
     structure: &Noun,
     data_source: &mut R,
     allocation_bound: &mut Ticks,
 ) -> Result<Noun, ShapeError> {
-    allocation_bound
-        .incur(size_of::<Noun>() as u64)
-        .map_err(|_| ShapeError::AllocationBoundExceeded)?;
-
-    if let Some((left, right)) = structure.as_cell() {
-        return Ok(Noun::new_cell(
-            populate_structure(left, data_source, allocation_bound)?,
-            populate_structure(right, data_source, allocation_bound)?,
-        ));
-    }
+    let mut work = vec![Build::Visit(structure)];
+    let mut built = Vec::new();
+    while let Some(step) = work.pop() {
+        let structure = match step {
+            Build::Visit(structure) => structure,
+            Build::Combine => {
+                combine(&mut built);
+                continue;
+            }
+        };
 
-    let expected_count = structure
-        .as_usize()
-        .ok_or(ShapeError::AllocationBoundExceeded)?;
-    allocation_bound
-        .incur(expected_count as u64)
-        .map_err(|_| ShapeError::AllocationBoundExceeded)?;
+        allocation_bound
+            .incur(size_of::<Noun>() as u64)
+            .map_err(|_| ShapeError::AllocationBoundExceeded)?;
 
-    let mut xs = vec![0u8; expected_count];
-    data_source
-        .read_exact(&mut xs[..])
-        .map_err(|_| ShapeError::DataTooShort)?;
+        if let Some((left, right)) = structure.as_cell() {
+            work.push(Build::Combine);
+            work.push(Build::Visit(right));
+            work.push(Build::Visit(left));
+            continue;
+        }
+
+        let expected_count = structure
+            .as_usize()
+            .ok_or(ShapeError::AllocationBoundExceeded)?;
+        allocation_bound
+            .incur(expected_count as u64)
+            .map_err(|_| ShapeError::AllocationBoundExceeded)?;
+
+        let mut xs = vec![0u8; expected_count];
+        data_source
+            .read_exact(&mut xs[..])
+            .map_err(|_| ShapeError::DataTooShort)?;
 
-    Ok(Noun::from_vec(xs))
+        built.push(Noun::from_vec(xs));
+    }
+    Ok(built.pop().unwrap())
 }
 
 pub struct NounReader<'a> {
     current_node: Option<Cursor<&'a [u8]>>,
     stack: Vec<&'a Noun>,
     ticks: &'a mut Ticks,
+
+    /// Ticks charged for each byte read.
+    byte_cost: u64,
 }
 
 impl<'a> NounReader<'a> {
-    fn new(noun: &'a Noun, ticks: &'a mut Ticks) -> NounReader<'a> {
+    fn new(noun: &'a Noun, ticks: &'a mut Ticks, byte_cost: u64) -> NounReader<'a> {
         NounReader {
             current_node: None,
             stack: vec![noun],
             ticks: ticks,
+            byte_cost: byte_cost,
         }
     }
 }
@@ -89,7 +121,7 @@ impl<'a> Read for NounReader<'a> {
                 let read_count = cursor.read(buf)?;
                 if read_count > 0 {
                     self.ticks
-                        .incur(read_count as u64)
+                        .incur((read_count as u64).saturating_mul(self.byte_cost))
                         .map_err(|_| io::Error::new(io::ErrorKind::Interrupted, "cost exceeded"))?;
                     return Ok(read_count);
                 }
@@ -99,38 +131,53 @@ impl<'a> Read for NounReader<'a> {
     }
 }
 
+/// Lay `data`'s bytes out in the shape of `structure`, charging `byte_cost` ticks for each byte
+/// copied and spending from `allocation_bound` for everything allocated.
 pub fn reshape(
     data: &Noun,
     structure: &Noun,
     ticks: &mut Ticks,
-    allocation_bound: usize,
+    byte_cost: u64,
+    allocation_bound: &mut Ticks,
 ) -> Result<Noun, ShapeError> {
     populate_structure(
         structure,
-        &mut NounReader::new(data, ticks),
-        &mut Ticks::new(allocation_bound as u64),
+        &mut NounReader::new(data, ticks, byte_cost),
+        allocation_bound,
     )
 }
 
+/// The shape of `data`: the same structure, with each atom replaced by its length. Charges
+/// `noun_cost` ticks for each noun measured.
 pub fn length(
     data: &Noun,
-    ticks: &mut Ticks
+    ticks: &mut Ticks,
+    noun_cost: u64,
 ) -> CostResult<Noun> {
-    Ok(match data.as_kind() {
-        NounKind::Atom(xs) => {
-            ticks.incur(1)?;
-            Noun::from_usize_compact(xs.len())
-        }
-        NounKind::Cell(left, right) => {
-            ticks.incur(1)?;
-            Noun::new_cell(length(left, ticks)?, length(right, ticks)?)
+    let mut work = vec![Build::Visit(data)];
+    let mut built = Vec::new();
+    while let Some(step) = work.pop() {
+        match step {
+            Build::Visit(data) => {
+                ticks.incur(noun_cost)?;
+                match data.as_kind() {
+                    NounKind::Atom(xs) => built.push(Noun::from_usize_compact(xs.len())),
+                    NounKind::Cell(left, right) => {
+                        work.push(Build::Combine);
+                        work.push(Build::Visit(right));
+                        work.push(Build::Visit(left));
+                    }
+                }
+            }
+            Build::Combine => combine(&mut built),
         }
-    })
+    }
+    Ok(built.pop().unwrap())
 }
 
 #[cfg(test)]
 mod test {
-    use super::{reshape, ShapeError};
+    use super::{length, reshape, ShapeError};
     use as_noun::AsNoun;
     use noun::Noun;
     use ticks::Ticks;
@@ -141,7 +188,8 @@ mod test {
                 &data.as_noun(),
                 &structure.as_noun(),
                 &mut Ticks::new(1_000_000),
-                1_000_000
+                1,
+                &mut Ticks::new(1_000_000)
             ),
             Ok(expected_result.as_noun())
         )
@@ -153,7 +201,8 @@ mod test {
                 &data.as_noun(),
                 &structure.as_noun(),
                 &mut Ticks::new(1_000_000),
-                1_000_000
+                1,
+                &mut Ticks::new(1_000_000)
             ),
             Err(error)
         );
@@ -205,4 +254,22 @@ mod test {
             ShapeError::AllocationBoundExceeded,
         );
     }
+
+    #[test]
+    fn deep() {
+        let mut structure = Noun::from_u8(1);
+        let mut data = Noun::from_u8(7);
+        let mut expected = Noun::from_u8(7);
+        for _ in 0..1_000_000 {
+            structure = Noun::new_cell(Noun::from_u8(1), structure);
+            data = Noun::new_cell(data, Noun::from_u8(7));
+            expected = Noun::new_cell(Noun::from_u8(7), expected);
+        }
+
+        assert_eq!(length(&structure, &mut Ticks::new(10_000_000), 1), Ok(structure.clone()));
+        assert_eq!(
+            reshape(&data, &structure, &mut Ticks::new(10_000_000), 1, &mut Ticks::new(100_000_000)),
+            Ok(expected)
+        );
+    }
 }
@@ -22,4 +22,8 @@ impl Ticks {
     pub fn get_consumed(&self) -> u64 {
         self.initial - self.count
     }
+
+    pub fn remaining(&self) -> u64 {
+        self.count
+    }
 }